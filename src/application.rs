@@ -12,7 +12,7 @@ use gtk::{gio, glib, prelude::*};
 use log::{debug, warn};
 
 use crate::{
-    audio::AudioPlayer,
+    audio::{AudioPlayer, CoverCache, CoverOptions, Library, PlaybackError},
     config::{APPLICATION_ID, VERSION},
     i18n::i18n,
     utils,
@@ -21,6 +21,7 @@ use crate::{
 
 pub enum ApplicationAction {
     Present,
+    PlaybackError(PlaybackError),
 }
 
 mod imp {
@@ -29,6 +30,7 @@ mod imp {
     #[derive(Debug)]
     pub struct Application {
         pub player: Rc<AudioPlayer>,
+        pub library: Library,
         pub receiver: RefCell<Option<Receiver<ApplicationAction>>>,
         pub background_hold: RefCell<Option<ApplicationHoldGuard>>,
         pub settings: gio::Settings,
@@ -46,6 +48,7 @@ mod imp {
 
             Self {
                 player: AudioPlayer::new(sender),
+                library: Library::default(),
                 receiver,
                 background_hold: RefCell::default(),
                 settings: utils::settings_manager(),
@@ -66,6 +69,7 @@ mod imp {
 
             obj.set_accels_for_action("queue.add-song", &["<primary>s"]);
             obj.set_accels_for_action("queue.add-folder", &["<primary>a"]);
+            obj.set_accels_for_action("queue.add-stream", &["<primary>u"]);
             obj.set_accels_for_action("queue.clear", &["<primary>L"]);
             obj.set_accels_for_action("queue.toggle", &["F9"]);
             obj.set_accels_for_action("queue.search", &["<primary>F"]);
@@ -85,6 +89,11 @@ mod imp {
             self.parent_startup();
 
             gtk::Window::set_default_icon_name(APPLICATION_ID);
+
+            utils::migrate_cache();
+            std::thread::spawn(utils::trim_cache);
+
+            self.library.reindex();
         }
 
         fn activate(&self) {
@@ -133,6 +142,10 @@ impl Application {
         self.imp().player.clone()
     }
 
+    pub fn library(&self) -> Library {
+        self.imp().library.clone()
+    }
+
     fn setup_settings(&self) {
         self.imp().settings.connect_changed(
             Some("background-play"),
@@ -149,6 +162,23 @@ impl Application {
         );
 
         let _dummy = self.imp().settings.boolean("background-play");
+
+        // Songs are loaded in other threads, so they cannot read the
+        // settings themselves
+        let update_cover_options = |settings: &gio::Settings| {
+            let options = CoverOptions::from_settings(settings);
+            CoverCache::global().lock().unwrap().set_options(options);
+        };
+        for key in [
+            "cover-file-patterns",
+            "prefer-cover-files",
+            "cover-files-ignore-case",
+        ] {
+            self.imp()
+                .settings
+                .connect_changed(Some(key), move |settings, _| update_cover_options(settings));
+        }
+        update_cover_options(&self.imp().settings);
     }
 
     fn setup_channel(&self) {
@@ -167,12 +197,19 @@ impl Application {
     fn process_action(&self, action: ApplicationAction) -> glib::ControlFlow {
         match action {
             ApplicationAction::Present => self.present_main_window(),
+            ApplicationAction::PlaybackError(error) => self.show_playback_error(&error),
             // _ => debug!("Received action {:?}", action),
         }
 
         glib::ControlFlow::Continue
     }
 
+    fn show_playback_error(&self, error: &PlaybackError) {
+        if let Some(window) = self.active_window().and_downcast::<Window>() {
+            window.show_playback_error(error);
+        }
+    }
+
     fn present_main_window(&self) {
         let window = if let Some(window) = self.active_window() {
             window
@@ -7,6 +7,7 @@ pub trait Controller {
     fn set_playback_state(&self, state: &PlaybackState);
 
     fn set_song(&self, song: &Song);
+    fn update_song_metadata(&self, song: &Song);
     fn set_position(&self, position: u64);
     fn set_repeat_mode(&self, repeat: RepeatMode);
 }
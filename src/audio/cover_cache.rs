@@ -3,8 +3,8 @@
 
 use std::{
     collections::HashMap,
-    path::{Path, PathBuf},
-    sync::Mutex,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
 };
 
 use gtk::{gdk, gio, glib, prelude::*};
@@ -12,32 +12,185 @@ use log::debug;
 use once_cell::sync::OnceCell;
 use sha2::{Digest, Sha256};
 
-use crate::utils;
+use crate::{audio::CoverPalette, utils};
 
-#[derive(Clone, Debug)]
-pub struct CoverArt {
+#[derive(Debug)]
+struct CoverData {
+    // The smallest size, which is all the playlist needs
     texture: gdk::Texture,
-    palette: Vec<gdk::RGBA>,
+    palette: CoverPalette,
     cache: Option<PathBuf>,
+    // The larger sizes, decoded from the cached image when something
+    // draws them, and dropped once nothing does any more
+    sizes: Mutex<Vec<(i32, glib::WeakRef<gdk::Texture>)>>,
+}
+
+// The songs of an album share the same cover art, and the textures are
+// only dropped once none of them uses it any more
+#[derive(Clone, Debug, glib::Boxed)]
+#[boxed_type(name = "AmberolCoverArt", nullable)]
+pub struct CoverArt(Arc<CoverData>);
+
+// Two covers are the same if they share their textures
+impl PartialEq for CoverArt {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
 impl CoverArt {
-    pub fn texture(&self) -> &gdk::Texture {
-        self.texture.as_ref()
+    fn new(pixbuf: &gdk_pixbuf::Pixbuf, palette: CoverPalette, cache: Option<PathBuf>) -> Self {
+        let small = utils::scale_cover_pixbuf(pixbuf, utils::small_cover_size());
+
+        Self(Arc::new(CoverData {
+            texture: gdk::Texture::for_pixbuf(&small),
+            palette,
+            cache,
+            sizes: Mutex::new(Vec::new()),
+        }))
+    }
+
+    // The texture we keep in memory
+    pub fn small_texture(&self) -> &gdk::Texture {
+        &self.0.texture
+    }
+
+    // The largest texture we have; this blocks if it has to be decoded
+    pub fn texture(&self) -> gdk::Texture {
+        self.texture_for_size(utils::large_cover_size())
+    }
+
+    fn covers_size(texture: &gdk::Texture, size: i32) -> bool {
+        texture.width().max(texture.height()) >= size
+    }
+
+    // The texture for the given size, in pixels, if we do not need to
+    // decode it
+    pub fn loaded_texture_for_size(&self, size: i32) -> Option<gdk::Texture> {
+        let size = utils::cover_size(size);
+        if self.0.cache.is_none() || CoverArt::covers_size(&self.0.texture, size) {
+            return Some(self.0.texture.clone());
+        }
+
+        let sizes = self.0.sizes.lock().unwrap();
+        sizes
+            .iter()
+            .find(|(s, _)| *s == size)
+            .and_then(|(_, t)| t.upgrade())
+    }
+
+    // The smallest texture that covers the given size, in pixels, without
+    // scaling up; this blocks if it has to be decoded, so it should be
+    // called off the main thread
+    pub fn texture_for_size(&self, size: i32) -> gdk::Texture {
+        if let Some(texture) = self.loaded_texture_for_size(size) {
+            return texture;
+        }
+
+        let size = utils::cover_size(size);
+        let path = self.0.cache.as_ref().unwrap();
+        let texture = match gdk_pixbuf::Pixbuf::from_file(path) {
+            Ok(p) => gdk::Texture::for_pixbuf(&utils::scale_cover_pixbuf(&p, size)),
+            Err(e) => {
+                debug!("Unable to load cached cover {:?}: {}", path, e);
+                return self.0.texture.clone();
+            }
+        };
+
+        let mut sizes = self.0.sizes.lock().unwrap();
+        sizes.retain(|(s, t)| *s != size && t.upgrade().is_some());
+        sizes.push((size, texture.downgrade()));
+
+        texture
     }
 
-    pub fn palette(&self) -> &Vec<gdk::RGBA> {
-        self.palette.as_ref()
+    pub fn palette(&self) -> &CoverPalette {
+        &self.0.palette
     }
 
     pub fn cache(&self) -> Option<&PathBuf> {
-        self.cache.as_ref()
+        self.0.cache.as_ref()
+    }
+}
+
+// Where to look for the cover art of a song
+#[derive(Clone, Debug)]
+pub struct CoverOptions {
+    // Glob patterns for the cover files next to the song, in order of
+    // preference; a pattern can contain a sub-folder, like "Scans/*.jpg"
+    pub patterns: Vec<String>,
+    // Whether cover files are preferred to the cover art in the tags
+    pub prefer_files: bool,
+    pub ignore_case: bool,
+}
+
+impl Default for CoverOptions {
+    fn default() -> Self {
+        Self {
+            patterns: [
+                "cover.*",
+                "folder.*",
+                "front.*",
+                "albumart*.*",
+                "Scans/*.jpg",
+            ]
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+            prefer_files: false,
+            ignore_case: true,
+        }
     }
 }
 
+impl CoverOptions {
+    // Identifies the options, so that covers found with different ones
+    // are not mixed up
+    pub fn key(&self) -> String {
+        let mut hasher = Sha256::new();
+        for pattern in &self.patterns {
+            hasher.update(pattern);
+            hasher.update([0]);
+        }
+        hasher.update([self.prefer_files as u8, self.ignore_case as u8]);
+
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub fn from_settings(settings: &gio::Settings) -> Self {
+        Self {
+            patterns: settings
+                .strv("cover-file-patterns")
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            prefer_files: settings.boolean("prefer-cover-files"),
+            ignore_case: settings.boolean("cover-files-ignore-case"),
+        }
+    }
+}
+
+// The textures we keep around for songs that are not in the playlist
+// any more are dropped once all the covers still alive take more than
+// this amount of memory, starting from the least recently used
+const MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+// Every cover that is still alive has an entry, so that we never load
+// it twice; only the ones we keep for later hold on to the textures
+#[derive(Debug)]
+struct CacheEntry {
+    cover: Weak<CoverData>,
+    retained: Option<CoverArt>,
+    size: usize,
+    last_used: u64,
+}
+
 #[derive(Debug)]
 pub struct CoverCache {
-    entries: HashMap<String, CoverArt>,
+    entries: HashMap<String, CacheEntry>,
+    options: CoverOptions,
+    // Incremented on every access, to order the entries by use
+    clock: u64,
 }
 
 impl CoverCache {
@@ -53,64 +206,258 @@ impl CoverCache {
     fn new() -> Self {
         CoverCache {
             entries: HashMap::new(),
+            options: CoverOptions::default(),
+            clock: 0,
         }
     }
 
-    fn add_entry(&mut self, uuid: &str, cover: CoverArt) -> &CoverArt {
-        self.entries.entry(uuid.to_string()).or_insert(cover)
+    // GSettings is only read on the main thread, so the options are
+    // copied here for the threads loading the songs; the options are
+    // part of the cover UUID, so songs loaded from now on use the new
+    // options, even if their metadata is cached
+    pub fn set_options(&mut self, options: CoverOptions) {
+        debug!("Cover options: {:?}", &options);
+        self.options = options;
+        self.clear();
     }
 
-    fn lookup(&self, uuid: &String) -> Option<&CoverArt> {
-        self.entries.get(uuid)
+    pub fn options_key() -> String {
+        CoverCache::global().lock().unwrap().options.key()
     }
 
-    fn load_cover_art(&self, tag: &lofty::Tag, path: Option<&Path>) -> Option<glib::Bytes> {
-        if let Some(picture) = tag.get_picture_type(lofty::PictureType::CoverFront) {
-            debug!("Found CoverFront");
-            return Some(glib::Bytes::from(picture.data()));
-        } else {
-            // If we don't have a CoverFront picture, we fall back to Other
-            // and BandLogo types
-            for picture in tag.pictures() {
-                let cover_art = match picture.pic_type() {
-                    lofty::PictureType::Other => Some(glib::Bytes::from(picture.data())),
-                    lofty::PictureType::BandLogo => Some(glib::Bytes::from(picture.data())),
-                    _ => None,
-                };
+    // The larger sizes are only alive while they are drawn, so we only
+    // count the texture that every cover keeps
+    fn memory_size(cover: &CoverArt) -> usize {
+        let t = cover.small_texture();
+        t.width() as usize * t.height() as usize * 4
+    }
 
-                if cover_art.is_some() {
-                    debug!("Found fallback");
-                    return cover_art;
-                }
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn add_entry(&mut self, uuid: &str, cover: CoverArt) -> CoverArt {
+        // Another thread may have loaded the same cover in the meantime
+        if let Some(cover) = self.lookup(uuid) {
+            return cover;
+        }
+
+        let last_used = self.tick();
+        self.entries.insert(
+            uuid.to_string(),
+            CacheEntry {
+                cover: Arc::downgrade(&cover.0),
+                retained: Some(cover.clone()),
+                size: CoverCache::memory_size(&cover),
+                last_used,
+            },
+        );
+        self.evict();
+
+        cover
+    }
+
+    fn lookup(&mut self, uuid: &str) -> Option<CoverArt> {
+        let last_used = self.tick();
+        let entry = self.entries.get_mut(uuid)?;
+        let cover = match entry.cover.upgrade() {
+            Some(c) => CoverArt(c),
+            None => {
+                self.entries.remove(uuid);
+                return None;
+            }
+        };
+
+        entry.last_used = last_used;
+        entry.retained = Some(cover.clone());
+        self.evict();
+
+        Some(cover)
+    }
+
+    // Only the covers we retain can be dropped; the ones still used by
+    // a song count towards the budget all the same. The most recently
+    // used entry is always kept, even if it's larger than the whole
+    // budget
+    fn evict(&mut self) {
+        self.entries.retain(|_, e| e.cover.strong_count() > 0);
+
+        let mut size: usize = self.entries.values().map(|e| e.size).sum();
+        if size <= MAX_MEMORY {
+            return;
+        }
+
+        let mut retained: Vec<(u64, String)> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.retained.is_some())
+            .map(|(uuid, e)| (e.last_used, uuid.clone()))
+            .collect();
+        retained.sort();
+        retained.pop();
+
+        for (_, uuid) in retained {
+            if size <= MAX_MEMORY {
+                break;
+            }
+
+            debug!("Evicting cover for UUID '{}'", &uuid);
+            let entry = self.entries.get_mut(&uuid).unwrap();
+            entry.retained = None;
+            if entry.cover.strong_count() == 0 {
+                size -= entry.size;
+                self.entries.remove(&uuid);
+            }
+        }
+    }
+
+    // The cached files of the covers that are still alive; the MPRIS
+    // controller may point to any of them
+    pub fn cache_files(&self) -> Vec<PathBuf> {
+        self.entries
+            .values()
+            .filter_map(|e| e.cover.upgrade())
+            .filter_map(|c| c.cache.clone())
+            .collect()
+    }
+
+    fn embedded_front_cover(tag: &lofty::Tag) -> Option<glib::Bytes> {
+        // Tags can contain many pictures, like the back cover or the
+        // booklet, so we look for the front cover first
+        let picture = tag.get_picture_type(lofty::PictureType::CoverFront)?;
+        debug!("Found CoverFront");
+        Some(glib::Bytes::from(picture.data()))
+    }
+
+    fn embedded_fallback_cover(tag: &lofty::Tag) -> Option<glib::Bytes> {
+        // If we don't have a CoverFront picture, we fall back to Other
+        // and BandLogo types
+        for picture in tag.pictures() {
+            let cover_art = match picture.pic_type() {
+                lofty::PictureType::Other => Some(glib::Bytes::from(picture.data())),
+                lofty::PictureType::BandLogo => Some(glib::Bytes::from(picture.data())),
+                _ => None,
+            };
+
+            if cover_art.is_some() {
+                debug!("Found fallback");
+                return cover_art;
             }
         }
 
-        // We always favour the cover art in the song metadata because it's going
-        // to be in a hot cache; looking for a separate file will blow a bunch of
-        // caches out of the water, which will slow down loading the song into the
-        // playlist model
-        if let Some(p) = path {
-            let ext_covers = vec!["Cover.jpg", "Cover.png", "cover.jpg", "cover.png"];
+        None
+    }
+
+    // Lists the names of the entries inside a folder, in the same order
+    // as a file manager would
+    fn list_folder(folder: &gio::File) -> Vec<String> {
+        let enumerator = match folder.enumerate_children(
+            "standard::name",
+            gio::FileQueryInfoFlags::NONE,
+            gio::Cancellable::NONE,
+        ) {
+            Ok(e) => e,
+            Err(_) => return vec![],
+        };
+
+        let mut names: Vec<String> = enumerator
+            .filter_map(|info| info.ok())
+            .map(|info| info.name().to_string_lossy().to_string())
+            .collect();
+        names.sort_by_cached_key(|n| glib::FilenameCollationKey::from(n.as_str()));
+        names
+    }
 
-            for name in ext_covers {
-                let mut cover_file = PathBuf::from(p);
-                cover_file.push(name);
-                debug!("Looking for external cover file: {:?}", &cover_file);
+    fn cover_file(folder: &gio::File, options: &CoverOptions) -> Option<glib::Bytes> {
+        let fold = |s: &str| {
+            if options.ignore_case {
+                s.to_lowercase()
+            } else {
+                s.to_string()
+            }
+        };
+
+        // Sub-folders are listed on demand, and only once
+        let mut listings: HashMap<String, (gio::File, Vec<String>)> = HashMap::new();
+
+        for pattern in &options.patterns {
+            let (subfolder, glob) = match pattern.rsplit_once('/') {
+                Some((subfolder, glob)) => (subfolder, glob),
+                None => ("", pattern.as_str()),
+            };
 
-                let f = gio::File::for_path(&cover_file);
-                if let Ok((res, _)) = f.load_bytes(None::<&gio::Cancellable>) {
-                    debug!("Loading cover from external cover file");
+            if !listings.contains_key(subfolder) {
+                let mut dir = Some(folder.clone());
+                for component in subfolder.split('/').filter(|c| !c.is_empty()) {
+                    dir = dir.and_then(|d| {
+                        let name = CoverCache::list_folder(&d)
+                            .into_iter()
+                            .find(|n| fold(n) == fold(component))?;
+                        Some(d.child(name))
+                    });
+                }
+                let listing = match dir {
+                    Some(d) => {
+                        let names = CoverCache::list_folder(&d);
+                        (d, names)
+                    }
+                    None => (folder.clone(), vec![]),
+                };
+                listings.insert(subfolder.to_string(), listing);
+            }
+
+            let (dir, names) = &listings[subfolder];
+            let spec = glib::PatternSpec::new(&fold(glob));
+            for name in names.iter().filter(|n| spec.matches_string(&fold(n))) {
+                let (content_type, _) = gio::content_type_guess(Some(name), &[]);
+                if !gio::content_type_is_mime_type(&content_type, "image/*") {
+                    continue;
+                }
+
+                let f = dir.child(name);
+                debug!("Loading cover from external cover file: {}", f.uri());
+                if let Ok((res, _)) = f.load_bytes(gio::Cancellable::NONE) {
                     return Some(res);
                 }
             }
         }
 
-        debug!("No cover art");
-
         None
     }
 
-    pub fn cover_art(&mut self, path: &Path, tag: &lofty::Tag) -> Option<(CoverArt, String)> {
+    fn load_cover_art(
+        tag: &lofty::Tag,
+        folder: Option<&gio::File>,
+        options: &CoverOptions,
+    ) -> Option<glib::Bytes> {
+        // We favour the cover art in the song metadata by default because
+        // it's going to be in a hot cache; looking for a separate file will
+        // blow a bunch of caches out of the water, which will slow down
+        // loading the song into the playlist model. Pictures that are not
+        // a front cover come last, as they are often something else, like
+        // the back cover
+        let cover_file = || folder.and_then(|f| CoverCache::cover_file(f, options));
+        let cover_art = if options.prefer_files {
+            cover_file().or_else(|| CoverCache::embedded_front_cover(tag))
+        } else {
+            CoverCache::embedded_front_cover(tag).or_else(cover_file)
+        };
+
+        let cover_art = cover_art.or_else(|| CoverCache::embedded_fallback_cover(tag));
+        if cover_art.is_none() {
+            debug!("No cover art");
+        }
+
+        cover_art
+    }
+
+    // Songs are loaded from multiple threads at the same time, so we only
+    // hold the lock on the global cache while looking up and adding
+    // entries; loading the cover art and computing its palette happens
+    // outside of it. Two threads may end up loading the same cover, in
+    // which case the first one to finish wins
+    pub fn cover_art(file: &gio::File, tag: &lofty::Tag) -> Option<(CoverArt, String)> {
         let mut album_artist = None;
         let mut track_artist = None;
         let mut album = None;
@@ -131,10 +478,17 @@ impl CoverCache {
             };
         }
 
+        let options = CoverCache::global().lock().unwrap().options.clone();
+
         // We use the album and artist to ensure we share the
         // same cover data for every track in the album; if we
-        // don't have an album, we use the file name
+        // don't have an album, we use the file name. The parse
+        // name is the path for local files, and the URI for
+        // everything else. Different options can find a different
+        // cover for the same album, so they are part of it as well
+        let parent = file.parent();
         let mut hasher = Sha256::new();
+        hasher.update(options.key());
         if let Some(album) = album {
             hasher.update(&album);
 
@@ -144,59 +498,50 @@ impl CoverCache {
                 hasher.update(&artist);
             }
 
-            if let Some(parent) = path.parent() {
-                hasher.update(parent.to_str().unwrap());
+            if let Some(ref parent) = parent {
+                hasher.update(parent.parse_name().as_str());
             }
         } else {
-            hasher.update(path.to_str().unwrap());
+            hasher.update(file.parse_name().as_str());
         }
 
         let uuid = format!("{:x}", hasher.finalize());
 
-        match self.lookup(&uuid) {
+        let cached = CoverCache::global().lock().unwrap().lookup(&uuid);
+        match cached {
             Some(c) => {
                 debug!("Found cover for UUID '{}'", &uuid);
-                Some((c.clone(), uuid))
+                Some((c, uuid))
             }
             None => {
                 debug!("Loading cover art for UUID: {}", &uuid);
 
-                let cover_art = self.load_cover_art(tag, path.parent());
+                let cover_art = CoverCache::load_cover_art(tag, parent.as_ref(), &options);
 
-                // The pixel buffer for the cover art
-                let cover_pixbuf = if let Some(ref cover_art) = cover_art {
-                    utils::load_cover_texture(cover_art)
-                } else {
-                    None
-                };
+                // The pixel buffer for the cover art, at its largest size
+                let cover_pixbuf = cover_art
+                    .as_ref()
+                    .and_then(utils::load_cover_pixbuf)
+                    .map(|p| utils::scale_cover_pixbuf(&p, utils::large_cover_size()));
 
-                // Cache the pixel buffer, so that the MPRIS controller can
-                // reference it later
-                let cache_path = if let Some(ref pixbuf) = cover_pixbuf {
-                    utils::cache_cover_art(&uuid, pixbuf)
-                } else {
-                    None
-                };
-
-                // The texture we draw on screen
-                let texture = cover_pixbuf.as_ref().map(gdk::Texture::for_pixbuf);
+                // Cache the largest pixel buffer, so that the MPRIS
+                // controller can reference it later, and so that we can
+                // decode the larger sizes from it
+                let cache_path = cover_pixbuf
+                    .as_ref()
+                    .and_then(|pixbuf| utils::cache_cover_art(&uuid, pixbuf));
 
                 // The color palette we use for styling the UI
-                let palette = if let Some(ref pixbuf) = cover_pixbuf {
-                    utils::load_palette(pixbuf)
-                } else {
-                    None
-                };
+                let palette = cover_pixbuf
+                    .as_ref()
+                    .and_then(|p| CoverPalette::from_pixbuf(&utils::palette_pixbuf(p)));
 
                 // We want both texture and palette
-                if texture.is_some() && palette.is_some() {
-                    let res = CoverArt {
-                        texture: texture.unwrap(),
-                        palette: palette.unwrap(),
-                        cache: cache_path,
-                    };
+                if let (Some(pixbuf), Some(palette)) = (cover_pixbuf, palette) {
+                    let res = CoverArt::new(&pixbuf, palette, cache_path);
 
-                    self.add_entry(&uuid, res.clone());
+                    let mut cover_cache = CoverCache::global().lock().unwrap();
+                    let res = cover_cache.add_entry(&uuid, res);
 
                     Some((res, uuid))
                 } else {
@@ -206,7 +551,49 @@ impl CoverCache {
         }
     }
 
+    // Restores the cover art of a song from the on-disk cache, using the
+    // palette stored in the metadata cache; this avoids decoding the
+    // original image, as well as computing its palette
+    pub fn cached_cover_art(uuid: &str, palette: CoverPalette) -> Option<CoverArt> {
+        if let Some(c) = CoverCache::global().lock().unwrap().lookup(uuid) {
+            return Some(c);
+        }
+
+        let mut path = utils::cache_dir("covers");
+        path.push(format!("{}.png", &uuid));
+
+        let pixbuf = match gdk_pixbuf::Pixbuf::from_file(&path) {
+            Ok(p) => p,
+            Err(e) => {
+                debug!("Unable to load cached cover {:?}: {}", &path, e);
+                return None;
+            }
+        };
+
+        let res = CoverArt::new(&pixbuf, palette, Some(path));
+
+        let mut cover_cache = CoverCache::global().lock().unwrap();
+        Some(cover_cache.add_entry(uuid, res))
+    }
+
+    // Drops the cover from both the memory and the disk caches, so that
+    // it's loaded again from the songs
+    pub fn invalidate(&mut self, uuid: &str) {
+        self.entries.remove(uuid);
+
+        let mut path = utils::cache_dir("covers");
+        path.push(format!("{}.png", uuid));
+        if let Err(e) = std::fs::remove_file(&path) {
+            debug!("Unable to remove cached cover {:?}: {}", &path, e);
+        }
+    }
+
+    // Covers still used by a song are kept, so that we do not load them
+    // a second time
     pub fn clear(&mut self) {
-        self.entries.clear();
+        for entry in self.entries.values_mut() {
+            entry.retained = None;
+        }
+        self.entries.retain(|_, e| e.cover.strong_count() > 0);
     }
 }
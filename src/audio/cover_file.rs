@@ -0,0 +1,99 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::Cursor;
+
+use gtk::{gio, glib, prelude::*};
+use lofty::{TagExt, TaggedFileExt};
+use log::debug;
+
+use crate::audio::song::read_tagged_file;
+
+// The name of the cover file we write next to the songs
+const COVER_FILE: &str = "cover.jpg";
+
+// The file extension matching the format of the image data
+pub fn cover_extension(data: &[u8]) -> &'static str {
+    let (content_type, _) = gio::content_type_guess(None::<&str>, data);
+    match gio::content_type_get_mime_type(&content_type).as_deref() {
+        Some("image/png") => "png",
+        Some("image/gif") => "gif",
+        Some("image/webp") => "webp",
+        Some("image/bmp") => "bmp",
+        _ => "jpg",
+    }
+}
+
+// The front cover stored in the tags of a song, at its original size;
+// this blocks, so it must be called off the main thread
+pub fn embedded_cover(uri: &str) -> Option<glib::Bytes> {
+    let file = gio::File::for_uri(uri);
+    let tagged_file = read_tagged_file(&file).ok()?;
+
+    let pictures: Vec<&lofty::Picture> = tagged_file
+        .tags()
+        .iter()
+        .flat_map(|tag| tag.pictures())
+        .collect();
+    let picture = pictures
+        .iter()
+        .find(|p| p.pic_type() == lofty::PictureType::CoverFront)
+        .or_else(|| pictures.first())?;
+
+    Some(glib::Bytes::from(picture.data()))
+}
+
+// Replaces the front cover in the tags of a song; only local files can
+// be written. This blocks, so it must be called off the main thread
+pub fn embed_cover(uri: &str, data: &[u8]) -> Result<(), String> {
+    let path = gio::File::for_uri(uri)
+        .path()
+        .ok_or_else(|| format!("Unable to write tags of '{}'", uri))?;
+
+    let mut picture =
+        lofty::Picture::from_reader(&mut Cursor::new(data)).map_err(|e| e.to_string())?;
+    picture.set_pic_type(lofty::PictureType::CoverFront);
+
+    let mut tagged_file = lofty::read_from_path(&path).map_err(|e| e.to_string())?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(lofty::Tag::new(tag_type));
+    }
+
+    let tag = tagged_file.primary_tag_mut().unwrap();
+    tag.remove_picture_type(lofty::PictureType::CoverFront);
+    tag.push_picture(picture);
+
+    debug!("Embedding cover in '{}'", uri);
+
+    tag.save_to_path(&path).map_err(|e| e.to_string())
+}
+
+// Writes the cover file inside the given folder, converting the image to
+// JPEG if needed; this blocks, so it must be called off the main thread
+pub fn save_cover_file(folder_uri: &str, data: &glib::Bytes) -> Result<(), String> {
+    let (content_type, _) = gio::content_type_guess(None::<&str>, data);
+    let data = if gio::content_type_is_mime_type(&content_type, "image/jpeg") {
+        data.clone()
+    } else {
+        let stream = gio::MemoryInputStream::from_bytes(data);
+        let pixbuf = gdk_pixbuf::Pixbuf::from_stream(&stream, gio::Cancellable::NONE)
+            .map_err(|e| e.to_string())?;
+        let buffer = pixbuf
+            .save_to_bufferv("jpeg", &[("quality", "90")])
+            .map_err(|e| e.to_string())?;
+        glib::Bytes::from_owned(buffer)
+    };
+
+    let file = gio::File::for_uri(folder_uri).child(COVER_FILE);
+    debug!("Writing cover file '{}'", file.uri());
+    file.replace_contents(
+        &data,
+        None,
+        false,
+        gio::FileCreateFlags::REPLACE_DESTINATION,
+        gio::Cancellable::NONE,
+    )
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
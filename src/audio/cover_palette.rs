@@ -0,0 +1,305 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use color_thief::{get_palette, ColorFormat};
+use gtk::gdk;
+
+// The text on top of the palette must be readable; this is the WCAG
+// ratio for normal text
+const MIN_CONTRAST: f32 = 4.5;
+
+// The foreground colors of the light and dark Adwaita styles
+const LIGHT_FG: [f32; 4] = [0.0, 0.0, 0.0, 0.8];
+const DARK_FG: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+const MAX_COLORS: u8 = 4;
+
+// The colors we use to style the window, derived from the cover art;
+// we keep one set of colors for each style
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoverPalette {
+    pub light: Vec<gdk::RGBA>,
+    pub dark: Vec<gdk::RGBA>,
+}
+
+impl CoverPalette {
+    pub fn from_pixbuf(pixbuf: &gdk_pixbuf::Pixbuf) -> Option<Self> {
+        let format = if pixbuf.has_alpha() {
+            ColorFormat::Rgba
+        } else {
+            ColorFormat::Rgb
+        };
+
+        // color-thief expects tightly packed rows
+        let bytes = pixbuf.read_pixel_bytes();
+        let n_channels = pixbuf.n_channels() as usize;
+        let width = pixbuf.width() as usize;
+        let rowstride = pixbuf.rowstride() as usize;
+        let pixels: Vec<u8> = bytes
+            .chunks(rowstride)
+            .flat_map(|row| &row[..(width * n_channels).min(row.len())])
+            .copied()
+            .collect();
+
+        let palette = get_palette(&pixels, format, 5, MAX_COLORS).ok()?;
+        let colors: Vec<gdk::RGBA> = palette
+            .iter()
+            .map(|c| {
+                gdk::RGBA::new(
+                    c.r as f32 / 255.0,
+                    c.g as f32 / 255.0,
+                    c.b as f32 / 255.0,
+                    1.0,
+                )
+            })
+            .collect();
+
+        if colors.is_empty() {
+            return None;
+        }
+
+        Some(CoverPalette::from_colors(&colors))
+    }
+
+    // Adjusts the lightness of each color until the text of each style
+    // is readable on top of it, keeping its hue and saturation
+    pub fn from_colors(colors: &[gdk::RGBA]) -> Self {
+        Self {
+            light: colors.iter().map(|c| readable_color(c, false)).collect(),
+            dark: colors.iter().map(|c| readable_color(c, true)).collect(),
+        }
+    }
+
+    pub fn colors(&self, dark: bool) -> &[gdk::RGBA] {
+        if dark {
+            &self.dark
+        } else {
+            &self.light
+        }
+    }
+}
+
+fn foreground(dark: bool) -> gdk::RGBA {
+    let fg = if dark { DARK_FG } else { LIGHT_FG };
+    gdk::RGBA::new(fg[0], fg[1], fg[2], fg[3])
+}
+
+// Blends a translucent color on top of an opaque one
+fn composite(fg: &gdk::RGBA, bg: &gdk::RGBA) -> gdk::RGBA {
+    let a = fg.alpha();
+    gdk::RGBA::new(
+        fg.red() * a + bg.red() * (1.0 - a),
+        fg.green() * a + bg.green() * (1.0 - a),
+        fg.blue() * a + bg.blue() * (1.0 - a),
+        1.0,
+    )
+}
+
+// See: https://www.w3.org/TR/WCAG21/#dfn-relative-luminance
+fn relative_luminance(color: &gdk::RGBA) -> f32 {
+    let linear = |c: f32| {
+        if c <= 0.03928 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+
+    0.2126 * linear(color.red()) + 0.7152 * linear(color.green()) + 0.0722 * linear(color.blue())
+}
+
+// See: https://www.w3.org/TR/WCAG21/#dfn-contrast-ratio
+pub fn contrast_ratio(fg: &gdk::RGBA, bg: &gdk::RGBA) -> f32 {
+    let fg = composite(fg, bg);
+    let l1 = relative_luminance(&fg);
+    let l2 = relative_luminance(bg);
+    (l1.max(l2) + 0.05) / (l1.min(l2) + 0.05)
+}
+
+fn rgb_to_hsl(color: &gdk::RGBA) -> (f32, f32, f32) {
+    let (r, g, b) = (color.red(), color.green(), color.blue());
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+
+    if max == min {
+        return (0.0, 0.0, l);
+    }
+
+    let d = max - min;
+    let s = if l > 0.5 {
+        d / (2.0 - max - min)
+    } else {
+        d / (max + min)
+    };
+    let h = if max == r {
+        (g - b) / d + if g < b { 6.0 } else { 0.0 }
+    } else if max == g {
+        (b - r) / d + 2.0
+    } else {
+        (r - g) / d + 4.0
+    };
+
+    (h / 6.0, s, l)
+}
+
+fn hsl_to_rgb(h: f32, s: f32, l: f32) -> gdk::RGBA {
+    if s == 0.0 {
+        return gdk::RGBA::new(l, l, l, 1.0);
+    }
+
+    let q = if l < 0.5 {
+        l * (1.0 + s)
+    } else {
+        l + s - l * s
+    };
+    let p = 2.0 * l - q;
+    let hue = |t: f32| {
+        let t = t.rem_euclid(1.0);
+        if t < 1.0 / 6.0 {
+            p + (q - p) * 6.0 * t
+        } else if t < 1.0 / 2.0 {
+            q
+        } else if t < 2.0 / 3.0 {
+            p + (q - p) * (2.0 / 3.0 - t) * 6.0
+        } else {
+            p
+        }
+    };
+
+    gdk::RGBA::new(hue(h + 1.0 / 3.0), hue(h), hue(h - 1.0 / 3.0), 1.0)
+}
+
+// The light style has dark text, so we make the colors lighter; the dark
+// style has light text, so we make them darker. We look for the smallest
+// change in lightness that gives us enough contrast
+fn readable_color(color: &gdk::RGBA, dark: bool) -> gdk::RGBA {
+    let fg = foreground(dark);
+    let color = gdk::RGBA::new(color.red(), color.green(), color.blue(), 1.0);
+    if contrast_ratio(&fg, &color) >= MIN_CONTRAST {
+        return color;
+    }
+
+    let (h, s, l) = rgb_to_hsl(&color);
+
+    // The extremes always have enough contrast, as they are white for
+    // the light style, and black for the dark style
+    let (mut readable, mut unreadable) = if dark { (0.0, l) } else { (1.0, l) };
+    for _ in 0..16 {
+        let mid = (readable + unreadable) / 2.0;
+        if contrast_ratio(&fg, &hsl_to_rgb(h, s, mid)) >= MIN_CONTRAST {
+            readable = mid;
+        } else {
+            unreadable = mid;
+        }
+    }
+
+    hsl_to_rgb(h, s, readable)
+}
+
+#[cfg(test)]
+mod tests {
+    use gtk::glib;
+
+    use super::*;
+
+    const SIZE: usize = 64;
+
+    // An image made of vertical bands of the given colors, with a bit of
+    // noise so that it looks less synthetic to the quantizer
+    fn image(colors: &[[u8; 3]]) -> gdk_pixbuf::Pixbuf {
+        let mut data = Vec::with_capacity(SIZE * SIZE * 3);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let color = colors[x * colors.len() / SIZE];
+                let noise = ((x + y) % 4) as u8;
+                data.extend(color.iter().map(|c| c.saturating_sub(noise)));
+            }
+        }
+
+        gdk_pixbuf::Pixbuf::from_bytes(
+            &glib::Bytes::from_owned(data),
+            gdk_pixbuf::Colorspace::Rgb,
+            false,
+            8,
+            SIZE as i32,
+            SIZE as i32,
+            (SIZE * 3) as i32,
+        )
+    }
+
+    fn assert_readable(palette: &CoverPalette) {
+        for dark in [false, true] {
+            let fg = foreground(dark);
+            for color in palette.colors(dark) {
+                let ratio = contrast_ratio(&fg, color);
+                assert!(
+                    ratio >= MIN_CONTRAST,
+                    "contrast {ratio} of {color} is too low (dark: {dark})"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn contrast() {
+        let black = gdk::RGBA::new(0.0, 0.0, 0.0, 1.0);
+        let white = gdk::RGBA::new(1.0, 1.0, 1.0, 1.0);
+        assert!((contrast_ratio(&black, &white) - 21.0).abs() < 0.01);
+        assert!((contrast_ratio(&white, &white) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn hsl_round_trip() {
+        let color = gdk::RGBA::new(0.8, 0.2, 0.4, 1.0);
+        let (h, s, l) = rgb_to_hsl(&color);
+        let res = hsl_to_rgb(h, s, l);
+        assert!((res.red() - color.red()).abs() < 0.001);
+        assert!((res.green() - color.green()).abs() < 0.001);
+        assert!((res.blue() - color.blue()).abs() < 0.001);
+    }
+
+    #[test]
+    fn light_cover() {
+        let palette = CoverPalette::from_pixbuf(&image(&[[255, 255, 255], [250, 245, 235]]));
+        let palette = palette.expect("Unable to compute palette");
+        assert_readable(&palette);
+
+        // Light colors are already fine for the light style, but need
+        // to be darkened for the dark one
+        assert_ne!(palette.light, palette.dark);
+    }
+
+    #[test]
+    fn dark_cover() {
+        let palette = CoverPalette::from_pixbuf(&image(&[[0, 0, 0], [20, 10, 30]]));
+        assert_readable(&palette.expect("Unable to compute palette"));
+    }
+
+    #[test]
+    fn saturated_cover() {
+        let colors = [[255, 0, 0], [0, 0, 255], [255, 255, 0], [0, 255, 0]];
+        let palette = CoverPalette::from_pixbuf(&image(&colors));
+        assert_readable(&palette.expect("Unable to compute palette"));
+    }
+
+    #[test]
+    fn keeps_hue() {
+        let blue = gdk::RGBA::new(0.0, 0.0, 1.0, 1.0);
+        let palette = CoverPalette::from_colors(&[blue]);
+        assert_readable(&palette);
+
+        for color in [&palette.light[0], &palette.dark[0]] {
+            let (h, ..) = rgb_to_hsl(color);
+            assert!((h - 2.0 / 3.0).abs() < 0.01, "hue of {color} changed");
+        }
+    }
+
+    #[test]
+    fn readable_colors_are_unchanged() {
+        let gray = gdk::RGBA::new(0.85, 0.85, 0.85, 1.0);
+        let palette = CoverPalette::from_colors(&[gray]);
+        assert_eq!(palette.light[0], gray);
+        assert_ne!(palette.dark[0], gray);
+    }
+}
@@ -0,0 +1,270 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashSet, rc::Rc};
+
+use gtk::{gio, glib, prelude::*};
+use log::{debug, warn};
+
+use crate::utils;
+
+// A file listing the names of the files and folders to skip, one glob
+// pattern per line; the patterns apply to the folder containing the
+// file and to all its sub-folders. An empty file skips the whole folder
+const IGNORE_FILE: &str = ".amberolignore";
+
+#[derive(Clone, Copy, Debug)]
+pub struct ScanOptions {
+    pub recursive: bool,
+    pub follow_symlinks: bool,
+}
+
+impl ScanOptions {
+    // GSettings cannot be used off the main thread, so we read the
+    // options before scanning
+    pub fn new(recursive: bool) -> Self {
+        Self {
+            recursive,
+            follow_symlinks: utils::settings_manager().boolean("follow-symlinks"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScanResult {
+    // The URIs of the audio files, in the order in which they appear
+    // in each folder
+    pub files: Vec<String>,
+    // The URIs of the folders we scanned
+    pub folders: Vec<String>,
+    // The URIs of the folders we could not read
+    pub skipped: Vec<String>,
+}
+
+struct IgnoreRules {
+    parent: Option<Rc<IgnoreRules>>,
+    patterns: Vec<glib::PatternSpec>,
+}
+
+impl IgnoreRules {
+    fn matches(&self, name: &str) -> bool {
+        self.patterns.iter().any(|p| p.matches_string(name))
+            || self.parent.as_ref().is_some_and(|p| p.matches(name))
+    }
+}
+
+struct Scanner<'a> {
+    options: ScanOptions,
+    cancellable: &'a gio::Cancellable,
+    progress: Option<&'a dyn Fn(usize)>,
+    // The identifiers of the folders we visited, so that symbolic links
+    // pointing to a parent folder do not send us into a loop
+    visited: HashSet<String>,
+    result: ScanResult,
+}
+
+impl Scanner<'_> {
+    fn query_flags(&self) -> gio::FileQueryInfoFlags {
+        if self.options.follow_symlinks {
+            gio::FileQueryInfoFlags::NONE
+        } else {
+            gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS
+        }
+    }
+
+    fn folder_id(&self, folder: &gio::File) -> String {
+        folder
+            .query_info("id::file", self.query_flags(), Some(self.cancellable))
+            .ok()
+            .and_then(|info| info.attribute_string("id::file"))
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| folder.uri().to_string())
+    }
+
+    // Returns None if the folder must be skipped entirely
+    fn load_ignore_rules(
+        folder: &gio::File,
+        parent: Option<Rc<IgnoreRules>>,
+    ) -> Option<Option<Rc<IgnoreRules>>> {
+        let ignore_file = folder.child(IGNORE_FILE);
+        let contents = match ignore_file.load_contents(gio::Cancellable::NONE) {
+            Ok((contents, _)) => contents,
+            Err(_) => return Some(parent),
+        };
+
+        let patterns: Vec<glib::PatternSpec> = String::from_utf8_lossy(&contents)
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(glib::PatternSpec::new)
+            .collect();
+
+        if patterns.is_empty() {
+            debug!("Ignoring folder '{}'", folder.uri());
+            return None;
+        }
+
+        Some(Some(Rc::new(IgnoreRules { parent, patterns })))
+    }
+
+    fn scan(&mut self, base: &gio::File, folder: &gio::File, rules: Option<Rc<IgnoreRules>>) {
+        if self.cancellable.is_cancelled() {
+            return;
+        }
+
+        if !self.visited.insert(self.folder_id(folder)) {
+            debug!("Skipping folder '{}': already scanned", folder.uri());
+            return;
+        }
+
+        let rules = match Scanner::load_ignore_rules(folder, rules) {
+            Some(rules) => rules,
+            None => return,
+        };
+
+        let enumerator = match folder.enumerate_children(
+            "standard::name,standard::type,standard::fast-content-type",
+            self.query_flags(),
+            Some(self.cancellable),
+        ) {
+            Ok(e) => e,
+            Err(e) => {
+                if !self.cancellable.is_cancelled() {
+                    warn!("Unable to enumerate folder '{}': {}", folder.uri(), e);
+                    self.result.skipped.push(folder.uri().to_string());
+                }
+                return;
+            }
+        };
+
+        self.result.folders.push(folder.uri().to_string());
+
+        let mut files = Vec::new();
+        let mut subfolders = Vec::new();
+        for info in enumerator {
+            let info = match info {
+                Ok(info) => info,
+                Err(e) => {
+                    warn!("Unable to read an entry of '{}': {}", folder.uri(), e);
+                    continue;
+                }
+            };
+
+            let name = info.name();
+            let name = name.to_string_lossy();
+            if name == IGNORE_FILE || rules.as_ref().is_some_and(|r| r.matches(&name)) {
+                continue;
+            }
+
+            match info.file_type() {
+                gio::FileType::Directory if self.options.recursive => {
+                    subfolders.push(folder.child(info.name()));
+                }
+                gio::FileType::Regular => {
+                    // Backends that cannot guess the content type get to
+                    // keep all files; the song loader will skip them
+                    let child = folder.child(info.name());
+                    let is_audio =
+                        info.attribute_string("standard::fast-content-type")
+                            .map_or(true, |ct| {
+                                gio::content_type_is_mime_type(&ct, "audio/*")
+                                    && !utils::is_radio_playlist(&child, Some(&ct))
+                            });
+                    if is_audio {
+                        files.push(child);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // gio::FileEnumerator has no guaranteed order, so we should
+        // rely on the basename being formatted in a way that gives us an
+        // implicit order; if anything, this will queue songs in the same
+        // order in which they appear in the directory when browsing its
+        // contents
+        files.sort_by(|a, b| utils::cmp_two_files(Some(base), a, b));
+        self.result
+            .files
+            .extend(files.iter().map(|f| f.uri().to_string()));
+
+        if let Some(progress) = self.progress {
+            progress(self.result.files.len());
+        }
+
+        subfolders.sort_by(|a, b| utils::cmp_two_files(Some(base), a, b));
+        for subfolder in subfolders {
+            self.scan(base, &subfolder, rules.clone());
+        }
+    }
+}
+
+// Lists the audio files inside the given folder, skipping the folders
+// we cannot read; this blocks, so it must be called off the main thread.
+// If given, the progress function is called with the number of files
+// found so far
+pub fn scan_folder(
+    uri: &str,
+    options: ScanOptions,
+    cancellable: &gio::Cancellable,
+    progress: Option<&dyn Fn(usize)>,
+) -> ScanResult {
+    let now = std::time::Instant::now();
+
+    let mut scanner = Scanner {
+        options,
+        cancellable,
+        progress,
+        visited: HashSet::new(),
+        result: ScanResult::default(),
+    };
+
+    let folder = gio::File::for_uri(uri);
+    scanner.scan(&folder, &folder, None);
+
+    debug!(
+        "Folder enumeration: {} us (recursive: {}), total files: {}, skipped folders: {}",
+        now.elapsed().as_micros(),
+        options.recursive,
+        scanner.result.files.len(),
+        scanner.result.skipped.len(),
+    );
+
+    scanner.result
+}
+
+// Scans the given folders in a separate thread, and calls the progress
+// function on the main thread with the number of files found so far;
+// the results are in the same order as the folders
+pub async fn scan_folders<F: Fn(usize)>(
+    folders: &[gio::File],
+    options: ScanOptions,
+    cancellable: &gio::Cancellable,
+    progress: F,
+) -> Vec<ScanResult> {
+    let (sender, receiver) = async_channel::unbounded();
+
+    let uris: Vec<String> = folders.iter().map(|f| f.uri().to_string()).collect();
+    let thread_cancellable = cancellable.clone();
+    let handle = gio::spawn_blocking(move || {
+        let mut results = Vec::with_capacity(uris.len());
+        let mut n_files = 0;
+        for uri in uris {
+            // Each folder counts from zero, but the progress is for all
+            // of them
+            let report = |n: usize| {
+                let _ = sender.send_blocking(n_files + n);
+            };
+            let res = scan_folder(&uri, options, &thread_cancellable, Some(&report));
+            n_files += res.files.len();
+            results.push(res);
+        }
+        results
+    });
+
+    while let Ok(n) = receiver.recv().await {
+        progress(n);
+    }
+
+    handle.await.unwrap_or_default()
+}
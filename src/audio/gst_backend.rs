@@ -1,19 +1,38 @@
 // SPDX-FileCopyrightText: 2022  Emmanuele Bassi
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
 use async_channel::Sender;
 use glib::clone;
 use gst::prelude::*;
 use gtk::glib;
 use log::{debug, error, warn};
 
-use crate::audio::{PlaybackAction, ReplayGainMode, SeekDirection};
+use crate::{
+    audio::{PlaybackAction, PlaybackError, ReplayGainMode, SeekDirection},
+    utils,
+};
+
+// The number of frequency bands of the spectrum
+const SPECTRUM_BANDS: u32 = 64;
+// How often we sample the spectrum, in nanoseconds
+const SPECTRUM_INTERVAL: u64 = 50_000_000;
+// The magnitude of the quietest band we show, in decibels
+const SPECTRUM_THRESHOLD: f32 = -80.0;
 
 #[derive(Debug)]
 pub struct GstBackend {
     sender: Sender<PlaybackAction>,
     gst_player: gst_player::Player,
     replaygain: Option<GstReplayGain>,
+    spectrum: Option<GstSpectrum>,
+    // The plugin the current song is missing, if any; the player only
+    // tells us that the playback failed
+    missing_plugin: Arc<Mutex<Option<String>>>,
 }
 
 #[derive(Debug)]
@@ -62,6 +81,126 @@ impl GstReplayGain {
     }
 }
 
+// Analyses the frequencies of the audio right before it's played; the
+// element is only linked while the spectrum is visible, so that the
+// audio is not analysed otherwise
+#[derive(Debug)]
+pub struct GstSpectrum {
+    spectrum: gst::Element,
+    audio_sink: gst::Element,
+    sink_pad: gst::GhostPad,
+}
+
+impl GstSpectrum {
+    pub fn new(playbin: &gst::Element) -> Result<GstSpectrum, Box<dyn std::error::Error>> {
+        let sink_str = format!(
+            "spectrum name=spectrum bands={} threshold={} interval={} ! autoaudiosink name=audiosink",
+            SPECTRUM_BANDS, SPECTRUM_THRESHOLD as i32, SPECTRUM_INTERVAL
+        );
+        let sink_bin = gst::parse::bin_from_description(&sink_str, true)?;
+        let spectrum = sink_bin
+            .by_name("spectrum")
+            .ok_or("Missing spectrum element")?;
+        let audio_sink = sink_bin
+            .by_name("audiosink")
+            .ok_or("Missing audio sink element")?;
+        let sink_pad = sink_bin
+            .static_pad("sink")
+            .and_then(|p| p.downcast::<gst::GhostPad>().ok())
+            .ok_or("Missing sink pad")?;
+
+        playbin.set_property("audio-sink", &sink_bin);
+
+        // Nothing flows through the bin yet, so we can unlink the spectrum
+        // right away
+        GstSpectrum::relink(&spectrum, &audio_sink, &sink_pad, false);
+
+        Ok(Self {
+            spectrum,
+            audio_sink,
+            sink_pad,
+        })
+    }
+
+    fn relink(
+        spectrum: &gst::Element,
+        audio_sink: &gst::Element,
+        sink_pad: &gst::GhostPad,
+        enabled: bool,
+    ) {
+        let spectrum_pad = spectrum.static_pad("sink").unwrap();
+        if (sink_pad.target() == Some(spectrum_pad.clone())) == enabled {
+            return;
+        }
+
+        let res = if enabled {
+            sink_pad
+                .set_target(Some(&spectrum_pad))
+                .and_then(|_| spectrum.link(audio_sink))
+        } else {
+            spectrum.unlink(audio_sink);
+            sink_pad.set_target(audio_sink.static_pad("sink").as_ref())
+        };
+
+        match res {
+            Ok(_) => debug!("Spectrum analysis enabled: {}", enabled),
+            Err(err) => warn!("Unable to relink the spectrum: {}", err),
+        }
+    }
+
+    // The pads are relinked while no audio flows through them, which
+    // happens right away if the pipeline is not playing
+    pub fn set_enabled(&self, enabled: bool) {
+        let spectrum = self.spectrum.clone();
+        let audio_sink = self.audio_sink.clone();
+        self.sink_pad
+            .add_probe(gst::PadProbeType::IDLE, move |pad, _| {
+                GstSpectrum::relink(&spectrum, &audio_sink, pad, enabled);
+                gst::PadProbeReturn::Remove
+            });
+    }
+}
+
+// The description of the plugin in a "missing-plugin" message; this is
+// the same check as gst_missing_plugin_message_is_missing_plugin_message()
+fn missing_plugin_name(s: &gst::StructureRef) -> Option<String> {
+    if !s.has_name("missing-plugin") {
+        return None;
+    }
+
+    let res = s
+        .get::<String>("name")
+        .or_else(|_| s.get::<String>("detail"))
+        .unwrap_or_default();
+    Some(res)
+}
+
+// How long until the audio described by a spectrum message is played;
+// the element analyses the audio before it reaches the sink
+fn spectrum_delay(msg: &gst::Message, s: &gst::StructureRef) -> Option<Duration> {
+    let element = msg.src()?.downcast_ref::<gst::Element>()?;
+    let now = element.clock()?.time()?.checked_sub(element.base_time()?)?;
+    let end = s.get::<u64>("running-time").ok()? + s.get::<u64>("duration").ok()?;
+
+    end.checked_sub(now.nseconds()).map(Duration::from_nanos)
+}
+
+// The magnitude of each band in a spectrum message, between 0 and 1
+fn spectrum_magnitudes(s: &gst::StructureRef) -> Option<Vec<f32>> {
+    if !s.has_name("spectrum") {
+        return None;
+    }
+
+    let magnitude = s.get::<gst::List>("magnitude").ok()?;
+    let res = magnitude
+        .iter()
+        .filter_map(|v| v.get::<f32>().ok())
+        .map(|db| ((db - SPECTRUM_THRESHOLD) / -SPECTRUM_THRESHOLD).clamp(0.0, 1.0))
+        .collect();
+
+    Some(res)
+}
+
 impl GstBackend {
     pub fn new(sender: Sender<PlaybackAction>) -> Self {
         let dispatcher = gst_player::PlayerGMainContextSignalDispatcher::new(None);
@@ -75,10 +214,20 @@ impl GstBackend {
         config.set_position_update_interval(250);
         gst_player.set_config(config).unwrap();
 
+        let spectrum = match GstSpectrum::new(&gst_player.pipeline()) {
+            Ok(s) => Some(s),
+            Err(err) => {
+                warn!("Unable to set up the spectrum: {}", err);
+                None
+            }
+        };
+
         let res = Self {
             sender,
             gst_player,
             replaygain: GstReplayGain::new().ok(),
+            spectrum,
+            missing_plugin: Arc::default(),
         };
 
         res.setup_signals();
@@ -91,6 +240,30 @@ impl GstBackend {
             warn!("GStreamer warning: {}", warn);
         });
 
+        self.gst_player.connect_error(
+            clone!(@strong self.sender as sender, @strong self.missing_plugin as missing_plugin => move |player, err| {
+                warn!("GStreamer error: {}", err);
+
+                // The pipeline posts a message for the missing plugin
+                // before failing
+                let missing_codec = match missing_plugin.lock().unwrap().take() {
+                    Some(plugin) => {
+                        debug!("Missing plugin: {}", plugin);
+                        true
+                    }
+                    None => false,
+                };
+                let error = PlaybackError {
+                    uri: player.uri().map(|uri| uri.to_string()),
+                    message: err.message().to_string(),
+                    missing_codec,
+                };
+                if let Err(e) = sender.send_blocking(PlaybackAction::PlaybackError(error)) {
+                    error!("Failed to send PlaybackError: {e}");
+                }
+            }),
+        );
+
         self.gst_player
             .connect_end_of_stream(clone!(@strong self.sender as sender => move |_| {
                 if let Err(e) = sender.send_blocking(PlaybackAction::PlayNext) {
@@ -109,6 +282,65 @@ impl GstBackend {
             }),
         );
 
+        self.gst_player.connect_buffering(
+            clone!(@strong self.sender as sender => move |_, percent| {
+                if let Err(e) = sender.send_blocking(PlaybackAction::Buffering(percent)) {
+                    error!("Failed to send Buffering({percent}): {e}");
+                }
+            }),
+        );
+
+        // Internet radio stations send the title of the current track
+        // as ICY metadata, which ends up in the tags of the media info
+        self.gst_player.connect_media_info_updated(
+            clone!(@strong self.sender as sender => move |_, info| {
+                if !utils::is_stream_uri(&info.uri()) {
+                    return;
+                }
+
+                if let Some(tags) = info.tags() {
+                    let title = tags.get::<gst::tags::Title>().map(|t| t.get().to_string());
+                    let station = tags
+                        .get::<gst::tags::Organization>()
+                        .map(|t| t.get().to_string());
+                    if title.is_none() && station.is_none() {
+                        return;
+                    }
+
+                    let action = PlaybackAction::UpdateStreamMetadata(title, station);
+                    if let Err(e) = sender.send_blocking(action) {
+                        error!("Failed to send UpdateStreamMetadata: {e}");
+                    }
+                }
+            }),
+        );
+
+        // The player does not forward element messages, so we look at the
+        // bus of its pipeline; this happens in the player's own thread
+        if let Some(bus) = self.gst_player.pipeline().bus() {
+            bus.connect_message(
+                Some("element"),
+                clone!(@strong self.sender as sender, @strong self.missing_plugin as missing_plugin => move |_, msg| {
+                    if let gst::MessageView::Element(element) = msg.view() {
+                        let s = match element.structure() {
+                            Some(s) => s,
+                            None => return,
+                        };
+
+                        if let Some(plugin) = missing_plugin_name(s) {
+                            missing_plugin.lock().unwrap().replace(plugin);
+                        } else if let Some(bands) = spectrum_magnitudes(s) {
+                            let delay = spectrum_delay(msg, s).unwrap_or_default();
+                            let action = PlaybackAction::Spectrum(bands, Instant::now() + delay);
+                            if let Err(e) = sender.send_blocking(action) {
+                                error!("Failed to send Spectrum: {e}");
+                            }
+                        }
+                    }
+                }),
+            );
+        }
+
         self.gst_player.connect_volume_changed(
             clone!(@strong self.sender as sender => move |player| {
                 let volume = gst_audio::StreamVolume::convert_volume(
@@ -126,6 +358,7 @@ impl GstBackend {
     pub fn set_song_uri(&self, uri: Option<&str>) {
         // FIXME: https://gitlab.freedesktop.org/gstreamer/gstreamer/-/issues/1124
         if uri.is_some() {
+            self.missing_plugin.lock().unwrap().take();
             self.gst_player.set_uri(uri);
         }
     }
@@ -191,4 +424,10 @@ impl GstBackend {
     pub fn replaygain_available(&self) -> bool {
         self.replaygain.is_some()
     }
+
+    pub fn set_spectrum_enabled(&self, enabled: bool) {
+        if let Some(ref s) = self.spectrum {
+            s.set_enabled(enabled);
+        }
+    }
 }
@@ -54,6 +54,7 @@ impl Controller for InhibitController {
     }
 
     fn set_song(&self, _song: &Song) {}
+    fn update_song_metadata(&self, _song: &Song) {}
     fn set_position(&self, _position: u64) {}
     fn set_repeat_mode(&self, _mode: RepeatMode) {}
 }
@@ -0,0 +1,510 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+};
+
+use glib::clone;
+use gtk::{gio, glib, prelude::*, subclass::prelude::*};
+use log::{debug, warn};
+
+use crate::{
+    audio::{self, scan_folder, LibraryGroup, LibraryGroupKind, ScanOptions, SmartPlaylist, Song},
+    i18n::i18n,
+    utils,
+};
+
+mod imp {
+    use glib::{ParamSpec, ParamSpecBoolean, ParamSpecUInt, Value};
+    use once_cell::sync::Lazy;
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct Library {
+        pub store: gio::ListStore,
+        pub songs: RefCell<HashMap<String, Song>>,
+        pub monitors: RefCell<HashMap<String, gio::FileMonitor>>,
+        pub cancellable: RefCell<gio::Cancellable>,
+        pub n_pending: Cell<u32>,
+        pub settings: gio::Settings,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for Library {
+        const NAME: &'static str = "AmberolLibrary";
+        type Type = super::Library;
+
+        fn new() -> Self {
+            Self {
+                store: gio::ListStore::new::<Song>(),
+                songs: RefCell::default(),
+                monitors: RefCell::default(),
+                cancellable: RefCell::new(gio::Cancellable::new()),
+                n_pending: Cell::new(0),
+                settings: utils::settings_manager(),
+            }
+        }
+    }
+
+    impl ObjectImpl for Library {
+        fn constructed(&self) {
+            self.parent_constructed();
+
+            let obj = self.obj();
+            self.settings.connect_changed(
+                Some("library-folders"),
+                clone!(@weak obj => move |_, _| {
+                    obj.reindex();
+                }),
+            );
+            self.settings.connect_changed(
+                Some("follow-symlinks"),
+                clone!(@weak obj => move |_, _| {
+                    obj.reindex();
+                }),
+            );
+
+            self.store
+                .connect_items_changed(clone!(@weak obj => move |_, _, _, _| {
+                    obj.notify("n-songs");
+                }));
+        }
+
+        fn properties() -> &'static [ParamSpec] {
+            static PROPERTIES: Lazy<Vec<ParamSpec>> = Lazy::new(|| {
+                vec![
+                    ParamSpecUInt::builder("n-songs").read_only().build(),
+                    ParamSpecBoolean::builder("indexing").read_only().build(),
+                    ParamSpecBoolean::builder("enabled").read_only().build(),
+                ]
+            });
+
+            PROPERTIES.as_ref()
+        }
+
+        fn property(&self, _id: usize, pspec: &ParamSpec) -> Value {
+            let obj = self.obj();
+            match pspec.name() {
+                "n-songs" => self.store.n_items().to_value(),
+                "indexing" => obj.is_indexing().to_value(),
+                "enabled" => obj.is_enabled().to_value(),
+                _ => unimplemented!(),
+            }
+        }
+    }
+}
+
+// The library is an index of all the songs inside the music folders
+// configured by the user; the folders are watched for changes, so that
+// the index is always up to date.
+//
+// Folders are enumerated, and songs loaded, off the main thread; the
+// songs are then added to a list model that can be used by the UI.
+glib::wrapper! {
+    pub struct Library(ObjectSubclass<imp::Library>);
+}
+
+impl Default for Library {
+    fn default() -> Self {
+        glib::Object::new()
+    }
+}
+
+impl Library {
+    pub fn model(&self) -> &gio::ListModel {
+        self.imp().store.upcast_ref()
+    }
+
+    pub fn n_songs(&self) -> u32 {
+        self.imp().store.n_items()
+    }
+
+    pub fn songs(&self) -> Vec<Song> {
+        self.imp()
+            .store
+            .iter::<Song>()
+            .filter_map(|s| s.ok())
+            .collect()
+    }
+
+    pub fn artists(&self) -> Vec<LibraryGroup> {
+        let mut groups: HashMap<String, Vec<Song>> = HashMap::new();
+        for song in self.songs() {
+            groups.entry(song.artist()).or_default().push(song);
+        }
+
+        sorted_groups(
+            groups
+                .into_iter()
+                .map(|(artist, songs)| LibraryGroup::new(LibraryGroupKind::Artist, &artist, songs))
+                .collect(),
+        )
+    }
+
+    // Albums with the same title in different folders are different
+    // albums; compilations have the same album title, but different
+    // artists, in the same folder
+    pub fn albums(&self) -> Vec<LibraryGroup> {
+        let mut groups: HashMap<(String, Option<String>), Vec<Song>> = HashMap::new();
+        for song in self.songs() {
+            let folder = song.file().parent().map(|p| p.uri().to_string());
+            groups.entry((song.album(), folder)).or_default().push(song);
+        }
+
+        sorted_groups(
+            groups
+                .into_iter()
+                .map(|((album, _), songs)| {
+                    let artist = songs[0].artist();
+                    let various = songs.iter().any(|s| s.artist() != artist);
+
+                    let group = LibraryGroup::new(LibraryGroupKind::Album, &album, songs);
+                    if various {
+                        group.set_subtitle(Some(&i18n("Various Artists")));
+                    } else {
+                        group.set_subtitle(Some(&artist));
+                    }
+                    group
+                })
+                .collect(),
+        )
+    }
+
+    pub fn genres(&self) -> Vec<LibraryGroup> {
+        let mut groups: HashMap<String, Vec<Song>> = HashMap::new();
+        for song in self.songs() {
+            if let Some(genre) = song.genre() {
+                groups.entry(genre).or_default().push(song);
+            }
+        }
+
+        sorted_groups(
+            groups
+                .into_iter()
+                .map(|(genre, songs)| LibraryGroup::new(LibraryGroupKind::Genre, &genre, songs))
+                .collect(),
+        )
+    }
+
+    pub fn smart_playlists(&self) -> Vec<LibraryGroup> {
+        sorted_groups(
+            SmartPlaylist::load_all()
+                .iter()
+                .map(|pls| self.evaluate(pls))
+                .collect(),
+        )
+    }
+
+    // Smart playlists are evaluated against the current contents of
+    // the library, and the current play stats
+    pub fn evaluate(&self, playlist: &SmartPlaylist) -> LibraryGroup {
+        let songs = playlist.evaluate(&self.songs());
+        LibraryGroup::for_smart_playlist(playlist, songs)
+    }
+
+    pub fn song_for_uri(&self, uri: &str) -> Option<Song> {
+        self.imp().songs.borrow().get(uri).cloned()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.folders().is_empty()
+    }
+
+    pub fn is_indexing(&self) -> bool {
+        self.imp().n_pending.get() > 0
+    }
+
+    pub fn folders(&self) -> Vec<gio::File> {
+        self.imp()
+            .settings
+            .strv("library-folders")
+            .iter()
+            .map(|uri| gio::File::for_uri(uri))
+            .collect()
+    }
+
+    fn set_folders(&self, folders: &[gio::File]) {
+        let uris: Vec<String> = folders.iter().map(|f| f.uri().to_string()).collect();
+        let uris: Vec<&str> = uris.iter().map(|s| s.as_str()).collect();
+        self.imp()
+            .settings
+            .set_strv("library-folders", uris.as_slice())
+            .expect("Unable to store the library folders");
+    }
+
+    pub fn add_folders(&self, new_folders: &[gio::File]) {
+        let mut folders = self.folders();
+
+        for folder in new_folders {
+            // Nested folders are already covered by their parent
+            if folders
+                .iter()
+                .any(|f| f.equal(folder) || folder.has_prefix(f))
+            {
+                debug!("Folder '{}' is already in the library", folder.uri());
+                continue;
+            }
+
+            folders.retain(|f| !f.has_prefix(folder));
+            folders.push(folder.clone());
+        }
+
+        self.set_folders(&folders);
+    }
+
+    pub fn remove_folder(&self, folder: &gio::File) {
+        let mut folders = self.folders();
+        folders.retain(|f| !f.equal(folder));
+        self.set_folders(&folders);
+    }
+
+    // Drops the current index, and builds a new one from the
+    // configured folders
+    pub fn reindex(&self) {
+        let imp = self.imp();
+
+        imp.cancellable.replace(gio::Cancellable::new()).cancel();
+        imp.monitors.borrow_mut().clear();
+        imp.songs.borrow_mut().clear();
+        imp.store.remove_all();
+        self.set_n_pending(0);
+
+        for folder in self.folders() {
+            self.index_folder(&folder);
+        }
+
+        self.notify("enabled");
+    }
+
+    fn set_n_pending(&self, n_pending: u32) {
+        let was_indexing = self.is_indexing();
+        self.imp().n_pending.replace(n_pending);
+        if was_indexing != self.is_indexing() {
+            self.notify("indexing");
+        }
+    }
+
+    fn begin_job(&self) {
+        self.set_n_pending(self.imp().n_pending.get() + 1);
+    }
+
+    fn end_job(&self) {
+        self.set_n_pending(self.imp().n_pending.get().saturating_sub(1));
+    }
+
+    // Enumerates the songs and the sub-folders of the given folder
+    // off the main thread, then loads the songs and starts watching
+    // every folder
+    fn index_folder(&self, folder: &gio::File) {
+        debug!("Indexing library folder '{}'", folder.uri());
+
+        let cancellable = self.imp().cancellable.borrow().clone();
+        let uri = folder.uri().to_string();
+        let options = ScanOptions::new(true);
+
+        self.begin_job();
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as library => async move {
+            let thread_cancellable = cancellable.clone();
+            let res = gio::spawn_blocking(move || {
+                scan_folder(&uri, options, &thread_cancellable, None)
+            })
+            .await;
+
+            // Jobs from a previous index do not count
+            if cancellable.is_cancelled() {
+                return;
+            }
+
+            match res {
+                Ok(res) => {
+                    for uri in res.skipped {
+                        warn!("Skipping unreadable library folder '{}'", uri);
+                    }
+
+                    for uri in res.folders {
+                        library.watch_folder(&gio::File::for_uri(&uri));
+                    }
+
+                    let files = res.files.iter().map(|uri| gio::File::for_uri(uri)).collect();
+                    library.load_files(files);
+                }
+                Err(_) => warn!("Unable to enumerate library folder"),
+            }
+
+            library.end_job();
+        }));
+    }
+
+    fn load_files(&self, files: Vec<gio::File>) {
+        if files.is_empty() {
+            return;
+        }
+
+        let cancellable = self.imp().cancellable.borrow().clone();
+        let receiver = audio::load_songs(files, &cancellable);
+
+        self.begin_job();
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as library => async move {
+            use futures::prelude::*;
+
+            let mut receiver = std::pin::pin!(receiver);
+            while let Some(batch) = receiver.next().await {
+                if cancellable.is_cancelled() {
+                    break;
+                }
+
+                let songs: Vec<Song> = batch
+                    .into_iter()
+                    .filter_map(|(_, data)| data.ok().map(Song::from_data))
+                    .collect();
+                library.add_songs(songs);
+            }
+
+            if cancellable.is_cancelled() {
+                return;
+            }
+
+            library.end_job();
+
+            // Store the metadata we just parsed
+            std::thread::spawn(|| {
+                audio::MetadataCache::save();
+                audio::PlayStats::global().lock().unwrap().save();
+            });
+        }));
+    }
+
+    fn add_songs(&self, songs: Vec<Song>) {
+        let imp = self.imp();
+
+        let mut new_songs = Vec::with_capacity(songs.len());
+        for song in songs {
+            let uri = song.uri();
+            let old = imp.songs.borrow_mut().insert(uri, song.clone());
+            let pos = old.and_then(|old| {
+                (0..imp.store.n_items())
+                    .find(|i| imp.store.item(*i).as_ref() == Some(old.upcast_ref()))
+            });
+            match pos {
+                // The file changed, so we replace the song in place
+                Some(pos) => imp.store.splice(pos, 1, &[song]),
+                None => new_songs.push(song),
+            }
+        }
+
+        // Bulk add to avoid hammering the UI with list model updates
+        imp.store.extend_from_slice(&new_songs);
+    }
+
+    // Removes the song for the given file, or all the songs inside
+    // it, if the file is a folder
+    fn remove_file(&self, file: &gio::File) {
+        let imp = self.imp();
+
+        let uri = file.uri().to_string();
+        let prefix = format!("{}/", uri.trim_end_matches('/'));
+
+        imp.monitors
+            .borrow_mut()
+            .retain(|k, _| *k != uri && !k.starts_with(&prefix));
+
+        let removed: HashSet<String> = {
+            let mut songs = imp.songs.borrow_mut();
+            let removed = songs
+                .keys()
+                .filter(|k| **k == uri || k.starts_with(&prefix))
+                .cloned()
+                .collect::<HashSet<String>>();
+            songs.retain(|k, _| !removed.contains(k));
+            removed
+        };
+
+        if !removed.is_empty() {
+            debug!("Removing {} songs from the library", removed.len());
+            imp.store.retain(|obj| {
+                let song = obj.downcast_ref::<Song>().unwrap();
+                !removed.contains(&song.uri())
+            });
+        }
+    }
+
+    fn add_file(&self, file: &gio::File) {
+        if is_folder(file) {
+            self.index_folder(file);
+        } else {
+            self.load_files(vec![file.clone()]);
+        }
+    }
+
+    fn watch_folder(&self, folder: &gio::File) {
+        let uri = folder.uri().to_string();
+        if self.imp().monitors.borrow().contains_key(&uri) {
+            return;
+        }
+
+        match folder.monitor_directory(gio::FileMonitorFlags::WATCH_MOVES, gio::Cancellable::NONE) {
+            Ok(monitor) => {
+                monitor.connect_changed(
+                    clone!(@weak self as library => move |_, file, other, event| {
+                        library.folder_changed(file, other, event);
+                    }),
+                );
+                self.imp().monitors.borrow_mut().insert(uri, monitor);
+            }
+            Err(e) => warn!("Unable to watch folder '{}': {}", uri, e),
+        }
+    }
+
+    fn folder_changed(
+        &self,
+        file: &gio::File,
+        other: Option<&gio::File>,
+        event: gio::FileMonitorEvent,
+    ) {
+        debug!("Library folder changed: {:?} '{}'", event, file.uri());
+
+        match event {
+            // New files are loaded once they have been fully written
+            gio::FileMonitorEvent::Created => {
+                if is_folder(file) {
+                    self.index_folder(file);
+                }
+            }
+            gio::FileMonitorEvent::ChangesDoneHint => {
+                if !is_folder(file) {
+                    self.load_files(vec![file.clone()]);
+                }
+            }
+            gio::FileMonitorEvent::MovedIn => self.add_file(file),
+            gio::FileMonitorEvent::Deleted | gio::FileMonitorEvent::MovedOut => {
+                self.remove_file(file)
+            }
+            gio::FileMonitorEvent::Renamed => {
+                self.remove_file(file);
+                if let Some(other) = other {
+                    self.add_file(other);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn is_folder(file: &gio::File) -> bool {
+    file.query_file_type(
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        gio::Cancellable::NONE,
+    ) == gio::FileType::Directory
+}
+
+fn sorted_groups(mut groups: Vec<LibraryGroup>) -> Vec<LibraryGroup> {
+    groups.sort_by_cached_key(|g| glib::CollationKey::from(g.title()));
+    groups
+}
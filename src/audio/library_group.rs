@@ -0,0 +1,202 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::RefCell;
+
+use gtk::{gdk, gio, glib, prelude::*, subclass::prelude::*};
+
+use crate::{
+    audio::{CoverArt, SmartPlaylist, Song},
+    i18n::ni18n_f,
+};
+
+#[derive(Clone, Copy, Debug, glib::Enum, PartialEq, Eq, Default)]
+#[enum_type(name = "AmberolLibraryGroupKind")]
+pub enum LibraryGroupKind {
+    #[default]
+    Artist = 0,
+    Album = 1,
+    Genre = 2,
+    Playlist = 3,
+}
+
+mod imp {
+    use glib::{ParamSpec, ParamSpecEnum, ParamSpecObject, ParamSpecString, ParamSpecUInt, Value};
+    use once_cell::sync::Lazy;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    pub struct LibraryGroup {
+        pub kind: RefCell<LibraryGroupKind>,
+        pub title: RefCell<String>,
+        pub subtitle: RefCell<Option<String>>,
+        pub songs: RefCell<Vec<Song>>,
+        pub smart_playlist: RefCell<Option<SmartPlaylist>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for LibraryGroup {
+        const NAME: &'static str = "AmberolLibraryGroup";
+        type Type = super::LibraryGroup;
+    }
+
+    impl ObjectImpl for LibraryGroup {
+        fn properties() -> &'static [ParamSpec] {
+            static PROPERTIES: Lazy<Vec<ParamSpec>> = Lazy::new(|| {
+                vec![
+                    ParamSpecEnum::builder::<LibraryGroupKind>("kind")
+                        .read_only()
+                        .build(),
+                    ParamSpecString::builder("title").read_only().build(),
+                    ParamSpecString::builder("subtitle").read_only().build(),
+                    ParamSpecObject::builder::<gdk::Texture>("cover")
+                        .read_only()
+                        .build(),
+                    ParamSpecUInt::builder("n-songs").read_only().build(),
+                ]
+            });
+            PROPERTIES.as_ref()
+        }
+
+        fn property(&self, _id: usize, pspec: &ParamSpec) -> Value {
+            let obj = self.obj();
+            match pspec.name() {
+                "kind" => obj.kind().to_value(),
+                "title" => obj.title().to_value(),
+                "subtitle" => obj.subtitle().to_value(),
+                "cover" => obj.cover().to_value(),
+                "n-songs" => obj.n_songs().to_value(),
+                _ => unimplemented!(),
+            }
+        }
+    }
+}
+
+// A group of songs in the library sharing the same artist, album, or
+// genre, or matching the rules of a smart playlist; the songs are kept
+// in playback order
+glib::wrapper! {
+    pub struct LibraryGroup(ObjectSubclass<imp::LibraryGroup>);
+}
+
+impl LibraryGroup {
+    pub fn new(kind: LibraryGroupKind, title: &str, mut songs: Vec<Song>) -> Self {
+        let res: Self = glib::Object::new();
+
+        songs.sort_by_cached_key(|s| sort_key(kind, s));
+
+        let imp = res.imp();
+        imp.kind.replace(kind);
+        imp.title.replace(title.to_string());
+        imp.songs.replace(songs);
+
+        res
+    }
+
+    // The songs past the limit of the playlist are dropped after
+    // sorting, so the result is stable
+    pub fn for_smart_playlist(playlist: &SmartPlaylist, songs: Vec<Song>) -> Self {
+        let res = LibraryGroup::new(LibraryGroupKind::Playlist, &playlist.name, songs);
+
+        let imp = res.imp();
+        if let Some(limit) = playlist.limit {
+            imp.songs.borrow_mut().truncate(limit as usize);
+        }
+        imp.smart_playlist.replace(Some(playlist.clone()));
+
+        res
+    }
+
+    pub fn kind(&self) -> LibraryGroupKind {
+        *self.imp().kind.borrow()
+    }
+
+    pub fn title(&self) -> String {
+        self.imp().title.borrow().clone()
+    }
+
+    pub fn subtitle(&self) -> String {
+        if let Some(subtitle) = self.imp().subtitle.borrow().as_ref() {
+            return subtitle.clone();
+        }
+
+        let n_songs = self.n_songs();
+        ni18n_f(
+            // Translators: the `{}` must be left unmodified;
+            // it will be expanded to the number of songs
+            "One song",
+            "{} songs",
+            n_songs,
+            &[&n_songs.to_string()],
+        )
+    }
+
+    pub fn set_subtitle(&self, subtitle: Option<&str>) {
+        self.imp().subtitle.replace(subtitle.map(|s| s.to_string()));
+        self.notify("subtitle");
+    }
+
+    // The cover of the first song that has one
+    pub fn cover(&self) -> Option<gdk::Texture> {
+        self.imp()
+            .songs
+            .borrow()
+            .iter()
+            .find_map(|s| s.cover_texture())
+    }
+
+    pub fn cover_art(&self) -> Option<CoverArt> {
+        self.imp().songs.borrow().iter().find_map(|s| s.cover_art())
+    }
+
+    pub fn n_songs(&self) -> u32 {
+        self.imp().songs.borrow().len() as u32
+    }
+
+    pub fn songs(&self) -> Vec<Song> {
+        self.imp().songs.borrow().clone()
+    }
+
+    pub fn smart_playlist(&self) -> Option<SmartPlaylist> {
+        self.imp().smart_playlist.borrow().clone()
+    }
+
+    pub fn model(&self) -> gio::ListStore {
+        let store = gio::ListStore::new::<Song>();
+        store.extend_from_slice(&self.imp().songs.borrow());
+        store
+    }
+}
+
+type SortKey = (
+    Option<glib::CollationKey>,
+    Option<glib::CollationKey>,
+    u32,
+    u32,
+    glib::CollationKey,
+);
+
+// Album tracks are ordered by disc and track number; the albums of an
+// artist by title; and the songs of a genre or playlist by artist
+// first. Songs without disc or track numbers go last
+fn sort_key(kind: LibraryGroupKind, song: &Song) -> SortKey {
+    let artist = match kind {
+        LibraryGroupKind::Genre | LibraryGroupKind::Playlist => {
+            Some(glib::CollationKey::from(song.artist()))
+        }
+        _ => None,
+    };
+    let album = match kind {
+        LibraryGroupKind::Album => None,
+        _ => Some(glib::CollationKey::from(song.album())),
+    };
+
+    (
+        artist,
+        album,
+        song.disc_number().unwrap_or(u32::MAX),
+        song.track_number().unwrap_or(u32::MAX),
+        glib::CollationKey::from(song.title()),
+    )
+}
@@ -0,0 +1,223 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashMap, fs, path::PathBuf, sync::Mutex};
+
+use gtk::{gdk, gio, glib, prelude::*};
+use log::{debug, warn};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::{audio::CoverPalette, utils};
+
+// The identity of a file on disk; if either the modification time or
+// the size change, we assume the tags changed as well
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    mtime: i64,
+    size: i64,
+}
+
+impl FileStamp {
+    pub fn new(mtime: i64, size: i64) -> Self {
+        FileStamp { mtime, size }
+    }
+
+    pub fn from_info(info: &gio::FileInfo) -> Option<Self> {
+        let mtime = info.modification_date_time()?.to_unix();
+        Some(FileStamp {
+            mtime,
+            size: info.size(),
+        })
+    }
+
+    pub fn mtime(&self) -> i64 {
+        self.mtime
+    }
+
+    pub fn size(&self) -> i64 {
+        self.size
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedMetadata {
+    pub stamp: FileStamp,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub disc_number: Option<u32>,
+    pub track_number: Option<u32>,
+    pub year: Option<u32>,
+    pub duration: u64,
+    pub uuid: Option<String>,
+    pub cover_uuid: Option<String>,
+    // The key of the cover options used to find the cover
+    pub cover_options: String,
+    pub palette: Option<Vec<[f32; 4]>>,
+    pub dark_palette: Option<Vec<[f32; 4]>>,
+}
+
+impl CachedMetadata {
+    pub fn palette(&self) -> Option<CoverPalette> {
+        match (&self.palette, &self.dark_palette) {
+            (Some(light), Some(dark)) => Some(CoverPalette {
+                light: colors_from_cache(light),
+                dark: colors_from_cache(dark),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn colors_from_cache(colors: &[[f32; 4]]) -> Vec<gdk::RGBA> {
+    colors
+        .iter()
+        .map(|c| gdk::RGBA::new(c[0], c[1], c[2], c[3]))
+        .collect()
+}
+
+pub fn colors_to_cache(colors: &[gdk::RGBA]) -> Vec<[f32; 4]> {
+    colors
+        .iter()
+        .map(|c| [c.red(), c.green(), c.blue(), c.alpha()])
+        .collect()
+}
+
+// Bump this whenever the fields of CachedMetadata change, so that
+// the songs are parsed again
+const METADATA_VERSION: u32 = 5;
+
+#[derive(Debug, Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: HashMap<String, CachedMetadata>,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheFileRef<'a> {
+    version: u32,
+    entries: &'a HashMap<String, CachedMetadata>,
+}
+
+// Parsing the tags of every song in the playlist on every launch is
+// expensive, so we keep the result around in a single file, keyed by
+// URI; the entries are only valid as long as the file they describe
+// has not been modified
+#[derive(Debug, Default)]
+pub struct MetadataCache {
+    entries: HashMap<String, CachedMetadata>,
+    dirty: bool,
+}
+
+impl MetadataCache {
+    pub fn global() -> &'static Mutex<MetadataCache> {
+        static CACHE: OnceCell<Mutex<MetadataCache>> = OnceCell::new();
+
+        CACHE.get_or_init(|| Mutex::new(MetadataCache::load()))
+    }
+
+    fn cache_file() -> PathBuf {
+        let mut path = utils::cache_dir("metadata");
+        path.push("songs.json");
+        path
+    }
+
+    fn load() -> Self {
+        let path = MetadataCache::cache_file();
+        let entries = match fs::read(&path) {
+            Ok(data) => match serde_json::from_slice::<CacheFile>(&data) {
+                Ok(f) if f.version == METADATA_VERSION => f.entries,
+                Ok(f) => {
+                    debug!("Discarding metadata cache version {}", f.version);
+                    HashMap::new()
+                }
+                Err(e) => {
+                    warn!("Discarding invalid metadata cache: {}", e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        debug!("Loaded {} cached song entries", entries.len());
+
+        MetadataCache {
+            entries,
+            dirty: false,
+        }
+    }
+
+    pub fn lookup(&self, uri: &str, stamp: &FileStamp) -> Option<&CachedMetadata> {
+        self.entries.get(uri).filter(|e| e.stamp == *stamp)
+    }
+
+    pub fn insert(&mut self, uri: &str, metadata: CachedMetadata) {
+        self.entries.insert(uri.to_string(), metadata);
+        self.dirty = true;
+    }
+
+    pub fn remove(&mut self, uri: &str) {
+        if self.entries.remove(uri).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    // Entries for local files that were removed are of no use
+    fn is_stale(uri: &str) -> bool {
+        gio::File::for_uri(uri)
+            .path()
+            .map_or(false, |path| !path.exists())
+    }
+
+    // The songs are loaded while we save, so we only hold the lock on the
+    // global cache while taking a copy of it; the file is written to a
+    // temporary file first, so that we never leave a truncated one
+    // behind. This blocks, so it should be called off the main thread
+    pub fn save() {
+        // Saves happen in different threads, and must not overwrite
+        // each other with older data
+        static SAVING: Mutex<()> = Mutex::new(());
+        let _saving = SAVING.lock().unwrap();
+
+        let mut entries = {
+            let mut cache = MetadataCache::global().lock().unwrap();
+            if !cache.dirty {
+                return;
+            }
+            cache.dirty = false;
+            cache.entries.clone()
+        };
+
+        let stale: Vec<String> = entries
+            .keys()
+            .filter(|uri| MetadataCache::is_stale(uri))
+            .cloned()
+            .collect();
+        if !stale.is_empty() {
+            debug!("Dropping {} stale song entries", stale.len());
+            let mut cache = MetadataCache::global().lock().unwrap();
+            for uri in &stale {
+                entries.remove(uri);
+                cache.entries.remove(uri);
+            }
+        }
+
+        let path = MetadataCache::cache_file();
+        let contents = CacheFileRef {
+            version: METADATA_VERSION,
+            entries: &entries,
+        };
+        let res = serde_json::to_vec(&contents)
+            .map_err(|e| e.to_string())
+            .and_then(|data| glib::file_set_contents(&path, &data).map_err(|e| e.to_string()));
+        match res {
+            Ok(_) => debug!("Saved {} song entries to {:?}", entries.len(), &path),
+            Err(e) => {
+                warn!("Unable to write metadata cache {:?}: {}", &path, e);
+                MetadataCache::global().lock().unwrap().dirty = true;
+            }
+        }
+    }
+}
@@ -41,7 +41,28 @@ mod controller;
 pub use controller::Controller;
 
 mod cover_cache;
-pub use cover_cache::CoverCache;
+pub use cover_cache::{CoverArt, CoverCache, CoverOptions};
+
+mod cover_file;
+mod cover_palette;
+pub use cover_file::{cover_extension, embed_cover, embedded_cover, save_cover_file};
+pub use cover_palette::CoverPalette;
+
+mod folder_scanner;
+pub use folder_scanner::{scan_folder, scan_folders, ScanOptions, ScanResult};
+
+mod metadata_cache;
+pub use metadata_cache::MetadataCache;
+
+mod play_stats;
+mod smart_playlist;
+pub use play_stats::{PlayStats, MAX_RATING};
+pub use smart_playlist::{FieldKind, Rule, RuleField, RuleOperator, SmartPlaylist};
+
+mod library;
+mod library_group;
+pub use library::Library;
+pub use library_group::{LibraryGroup, LibraryGroupKind};
 
 mod inhibit_controller;
 mod mpris_controller;
@@ -55,14 +76,20 @@ mod player;
 mod queue;
 mod shuffle;
 mod song;
+mod song_loader;
 mod state;
+mod waveform_cache;
 mod waveform_generator;
+mod waveform_prefetcher;
 
 pub use player::{
-    AudioPlayer, PlaybackAction, PlaybackState, RepeatMode, ReplayGainMode, SeekDirection,
+    AudioPlayer, PlaybackAction, PlaybackError, PlaybackState, RepeatMode, ReplayGainMode,
+    SeekDirection,
 };
-pub use queue::Queue;
+pub use queue::{Queue, SortMode};
 pub use shuffle::ShuffleListModel;
 pub use song::Song;
+pub use song_loader::load_songs;
 pub use state::PlayerState;
 pub use waveform_generator::WaveformGenerator;
+pub use waveform_prefetcher::WaveformPrefetcher;
@@ -145,8 +145,11 @@ impl MprisController {
             metadata.title = Some(song.title());
             metadata.album = Some(song.album());
 
-            let length = Duration::from_secs(song.duration()).as_micros() as i64;
-            metadata.length = Some(length);
+            // Internet radio streams have no length
+            if !song.is_stream() {
+                let length = Duration::from_secs(song.duration()).as_micros() as i64;
+                metadata.length = Some(length);
+            }
 
             // MPRIS should really support passing a bytes buffer for
             // the cover art, instead of requiring this ridiculous
@@ -184,6 +187,12 @@ impl Controller for MprisController {
     }
 
     fn set_song(&self, song: &Song) {
+        self.mpris.set_can_seek(!song.is_stream());
+        self.song.replace(Some(song.clone()));
+        self.update_metadata();
+    }
+
+    fn update_song_metadata(&self, song: &Song) {
         self.song.replace(Some(song.clone()));
         self.update_metadata();
     }
@@ -0,0 +1,137 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use gtk::glib;
+use log::{debug, warn};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+// The highest rating a song can have
+pub const MAX_RATING: u32 = 5;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SongStats {
+    // When the song was first seen, in seconds since the epoch
+    pub added: Option<i64>,
+    pub play_count: u32,
+    pub last_played: Option<i64>,
+    // Between 1 and MAX_RATING; None if the song was never rated
+    pub rating: Option<u32>,
+}
+
+// Unlike the metadata cache, the stats cannot be recreated from the
+// songs themselves, so we keep them even if the files change
+#[derive(Debug, Default)]
+pub struct PlayStats {
+    entries: HashMap<String, SongStats>,
+    dirty: bool,
+}
+
+pub fn now() -> i64 {
+    glib::real_time() / 1_000_000
+}
+
+impl PlayStats {
+    pub fn global() -> &'static Mutex<PlayStats> {
+        static STATS: OnceCell<Mutex<PlayStats>> = OnceCell::new();
+
+        STATS.get_or_init(|| Mutex::new(PlayStats::load()))
+    }
+
+    fn stats_file() -> PathBuf {
+        let mut path = utils::data_dir("stats");
+        path.push("songs.json");
+        path
+    }
+
+    // The stats cannot be recreated, so a file we cannot parse is moved
+    // aside instead of being overwritten on the next save
+    fn back_up(path: &Path) {
+        let backup = path.with_file_name(format!("songs-{}.json.bak", now()));
+        match fs::rename(path, &backup) {
+            Ok(_) => warn!("Invalid play stats moved to {:?}", &backup),
+            Err(e) => warn!("Unable to back up invalid play stats {:?}: {}", path, e),
+        }
+    }
+
+    fn load() -> Self {
+        let path = PlayStats::stats_file();
+        let entries = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Unable to parse play stats {:?}: {}", &path, e);
+                PlayStats::back_up(&path);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        debug!("Loaded play stats for {} songs", entries.len());
+
+        PlayStats {
+            entries,
+            dirty: false,
+        }
+    }
+
+    pub fn get(&self, uri: &str) -> SongStats {
+        self.entries.get(uri).copied().unwrap_or_default()
+    }
+
+    // Records the first time we see a song
+    pub fn touch(&mut self, uri: &str) {
+        let entry = self.entries.entry(uri.to_string()).or_default();
+        if entry.added.is_none() {
+            entry.added = Some(now());
+            self.dirty = true;
+        }
+    }
+
+    pub fn record_play(&mut self, uri: &str) {
+        let entry = self.entries.entry(uri.to_string()).or_default();
+        entry.play_count += 1;
+        entry.last_played = Some(now());
+        self.dirty = true;
+    }
+
+    pub fn set_rating(&mut self, uri: &str, rating: u32) {
+        let entry = self.entries.entry(uri.to_string()).or_default();
+        let rating = match rating {
+            0 => None,
+            r => Some(r.min(MAX_RATING)),
+        };
+        if entry.rating != rating {
+            entry.rating = rating;
+            self.dirty = true;
+        }
+    }
+
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        let path = PlayStats::stats_file();
+        match serde_json::to_vec(&self.entries) {
+            Ok(data) => {
+                // Write to a temporary file first, so that we never leave
+                // a truncated file behind
+                if let Err(e) = glib::file_set_contents(&path, &data) {
+                    warn!("Unable to write play stats {:?}: {}", &path, e);
+                    return;
+                }
+                debug!("Saved play stats for {} songs", self.entries.len());
+                self.dirty = false;
+            }
+            Err(e) => warn!("Unable to serialize play stats: {}", e),
+        }
+    }
+}
@@ -2,9 +2,10 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     fmt::{self, Display, Formatter},
     rc::Rc,
+    time::Instant,
 };
 
 use async_channel::{Receiver, Sender};
@@ -15,9 +16,10 @@ use log::{debug, error};
 use crate::{
     application::ApplicationAction,
     audio::{
-        Controller, CoverCache, GstBackend, InhibitController, MprisController, PlayerState, Queue,
-        Song, WaveformGenerator,
+        Controller, CoverCache, GstBackend, InhibitController, MprisController, PlayStats,
+        PlayerState, Queue, Song, WaveformGenerator, WaveformPrefetcher,
     },
+    utils,
 };
 
 #[derive(Clone, Debug)]
@@ -33,10 +35,24 @@ pub enum PlaybackAction {
     Repeat(RepeatMode),
     Seek(u64),
     PlayNext,
+    UpdateStreamMetadata(Option<String>, Option<String>),
+    PlaybackError(PlaybackError),
+    Buffering(i32),
+    // The magnitude of each band, and when its audio is played
+    Spectrum(Vec<f32>, Instant),
 
     Raise,
 }
 
+// An error that stopped the backend from playing a song
+#[derive(Clone, Debug)]
+pub struct PlaybackError {
+    pub uri: Option<String>,
+    pub message: String,
+    // GStreamer does not have the plugins to decode the song
+    pub missing_codec: bool,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Default)]
 pub enum PlaybackState {
     #[default]
@@ -116,6 +132,11 @@ pub struct AudioPlayer {
     queue: Queue,
     state: PlayerState,
     waveform_generator: WaveformGenerator,
+    waveform_prefetcher: WaveformPrefetcher,
+    // The number of songs in a row that failed to play
+    failures: Cell<u32>,
+    // Shared with the spectrum updates that are waiting for their audio
+    spectrum_enabled: Rc<Cell<bool>>,
 }
 
 impl fmt::Debug for AudioPlayer {
@@ -143,6 +164,10 @@ impl AudioPlayer {
         let backend = GstBackend::new(sender);
 
         let queue = Queue::default();
+
+        let waveform_prefetcher = WaveformPrefetcher::new(&queue);
+        controllers.push(Box::new(waveform_prefetcher.clone()));
+        waveform_generator.set_prefetcher(&waveform_prefetcher);
         let state = PlayerState::default();
 
         let res = Rc::new(Self {
@@ -153,6 +178,9 @@ impl AudioPlayer {
             queue,
             state,
             waveform_generator,
+            waveform_prefetcher,
+            failures: Cell::new(0),
+            spectrum_enabled: Rc::default(),
         });
 
         res.clone().setup_channel();
@@ -183,6 +211,18 @@ impl AudioPlayer {
             PlaybackAction::UpdatePosition(pos) => self.update_position(pos),
             PlaybackAction::VolumeChanged(vol) => self.update_volume(vol),
             PlaybackAction::PlayNext => self.play_next(),
+            PlaybackAction::UpdateStreamMetadata(title, station) => {
+                self.update_stream_metadata(title.as_deref(), station.as_deref())
+            }
+            PlaybackAction::PlaybackError(error) => self.playback_error(error),
+            PlaybackAction::Buffering(percent) => {
+                self.waveform_prefetcher.set_buffering(percent < 100)
+            }
+            // Ignore what was still in flight after the playback stopped
+            PlaybackAction::Spectrum(bands, due) if self.state.playing() => {
+                self.show_spectrum(bands, due)
+            }
+            PlaybackAction::Spectrum(..) => (),
             PlaybackAction::Raise => self.present(),
             PlaybackAction::Repeat(mode) => self.update_repeat_mode(mode),
             PlaybackAction::Seek(pos) => self.seek_position_abs(pos),
@@ -244,7 +284,15 @@ impl AudioPlayer {
         }
     }
 
+    // The current song played until the end
     fn play_next(&self) {
+        if let Some(song) = self.state.current_song() {
+            if !song.is_stream() {
+                PlayStats::global().lock().unwrap().record_play(&song.uri());
+                std::thread::spawn(|| PlayStats::global().lock().unwrap().save());
+            }
+        }
+
         self.skip_next();
     }
 
@@ -459,11 +507,57 @@ impl AudioPlayer {
     fn update_position(&self, position: u64) {
         self.state.set_position(position);
 
+        // The song is playing, so it's not broken after all
+        if position > 0 {
+            self.failures.set(0);
+            if let Some(song) = self.state.current_song() {
+                song.set_broken(false);
+            }
+        }
+
         for c in &self.controllers {
             c.set_position(position);
         }
     }
 
+    fn update_stream_metadata(&self, title: Option<&str>, station: Option<&str>) {
+        if let Some(song) = self.state.current_song() {
+            if song.set_stream_metadata(title, station) {
+                debug!("Stream metadata updated: {}", song.title());
+                self.state.update_metadata();
+
+                for c in &self.controllers {
+                    c.update_song_metadata(&song);
+                }
+            }
+        }
+    }
+
+    // Skips the songs that fail to play, unless too many of them fail
+    // in a row, as it's likely that none of the songs will play
+    fn playback_error(&self, error: PlaybackError) {
+        if let Some(song) = self.state.current_song() {
+            song.set_broken(true);
+        }
+
+        if let Err(e) = self
+            .app_sender
+            .send_blocking(ApplicationAction::PlaybackError(error))
+        {
+            error!("Unable to send PlaybackError: {e}");
+        }
+
+        let failures = self.failures.get() + 1;
+        let max_failures = utils::settings_manager().int("max-playback-errors").max(0) as u32;
+        if failures < max_failures {
+            self.failures.set(failures);
+            self.skip_next();
+        } else {
+            self.failures.set(0);
+            self.set_playback_state(PlaybackState::Stopped);
+        }
+    }
+
     fn update_volume(&self, volume: f64) {
         debug!("Updating volume to: {}", &volume);
         self.state.set_volume(volume);
@@ -512,6 +606,12 @@ impl AudioPlayer {
         cover_cache.clear();
     }
 
+    pub fn replace_queue(&self, songs: &[Song]) {
+        self.stop();
+        self.state.set_current_song(None);
+        self.queue.replace_songs(songs);
+    }
+
     pub fn remove_song(&self, song: &Song) {
         if song.playing() {
             self.skip_next();
@@ -531,4 +631,32 @@ impl AudioPlayer {
     pub fn replaygain_available(&self) -> bool {
         self.backend.replaygain_available()
     }
+
+    pub fn set_spectrum_enabled(&self, enabled: bool) {
+        self.spectrum_enabled.set(enabled);
+        self.backend.set_spectrum_enabled(enabled);
+        if !enabled {
+            self.state.set_spectrum(vec![]);
+        }
+    }
+
+    // The audio is analysed before it's played, so we wait until it's
+    // heard to show its spectrum
+    fn show_spectrum(&self, bands: Vec<f32>, due: Instant) {
+        let delay = due.saturating_duration_since(Instant::now());
+        if delay.is_zero() {
+            self.state.set_spectrum(bands);
+            return;
+        }
+
+        let enabled = self.spectrum_enabled.clone();
+        glib::timeout_add_local_once(
+            delay,
+            clone!(@weak self.state as state => move || {
+                if enabled.get() && state.playing() {
+                    state.set_spectrum(bands);
+                }
+            }),
+        );
+    }
 }
@@ -1,11 +1,136 @@
 // SPDX-FileCopyrightText: 2022  Emmanuele Bassi
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::cell::Cell;
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+};
 
 use gtk::{gio, glib, prelude::*, subclass::prelude::*};
+use rand::prelude::*;
+
+use crate::audio::{PlayStats, RepeatMode, ShuffleListModel, Song};
+
+#[derive(Clone, Copy, Debug, glib::Enum, PartialEq, Eq, Default)]
+#[enum_type(name = "AmberolSortMode")]
+pub enum SortMode {
+    // Keep the order in which songs are added
+    #[default]
+    None,
+    Artist,
+    Album,
+    TrackNumber,
+    Title,
+    Duration,
+    DateAdded,
+    PlayCount,
+    Random,
+}
+
+impl SortMode {
+    // Whether newly added songs should be sorted as well; shuffling
+    // the whole queue every time we add a song would be confusing
+    fn is_automatic(&self) -> bool {
+        !matches!(self, SortMode::None | SortMode::Random)
+    }
+}
+
+impl From<i32> for SortMode {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::None,
+            1 => Self::Artist,
+            2 => Self::Album,
+            3 => Self::TrackNumber,
+            4 => Self::Title,
+            5 => Self::Duration,
+            6 => Self::DateAdded,
+            7 => Self::PlayCount,
+            8 => Self::Random,
+            _ => panic!("invalid SortMode enum key"),
+        }
+    }
+}
+
+impl From<SortMode> for i32 {
+    fn from(value: SortMode) -> Self {
+        match value {
+            SortMode::None => 0,
+            SortMode::Artist => 1,
+            SortMode::Album => 2,
+            SortMode::TrackNumber => 3,
+            SortMode::Title => 4,
+            SortMode::Duration => 5,
+            SortMode::DateAdded => 6,
+            SortMode::PlayCount => 7,
+            SortMode::Random => 8,
+        }
+    }
+}
+
+// The key of a song in the automatic sort modes: a number first, then
+// the artist, album, disc and track numbers, and title. Comparing text
+// goes through collation keys, which are expensive to create, so we
+// create them once for each song
+type SortKey = (
+    i64,
+    Option<glib::CollationKey>,
+    Option<glib::CollationKey>,
+    u32,
+    u32,
+    Option<glib::CollationKey>,
+);
+
+fn sort_key(mode: SortMode, song: &Song, stats: &PlayStats) -> SortKey {
+    let text = |s: String| Some(glib::CollationKey::from(s));
+    // Songs without disc or track numbers go last
+    let disc = song.disc_number().unwrap_or(u32::MAX);
+    let track = song.track_number().unwrap_or(u32::MAX);
+
+    match mode {
+        SortMode::Artist => (
+            0,
+            text(song.artist()),
+            text(song.album()),
+            disc,
+            track,
+            text(song.title()),
+        ),
+        SortMode::Album => (0, None, text(song.album()), disc, track, text(song.title())),
+        SortMode::TrackNumber => (0, None, None, disc, track, text(song.title())),
+        SortMode::Title => (0, None, None, 0, 0, text(song.title())),
+        SortMode::Duration => (song.duration() as i64, None, None, 0, 0, None),
+        // Songs we have never seen count as just added
+        SortMode::DateAdded => {
+            let added = stats.get(&song.uri()).added.unwrap_or(i64::MAX);
+            (added, None, None, 0, 0, None)
+        }
+        // The most played songs go first
+        SortMode::PlayCount => {
+            let play_count = stats.get(&song.uri()).play_count;
+            (-i64::from(play_count), None, None, 0, 0, None)
+        }
+        SortMode::None | SortMode::Random => (0, None, None, 0, 0, None),
+    }
+}
 
-use crate::audio::{RepeatMode, ShuffleListModel, Song};
+fn sort_songs(songs: &mut [Song], mode: SortMode) {
+    match mode {
+        SortMode::None => {}
+        SortMode::Random => songs.shuffle(&mut thread_rng()),
+        _ => {
+            let stats = PlayStats::global().lock().unwrap();
+            songs.sort_by_cached_key(|s| sort_key(mode, s, &stats));
+        }
+    }
+}
+
+fn to_songs(objects: &[impl IsA<glib::Object>]) -> Vec<Song> {
+    objects
+        .iter()
+        .filter_map(|o| o.dynamic_cast_ref::<Song>().cloned())
+        .collect()
+}
 
 mod imp {
     use glib::{ParamSpec, ParamSpecEnum, ParamSpecObject, ParamSpecUInt, Value};
@@ -20,6 +145,7 @@ mod imp {
         pub repeat_mode: Cell<RepeatMode>,
         pub current_pos: Cell<Option<u32>>,
         pub shuffled: Cell<bool>,
+        pub sort_mode: Cell<SortMode>,
     }
 
     #[glib::object_subclass]
@@ -37,6 +163,7 @@ mod imp {
                 repeat_mode: Cell::new(RepeatMode::default()),
                 current_pos: Cell::new(None),
                 shuffled: Cell::new(false),
+                sort_mode: Cell::new(SortMode::default()),
             }
         }
     }
@@ -140,9 +267,31 @@ impl Queue {
     }
 
     pub fn add_songs(&self, songs: &[impl IsA<glib::Object>]) {
-        self.imp()
-            .store
-            .splice(self.imp().model.n_items(), 0, songs);
+        if self.is_sorted_automatically() {
+            self.merge_songs(to_songs(songs));
+        } else {
+            self.imp()
+                .store
+                .splice(self.imp().model.n_items(), 0, songs);
+        }
+        self.notify("n-songs");
+    }
+
+    // Replaces the contents of the queue in one go, without going
+    // through the empty state
+    pub fn replace_songs(&self, songs: &[impl IsA<glib::Object>]) {
+        let imp = self.imp();
+        imp.current_pos.replace(None);
+        if self.is_sorted_automatically() {
+            let mut songs = to_songs(songs);
+            sort_songs(&mut songs, imp.sort_mode.get());
+            imp.store.splice(0, imp.store.n_items(), &songs);
+        } else {
+            imp.store.splice(0, imp.store.n_items(), songs);
+        }
+        if self.imp().model.shuffled() {
+            self.imp().model.reshuffle(0);
+        }
         self.notify("n-songs");
     }
 
@@ -312,6 +461,193 @@ impl Queue {
         count
     }
 
+    pub fn sort_mode(&self) -> SortMode {
+        self.imp().sort_mode.get()
+    }
+
+    // Sorts the backing store, so that moving to the next or previous
+    // song follows the new order; the current song stays current
+    pub fn sort(&self, mode: SortMode) {
+        let imp = self.imp();
+
+        imp.sort_mode.set(mode);
+        if mode == SortMode::None {
+            return;
+        }
+
+        let current = self.current_song();
+        let mut songs: Vec<Song> = (0..imp.store.n_items())
+            .map(|i| imp.store.item(i).unwrap().downcast::<Song>().unwrap())
+            .collect();
+        sort_songs(&mut songs, mode);
+        imp.store.splice(0, imp.store.n_items(), &songs);
+
+        self.update_current_pos(current.as_ref());
+    }
+
+    // Looks for the song object itself, as duplicates of a song are
+    // equal to each other
+    fn update_current_pos(&self, song: Option<&Song>) {
+        let pos = song.and_then(|song| {
+            (0..self.n_songs()).find(|i| self.song_at(*i).is_some_and(|s| &s == song))
+        });
+        self.imp().current_pos.replace(pos);
+        self.notify("current");
+    }
+
+    // Whether we keep the queue sorted when adding songs; shuffling
+    // takes precedence over sorting
+    fn is_sorted_automatically(&self) -> bool {
+        self.imp().sort_mode.get().is_automatic() && !self.imp().model.shuffled()
+    }
+
+    // Inserts the songs into the sorted queue, instead of sorting the
+    // whole queue again; new songs go after the songs that sort the same
+    fn merge_songs(&self, songs: Vec<Song>) {
+        let imp = self.imp();
+        let mode = imp.sort_mode.get();
+        let current = self.current_song();
+
+        // The songs that go in the same place are inserted in one go,
+        // starting from the end, so that the positions stay valid
+        let mut runs: Vec<(u32, Vec<Song>)> = vec![];
+        {
+            let stats = PlayStats::global().lock().unwrap();
+            let mut songs: Vec<(SortKey, Song)> = songs
+                .into_iter()
+                .map(|s| (sort_key(mode, &s, &stats), s))
+                .collect();
+            songs.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            let key_at = |pos: u32| {
+                let song = imp.store.item(pos).unwrap().downcast::<Song>().unwrap();
+                sort_key(mode, &song, &stats)
+            };
+
+            let mut end = imp.store.n_items();
+            for (key, song) in songs.into_iter().rev() {
+                let (mut low, mut high) = (0, end);
+                while low < high {
+                    let mid = low + (high - low) / 2;
+                    if key_at(mid) <= key {
+                        low = mid + 1;
+                    } else {
+                        high = mid;
+                    }
+                }
+                end = low;
+
+                match runs.last_mut() {
+                    Some((pos, run)) if *pos == low => run.push(song),
+                    _ => runs.push((low, vec![song])),
+                }
+            }
+        }
+
+        for (pos, mut run) in runs {
+            run.reverse();
+            imp.store.splice(pos, 0, &run);
+        }
+
+        if current.is_some() {
+            self.update_current_pos(current.as_ref());
+        }
+    }
+
+    // Songs are duplicates if they have the same file or identity, or if
+    // they have the same tags and roughly the same duration. The current
+    // song is never a duplicate, so we keep playing it
+    pub fn duplicate_songs(&self) -> Vec<Song> {
+        let imp = self.imp();
+
+        let current = self.current_song();
+        let mut songs: Vec<Song> = (0..imp.store.n_items())
+            .map(|i| imp.store.item(i).unwrap().downcast::<Song>().unwrap())
+            .collect();
+        if let Some(current) = current {
+            songs.retain(|s| s != &current);
+            songs.insert(0, current);
+        }
+
+        let mut identities = HashSet::new();
+        let mut recordings: HashMap<(String, String, String), Vec<u64>> = HashMap::new();
+        let mut res = Vec::new();
+        for song in songs {
+            if !identities.insert(song.uuid().unwrap_or_else(|| song.uri())) {
+                res.push(song);
+                continue;
+            }
+
+            if song.is_stream() || !song.has_metadata() {
+                continue;
+            }
+
+            let key = (
+                song.artist().trim().to_lowercase(),
+                song.title().trim().to_lowercase(),
+                song.album().trim().to_lowercase(),
+            );
+            let durations = recordings.entry(key).or_default();
+            if durations.iter().any(|d| d.abs_diff(song.duration()) <= 2) {
+                res.push(song);
+            } else {
+                durations.push(song.duration());
+            }
+        }
+
+        res
+    }
+
+    // Removes the given songs in one go, and returns them with their
+    // positions in the backing store, so they can be put back
+    pub fn remove_songs(&self, songs: &[Song]) -> Vec<(u32, Song)> {
+        let imp = self.imp();
+
+        let current = self.current_song();
+        let mut removed = Vec::new();
+        let mut kept = Vec::new();
+        for pos in 0..imp.store.n_items() {
+            let song = imp.store.item(pos).unwrap().downcast::<Song>().unwrap();
+            if songs.contains(&song) {
+                removed.push((pos, song));
+            } else {
+                kept.push(song);
+            }
+        }
+
+        if removed.is_empty() {
+            return removed;
+        }
+
+        imp.store.splice(0, imp.store.n_items(), &kept);
+        if imp.model.shuffled() {
+            imp.model.reshuffle(0);
+        }
+        self.update_current_pos(current.as_ref());
+        self.notify("n-songs");
+
+        removed
+    }
+
+    // Puts back the songs returned by remove_songs()
+    pub fn restore_songs(&self, songs: &[(u32, Song)]) {
+        let imp = self.imp();
+
+        let current = self.current_song();
+        for (pos, song) in songs {
+            imp.store.insert((*pos).min(imp.store.n_items()), song);
+        }
+        if imp.model.shuffled() {
+            imp.model.reshuffle(0);
+        }
+        self.update_current_pos(current.as_ref());
+        self.notify("n-songs");
+    }
+
+    pub fn position(&self, s: &Song) -> Option<u32> {
+        (0..self.n_songs()).find(|i| self.song_at(*i).unwrap().equals(s))
+    }
+
     pub fn contains(&self, s: &Song) -> bool {
         for i in 0..self.imp().store.n_items() {
             let song = self.imp().store.item(i).unwrap();
@@ -0,0 +1,634 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{fs, path::PathBuf};
+
+use gtk::glib;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::{
+        play_stats::{self, PlayStats, SongStats},
+        Song,
+    },
+    i18n::i18n,
+    utils,
+};
+
+// Smart playlists are stored as JSON files in the user data directory;
+// the same format is used when exporting a playlist, so that it can be
+// shared and imported again
+const FORMAT: &str = "amberol-smart-playlist";
+const FORMAT_VERSION: u32 = 1;
+const FILE_SUFFIX: &str = ".smart.json";
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    Text,
+    Number,
+    Date,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleField {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Year,
+    Duration,
+    PlayCount,
+    Rating,
+    DateAdded,
+    LastPlayed,
+}
+
+impl RuleField {
+    pub const ALL: [RuleField; 10] = [
+        RuleField::Title,
+        RuleField::Artist,
+        RuleField::Album,
+        RuleField::Genre,
+        RuleField::Year,
+        RuleField::Duration,
+        RuleField::PlayCount,
+        RuleField::Rating,
+        RuleField::DateAdded,
+        RuleField::LastPlayed,
+    ];
+
+    pub fn kind(&self) -> FieldKind {
+        match self {
+            RuleField::Title | RuleField::Artist | RuleField::Album | RuleField::Genre => {
+                FieldKind::Text
+            }
+            RuleField::Year | RuleField::Duration | RuleField::PlayCount | RuleField::Rating => {
+                FieldKind::Number
+            }
+            RuleField::DateAdded | RuleField::LastPlayed => FieldKind::Date,
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            RuleField::Title => i18n("Title"),
+            RuleField::Artist => i18n("Artist"),
+            RuleField::Album => i18n("Album"),
+            RuleField::Genre => i18n("Genre"),
+            RuleField::Year => i18n("Year"),
+            RuleField::Duration => i18n("Duration"),
+            RuleField::PlayCount => i18n("Play Count"),
+            RuleField::Rating => i18n("Rating"),
+            RuleField::DateAdded => i18n("Date Added"),
+            RuleField::LastPlayed => i18n("Last Played"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleOperator {
+    Is,
+    IsNot,
+    Contains,
+    DoesNotContain,
+    LessThan,
+    GreaterThan,
+    AtLeast,
+    AtMost,
+    InLast,
+    NotInLast,
+}
+
+impl RuleOperator {
+    // The operators that make sense for each kind of field
+    pub fn for_kind(kind: FieldKind) -> &'static [RuleOperator] {
+        match kind {
+            FieldKind::Text => &[
+                RuleOperator::Is,
+                RuleOperator::IsNot,
+                RuleOperator::Contains,
+                RuleOperator::DoesNotContain,
+            ],
+            FieldKind::Number => &[
+                RuleOperator::Is,
+                RuleOperator::IsNot,
+                RuleOperator::LessThan,
+                RuleOperator::GreaterThan,
+                RuleOperator::AtLeast,
+                RuleOperator::AtMost,
+            ],
+            FieldKind::Date => &[RuleOperator::InLast, RuleOperator::NotInLast],
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            RuleOperator::Is => i18n("is"),
+            RuleOperator::IsNot => i18n("is not"),
+            RuleOperator::Contains => i18n("contains"),
+            RuleOperator::DoesNotContain => i18n("does not contain"),
+            RuleOperator::LessThan => i18n("is less than"),
+            RuleOperator::GreaterThan => i18n("is greater than"),
+            RuleOperator::AtLeast => i18n("is at least"),
+            RuleOperator::AtMost => i18n("is at most"),
+            // Translators: followed by a number of days
+            RuleOperator::InLast => i18n("in the last days"),
+            // Translators: followed by a number of days
+            RuleOperator::NotInLast => i18n("not in the last days"),
+        }
+    }
+}
+
+// The value of a field for a given song
+#[derive(Debug)]
+enum FieldValue {
+    Text(Option<String>),
+    Number(Option<u64>),
+    // In seconds since the epoch
+    Date(Option<i64>),
+}
+
+// A single condition, like "genre is Jazz"; the value is always stored
+// as a string, and interpreted according to the field: durations are
+// in seconds, or minutes and seconds, and dates are a number of days
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub field: RuleField,
+    pub op: RuleOperator,
+    pub value: String,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Rule {
+            field: RuleField::Artist,
+            op: RuleOperator::Is,
+            value: String::new(),
+        }
+    }
+}
+
+impl Rule {
+    pub fn is_valid(&self) -> bool {
+        if !RuleOperator::for_kind(self.field.kind()).contains(&self.op) {
+            return false;
+        }
+
+        match self.field.kind() {
+            FieldKind::Text => true,
+            FieldKind::Number => self.number_value().is_some(),
+            FieldKind::Date => self.value.trim().parse::<u32>().is_ok(),
+        }
+    }
+
+    fn number_value(&self) -> Option<u64> {
+        let value = self.value.trim();
+        if self.field == RuleField::Duration {
+            if let Some((min, sec)) = value.split_once(':') {
+                let min = min.parse::<u64>().ok()?;
+                let sec = sec.parse::<u64>().ok()?;
+                return Some(min * 60 + sec);
+            }
+        }
+
+        value.parse::<u64>().ok()
+    }
+
+    fn text_matches(&self, text: Option<&str>) -> bool {
+        let text = text.unwrap_or_default().to_lowercase();
+        let value = self.value.trim().to_lowercase();
+        match self.op {
+            RuleOperator::Is => text == value,
+            RuleOperator::IsNot => text != value,
+            RuleOperator::Contains => text.contains(&value),
+            RuleOperator::DoesNotContain => !text.contains(&value),
+            _ => false,
+        }
+    }
+
+    // Songs without a value never match, unless the rule excludes
+    // a value; a song without a year is not from before 1970
+    fn number_matches(&self, n: Option<u64>) -> bool {
+        let value = match self.number_value() {
+            Some(v) => v,
+            None => return false,
+        };
+        match (self.op, n) {
+            (RuleOperator::IsNot, n) => n != Some(value),
+            (_, None) => false,
+            (RuleOperator::Is, Some(n)) => n == value,
+            (RuleOperator::LessThan, Some(n)) => n < value,
+            (RuleOperator::GreaterThan, Some(n)) => n > value,
+            (RuleOperator::AtLeast, Some(n)) => n >= value,
+            (RuleOperator::AtMost, Some(n)) => n <= value,
+            _ => false,
+        }
+    }
+
+    fn date_matches(&self, timestamp: Option<i64>, now: i64) -> bool {
+        let days = match self.value.trim().parse::<i64>() {
+            Ok(d) => d,
+            Err(_) => return false,
+        };
+        let recent = timestamp.is_some_and(|t| t >= now - days * SECONDS_PER_DAY);
+        match self.op {
+            RuleOperator::InLast => recent,
+            RuleOperator::NotInLast => !recent,
+            _ => false,
+        }
+    }
+
+    fn field_value(&self, song: &Song, stats: &SongStats) -> FieldValue {
+        match self.field {
+            RuleField::Title => FieldValue::Text(Some(song.title())),
+            RuleField::Artist => FieldValue::Text(Some(song.artist())),
+            RuleField::Album => FieldValue::Text(Some(song.album())),
+            RuleField::Genre => FieldValue::Text(song.genre()),
+            RuleField::Year => FieldValue::Number(song.year().map(u64::from)),
+            RuleField::Duration => FieldValue::Number(Some(song.duration())),
+            RuleField::PlayCount => FieldValue::Number(Some(stats.play_count.into())),
+            RuleField::Rating => FieldValue::Number(Some(stats.rating.unwrap_or(0).into())),
+            RuleField::DateAdded => FieldValue::Date(stats.added),
+            RuleField::LastPlayed => FieldValue::Date(stats.last_played),
+        }
+    }
+
+    fn value_matches(&self, value: &FieldValue, now: i64) -> bool {
+        match value {
+            FieldValue::Text(text) => self.text_matches(text.as_deref()),
+            FieldValue::Number(n) => self.number_matches(*n),
+            FieldValue::Date(timestamp) => self.date_matches(*timestamp, now),
+        }
+    }
+
+    pub fn matches(&self, song: &Song, stats: &SongStats, now: i64) -> bool {
+        self.value_matches(&self.field_value(song, stats), now)
+    }
+}
+
+fn default_format() -> String {
+    FORMAT.to_string()
+}
+
+fn default_version() -> u32 {
+    FORMAT_VERSION
+}
+
+// A playlist whose contents are the songs of the library matching a
+// set of rules; the rules are evaluated every time the playlist is
+// played, so the songs are always up to date
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SmartPlaylist {
+    #[serde(default = "default_format")]
+    format: String,
+    #[serde(default = "default_version")]
+    version: u32,
+    // The identifier is local to this installation, and it is not
+    // part of the shared format
+    #[serde(skip)]
+    id: String,
+    pub name: String,
+    // Whether a song needs to match any rule, instead of all of them
+    #[serde(default)]
+    pub match_any: bool,
+    pub rules: Vec<Rule>,
+    // The maximum number of songs, if any
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+impl Default for SmartPlaylist {
+    fn default() -> Self {
+        SmartPlaylist {
+            format: default_format(),
+            version: default_version(),
+            id: glib::uuid_string_random().to_string(),
+            name: String::new(),
+            match_any: false,
+            rules: vec![Rule::default()],
+            limit: None,
+        }
+    }
+}
+
+impl SmartPlaylist {
+    pub fn new(name: &str) -> Self {
+        SmartPlaylist {
+            name: name.to_string(),
+            ..SmartPlaylist::default()
+        }
+    }
+
+    fn playlists_dir() -> PathBuf {
+        utils::data_dir("smart-playlists")
+    }
+
+    fn path(&self) -> PathBuf {
+        let mut path = SmartPlaylist::playlists_dir();
+        path.push(format!("{}{}", self.id, FILE_SUFFIX));
+        path
+    }
+
+    pub fn from_json(data: &[u8]) -> Result<Self, String> {
+        let mut res: SmartPlaylist = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+        if res.format != FORMAT {
+            return Err(format!("Unknown format '{}'", res.format));
+        }
+        if res.version > FORMAT_VERSION {
+            return Err(format!("Unsupported version {}", res.version));
+        }
+
+        res.version = FORMAT_VERSION;
+        res.id = glib::uuid_string_random().to_string();
+        Ok(res)
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).expect("Unable to serialize smart playlist")
+    }
+
+    pub fn load_all() -> Vec<SmartPlaylist> {
+        let entries = match fs::read_dir(SmartPlaylist::playlists_dir()) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+
+        let mut res = vec![];
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let id = match path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(FILE_SUFFIX))
+            {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+
+            match fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|d| SmartPlaylist::from_json(&d))
+            {
+                Ok(mut pls) => {
+                    pls.id = id;
+                    res.push(pls);
+                }
+                // The file is left alone, so that a newer version can
+                // still load it
+                Err(e) => warn!("Unable to load smart playlist {:?}: {}", &path, e),
+            }
+        }
+
+        debug!("Loaded {} smart playlists", res.len());
+
+        res
+    }
+
+    pub fn save(&self) {
+        let path = self.path();
+        match glib::file_set_contents(&path, &self.to_json()) {
+            Ok(_) => debug!("Smart playlist saved to {:?}", &path),
+            Err(e) => warn!("Unable to save smart playlist {:?}: {}", &path, e),
+        }
+    }
+
+    pub fn delete(&self) {
+        let path = self.path();
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Unable to remove smart playlist {:?}: {}", &path, e);
+        }
+    }
+
+    // A playlist without valid rules is empty, instead of having the
+    // whole library in it
+    pub fn matches(&self, song: &Song, stats: &SongStats, now: i64) -> bool {
+        self.rules_match(|r| r.matches(song, stats, now))
+    }
+
+    fn rules_match<F: Fn(&Rule) -> bool>(&self, rule_matches: F) -> bool {
+        let mut rules = self.rules.iter().filter(|r| r.is_valid()).peekable();
+        if rules.peek().is_none() {
+            return false;
+        }
+
+        if self.match_any {
+            rules.any(rule_matches)
+        } else {
+            rules.all(rule_matches)
+        }
+    }
+
+    // Returns the songs matching the rules; streams are never part of
+    // a smart playlist
+    pub fn evaluate(&self, songs: &[Song]) -> Vec<Song> {
+        let now = play_stats::now();
+        let stats = PlayStats::global().lock().unwrap();
+
+        songs
+            .iter()
+            .filter(|s| !s.is_stream())
+            .filter(|s| self.matches(s, &stats.get(&s.uri()), now))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn rule(field: RuleField, op: RuleOperator, value: &str) -> Rule {
+        Rule {
+            field,
+            op,
+            value: value.to_string(),
+        }
+    }
+
+    // A song from 1969, added ten days ago and never played
+    fn song_value(field: RuleField) -> FieldValue {
+        match field {
+            RuleField::Title => FieldValue::Text(Some("Come Together".to_string())),
+            RuleField::Artist => FieldValue::Text(Some("The Beatles".to_string())),
+            RuleField::Album => FieldValue::Text(Some("Abbey Road".to_string())),
+            RuleField::Genre => FieldValue::Text(None),
+            RuleField::Year => FieldValue::Number(Some(1969)),
+            RuleField::Duration => FieldValue::Number(Some(259)),
+            RuleField::PlayCount => FieldValue::Number(Some(0)),
+            RuleField::Rating => FieldValue::Number(Some(4)),
+            RuleField::DateAdded => FieldValue::Date(Some(NOW - 10 * SECONDS_PER_DAY)),
+            RuleField::LastPlayed => FieldValue::Date(None),
+        }
+    }
+
+    fn rule_matches(rule: &Rule) -> bool {
+        rule.value_matches(&song_value(rule.field), NOW)
+    }
+
+    fn playlist(match_any: bool, rules: Vec<Rule>) -> SmartPlaylist {
+        SmartPlaylist {
+            match_any,
+            rules,
+            ..SmartPlaylist::new("Test")
+        }
+    }
+
+    #[test]
+    fn text_rules() {
+        use RuleOperator::*;
+
+        assert!(rule_matches(&rule(RuleField::Artist, Is, "the beatles")));
+        assert!(!rule_matches(&rule(
+            RuleField::Artist,
+            IsNot,
+            " The Beatles "
+        )));
+        assert!(rule_matches(&rule(RuleField::Album, Contains, "abbey")));
+        assert!(!rule_matches(&rule(
+            RuleField::Title,
+            DoesNotContain,
+            "together"
+        )));
+
+        // A missing genre is empty
+        assert!(rule_matches(&rule(RuleField::Genre, IsNot, "Jazz")));
+        assert!(!rule_matches(&rule(RuleField::Genre, Contains, "Jazz")));
+    }
+
+    #[test]
+    fn number_rules() {
+        use RuleOperator::*;
+
+        assert!(rule_matches(&rule(RuleField::Year, Is, "1969")));
+        assert!(rule_matches(&rule(RuleField::Year, LessThan, "1970")));
+        assert!(!rule_matches(&rule(RuleField::Year, GreaterThan, "1969")));
+        assert!(rule_matches(&rule(RuleField::Rating, AtLeast, "4")));
+        assert!(!rule_matches(&rule(RuleField::Rating, AtMost, "3")));
+        assert!(rule_matches(&rule(RuleField::PlayCount, Is, "0")));
+
+        // Durations are in seconds, or in minutes and seconds
+        assert!(rule_matches(&rule(RuleField::Duration, Is, "4:19")));
+        assert!(rule_matches(&rule(RuleField::Duration, GreaterThan, "240")));
+
+        assert!(rule(RuleField::Year, Is, "1969").is_valid());
+        assert!(!rule(RuleField::Year, Is, "sixties").is_valid());
+        assert!(!rule(RuleField::Year, Contains, "19").is_valid());
+    }
+
+    #[test]
+    fn missing_numbers() {
+        let value = FieldValue::Number(None);
+        assert!(!rule(RuleField::Year, RuleOperator::LessThan, "1970").value_matches(&value, NOW));
+        assert!(rule(RuleField::Year, RuleOperator::IsNot, "1970").value_matches(&value, NOW));
+    }
+
+    #[test]
+    fn date_rules() {
+        use RuleOperator::*;
+
+        assert!(rule_matches(&rule(RuleField::DateAdded, InLast, "30")));
+        assert!(!rule_matches(&rule(RuleField::DateAdded, InLast, "7")));
+        assert!(rule_matches(&rule(RuleField::DateAdded, NotInLast, "7")));
+
+        // Songs that were never played were not played recently
+        assert!(!rule_matches(&rule(RuleField::LastPlayed, InLast, "30")));
+        assert!(rule_matches(&rule(RuleField::LastPlayed, NotInLast, "30")));
+
+        assert!(!rule(RuleField::DateAdded, InLast, "-1").is_valid());
+        assert!(!rule(RuleField::DateAdded, Is, "7").is_valid());
+    }
+
+    #[test]
+    fn match_any_and_all() {
+        let rules = vec![
+            rule(RuleField::Artist, RuleOperator::Is, "The Beatles"),
+            rule(RuleField::Year, RuleOperator::GreaterThan, "1970"),
+        ];
+
+        assert!(!playlist(false, rules.clone()).rules_match(rule_matches));
+        assert!(playlist(true, rules).rules_match(rule_matches));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        // Invalid rules are ignored
+        let rules = vec![
+            rule(RuleField::Artist, RuleOperator::Is, "The Beatles"),
+            rule(RuleField::Year, RuleOperator::Is, "sixties"),
+        ];
+        assert!(playlist(false, rules).rules_match(rule_matches));
+
+        // Without valid rules, nothing matches
+        for match_any in [false, true] {
+            assert!(!playlist(match_any, vec![]).rules_match(rule_matches));
+
+            let rules = vec![rule(RuleField::Year, RuleOperator::Is, "sixties")];
+            assert!(!playlist(match_any, rules).rules_match(rule_matches));
+        }
+    }
+
+    #[test]
+    fn serialization() {
+        let pls = SmartPlaylist {
+            limit: Some(25),
+            ..playlist(
+                true,
+                vec![
+                    rule(RuleField::PlayCount, RuleOperator::AtLeast, "3"),
+                    rule(RuleField::LastPlayed, RuleOperator::NotInLast, "30"),
+                ],
+            )
+        };
+
+        let json: serde_json::Value = serde_json::from_slice(&pls.to_json()).unwrap();
+        assert_eq!(json["format"], FORMAT);
+        assert_eq!(json["version"], FORMAT_VERSION);
+        assert_eq!(json["rules"][0]["field"], "play-count");
+        assert_eq!(json["rules"][1]["op"], "not-in-last");
+        // The local identifier is not shared
+        assert!(json.get("id").is_none());
+
+        let res = SmartPlaylist::from_json(&pls.to_json()).unwrap();
+        assert_ne!(res.id, pls.id);
+        assert_eq!(res.name, pls.name);
+        assert_eq!(res.match_any, pls.match_any);
+        assert_eq!(res.rules, pls.rules);
+        assert_eq!(res.limit, pls.limit);
+    }
+
+    #[test]
+    fn deserialization_defaults() {
+        let json = br#"{
+            "name": "Jazz",
+            "rules": [{ "field": "genre", "op": "contains", "value": "jazz" }]
+        }"#;
+
+        let res = SmartPlaylist::from_json(json).unwrap();
+        assert_eq!(res.format, FORMAT);
+        assert_eq!(res.version, FORMAT_VERSION);
+        assert!(!res.match_any);
+        assert_eq!(res.limit, None);
+        assert_eq!(
+            res.rules,
+            vec![rule(RuleField::Genre, RuleOperator::Contains, "jazz")]
+        );
+    }
+
+    #[test]
+    fn deserialization_errors() {
+        let json = br#"{ "format": "something-else", "name": "", "rules": [] }"#;
+        assert!(SmartPlaylist::from_json(json).is_err());
+
+        let json = br#"{ "version": 42, "name": "", "rules": [] }"#;
+        assert!(SmartPlaylist::from_json(json).is_err());
+
+        assert!(SmartPlaylist::from_json(b"not json").is_err());
+    }
+}
@@ -4,32 +4,134 @@
 use std::{
     cell::{Cell, RefCell},
     fmt::{self, Display, Formatter},
+    io::Cursor,
     path::PathBuf,
+    rc::Rc,
     time::Instant,
 };
 
-use glib::{ParamSpec, ParamSpecBoolean, ParamSpecObject, ParamSpecString, ParamSpecUInt, Value};
+use glib::{
+    ParamSpec, ParamSpecBoolean, ParamSpecBoxed, ParamSpecObject, ParamSpecString, ParamSpecUInt,
+    Value,
+};
+use gst::prelude::*;
 use gtk::{gdk, gio, glib, prelude::*, subclass::prelude::*};
-use lofty::{Accessor, TaggedFileExt};
+use lofty::{
+    error::{ErrorKind, LoftyError},
+    Accessor, TaggedFileExt,
+};
 use log::{debug, warn};
 use once_cell::sync::Lazy;
 use sha2::{Digest, Sha256};
 
 use crate::{
-    audio::cover_cache::{CoverArt, CoverCache},
+    audio::{
+        cover_cache::{CoverArt, CoverCache},
+        metadata_cache::{self, CachedMetadata, FileStamp, MetadataCache},
+        CoverPalette, PlayStats,
+    },
     i18n::i18n,
+    query::SearchFields,
+    utils,
 };
 
+// How long we wait for GStreamer to find out whether it can play a
+// file, in seconds
+const DISCOVER_TIMEOUT: u64 = 5;
+
+// The reasons why a file cannot be loaded as a song
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SongError {
+    NotFound,
+    PermissionDenied,
+    // Neither lofty nor GStreamer know the format of the file
+    UnsupportedFormat,
+    CorruptTags(String),
+    // Any other error while reading the file
+    Unreadable(String),
+}
+
+impl Display for SongError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SongError::NotFound => write!(f, "file not found"),
+            SongError::PermissionDenied => write!(f, "permission denied"),
+            SongError::UnsupportedFormat => write!(f, "unsupported format"),
+            SongError::CorruptTags(e) => write!(f, "corrupt tags: {e}"),
+            SongError::Unreadable(e) => write!(f, "unreadable file: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SongError {}
+
+// Both GIO and GStreamer report errors through GError
+impl From<glib::Error> for SongError {
+    fn from(err: glib::Error) -> Self {
+        if err.matches(gio::IOErrorEnum::NotFound) || err.matches(gst::ResourceError::NotFound) {
+            SongError::NotFound
+        } else if err.matches(gio::IOErrorEnum::PermissionDenied)
+            || err.matches(gst::ResourceError::NotAuthorized)
+        {
+            SongError::PermissionDenied
+        } else if err.matches(gst::StreamError::TypeNotFound)
+            || err.matches(gst::StreamError::WrongType)
+            || err.matches(gst::StreamError::CodecNotFound)
+            || err.matches(gst::CoreError::MissingPlugin)
+        {
+            SongError::UnsupportedFormat
+        } else {
+            SongError::Unreadable(err.to_string())
+        }
+    }
+}
+
+impl From<std::io::Error> for SongError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => SongError::NotFound,
+            std::io::ErrorKind::PermissionDenied => SongError::PermissionDenied,
+            _ => SongError::Unreadable(err.to_string()),
+        }
+    }
+}
+
+impl From<LoftyError> for SongError {
+    fn from(err: LoftyError) -> Self {
+        match err.kind() {
+            ErrorKind::UnknownFormat => SongError::UnsupportedFormat,
+            ErrorKind::Io(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => SongError::NotFound,
+                std::io::ErrorKind::PermissionDenied => SongError::PermissionDenied,
+                _ => SongError::Unreadable(e.to_string()),
+            },
+            _ => SongError::CorruptTags(err.to_string()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SongData {
     artist: Option<String>,
     title: Option<String>,
     album: Option<String>,
+    genre: Option<String>,
+    disc_number: Option<u32>,
+    track_number: Option<u32>,
+    year: Option<u32>,
     cover_art: Option<CoverArt>,
     cover_uuid: Option<String>,
     uuid: Option<String>,
     duration: u64,
     file: gio::File,
+    stream: Option<StreamData>,
+}
+
+// Internet radio streams have no duration, and their metadata
+// changes while playing, whenever the station moves to a new track
+#[derive(Debug, Clone, Default)]
+pub struct StreamData {
+    station: Option<String>,
 }
 
 impl SongData {
@@ -45,6 +147,22 @@ impl SongData {
         self.album.as_deref()
     }
 
+    pub fn genre(&self) -> Option<&str> {
+        self.genre.as_deref()
+    }
+
+    pub fn disc_number(&self) -> Option<u32> {
+        self.disc_number
+    }
+
+    pub fn track_number(&self) -> Option<u32> {
+        self.track_number
+    }
+
+    pub fn year(&self) -> Option<u32> {
+        self.year
+    }
+
     pub fn uuid(&self) -> Option<&str> {
         self.uuid.as_deref()
     }
@@ -57,7 +175,11 @@ impl SongData {
         self.duration
     }
 
-    pub fn cover_texture(&self) -> Option<&gdk::Texture> {
+    pub fn cover_art(&self) -> Option<&CoverArt> {
+        self.cover_art.as_ref()
+    }
+
+    pub fn cover_texture(&self) -> Option<gdk::Texture> {
         if let Some(cover) = &self.cover_art {
             return Some(cover.texture());
         }
@@ -65,7 +187,7 @@ impl SongData {
         None
     }
 
-    pub fn cover_palette(&self) -> Option<&Vec<gdk::RGBA>> {
+    pub fn cover_palette(&self) -> Option<&CoverPalette> {
         if let Some(cover) = &self.cover_art {
             return Some(cover.palette());
         }
@@ -81,25 +203,172 @@ impl SongData {
         None
     }
 
-    pub fn from_uri(uri: &str) -> Self {
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    pub fn station(&self) -> Option<&str> {
+        self.stream.as_ref().and_then(|s| s.station.as_deref())
+    }
+
+    pub fn from_stream(uri: &str) -> Self {
+        let file = gio::File::for_uri(uri);
+
+        let mut hasher = Sha256::new();
+        hasher.update(uri);
+
+        SongData {
+            artist: None,
+            title: None,
+            album: None,
+            genre: None,
+            disc_number: None,
+            track_number: None,
+            year: None,
+            cover_art: None,
+            cover_uuid: None,
+            uuid: Some(format!("{:x}", hasher.finalize())),
+            duration: 0,
+            file,
+            stream: Some(StreamData::default()),
+        }
+    }
+
+    pub fn from_uri(uri: &str) -> Result<Self, SongError> {
+        if utils::is_stream_uri(uri) {
+            return Ok(SongData::from_stream(uri));
+        }
+
         let now = Instant::now();
 
         let file = gio::File::for_uri(uri);
-        let path = file.path().expect("Unable to find file");
 
-        let tagged_file = match lofty::read_from_path(&path) {
-            Ok(f) => f,
+        let info = file
+            .query_info(
+                "standard::display-name,standard::content-type,standard::size,time::modified",
+                gio::FileQueryInfoFlags::NONE,
+                gio::Cancellable::NONE,
+            )
+            .ok();
+        let stamp = info.as_ref().and_then(FileStamp::from_info);
+
+        // The options may change while we load the song, in which case
+        // we will look for its cover again next time
+        let cover_options = CoverCache::options_key();
+
+        if let Some(ref stamp) = stamp {
+            let cached = MetadataCache::global()
+                .lock()
+                .unwrap()
+                .lookup(uri, stamp)
+                .cloned();
+            if let Some(cached) = cached {
+                debug!(
+                    "Song {:?} ('{:?}') loaded from cache: {} ms",
+                    &cached.uuid,
+                    &cached.title,
+                    now.elapsed().as_millis()
+                );
+                PlayStats::global().lock().unwrap().touch(uri);
+
+                // The cover options changed since we cached the song, so
+                // we look for its cover again, and remember the result
+                let reload_cover = cached.cover_options != cover_options;
+                let res = SongData::from_cache(file, cached, reload_cover);
+                if reload_cover {
+                    MetadataCache::global()
+                        .lock()
+                        .unwrap()
+                        .insert(uri, res.to_cache(*stamp, cover_options));
+                }
+
+                return Ok(res);
+            }
+        }
+
+        let mut res = match read_tagged_file(&file) {
+            Ok(tagged_file) => SongData::from_tagged_file(file, &tagged_file),
+            // GStreamer can play more formats than lofty can parse, and
+            // it does not mind broken tags as much
+            Err(e @ (SongError::UnsupportedFormat | SongError::CorruptTags(_))) => {
+                let is_audio =
+                    info.as_ref()
+                        .and_then(|info| info.content_type())
+                        .map_or(true, |ct| {
+                            gio::content_type_is_mime_type(&ct, "audio/*")
+                                || gio::content_type_is_mime_type(&ct, "video/*")
+                        });
+                if !is_audio {
+                    return Err(e);
+                }
+
+                debug!("Unable to parse {} ({}), using GStreamer", uri, e);
+                match SongData::discover(file) {
+                    Ok(res) => res,
+                    Err(discover_err) => {
+                        warn!("Unable to load file {}: {}, {}", uri, e, discover_err);
+                        return Err(e);
+                    }
+                }
+            }
             Err(e) => {
-                warn!("Unable to open file {:?}: {}", path, e);
-                return SongData::default();
+                warn!("Unable to open file {}: {}", uri, e);
+                return Err(e);
             }
         };
 
-        let mut cover_cache = CoverCache::global().lock().unwrap();
+        res.uuid = info.as_ref().map(|info| {
+            let mut hasher = Sha256::new();
+
+            hasher.update(info.display_name().as_str());
+
+            if let Some(ref artist) = res.artist {
+                hasher.update(artist);
+            }
+            if let Some(ref title) = res.title {
+                hasher.update(title);
+            }
+            if let Some(ref album) = res.album {
+                hasher.update(album);
+            }
+
+            format!("{:x}", hasher.finalize())
+        });
+
+        // The file name is better than no title at all; we do this after
+        // computing the identity of the song, so it does not change
+        if res.title.is_none() {
+            res.title = title_from_file(&res.file);
+        }
+
+        debug!(
+            "Song {:?} ('{:?}') loading time: {} ms",
+            &res.uuid,
+            &res.title,
+            now.elapsed().as_millis()
+        );
+
+        if let Some(stamp) = stamp {
+            MetadataCache::global()
+                .lock()
+                .unwrap()
+                .insert(uri, res.to_cache(stamp, cover_options));
+        }
+
+        // Remember when we first saw the song
+        PlayStats::global().lock().unwrap().touch(uri);
+
+        Ok(res)
+    }
 
+    fn from_tagged_file(file: gio::File, tagged_file: &lofty::TaggedFile) -> Self {
         let mut artist = None;
         let mut title = None;
         let mut album = None;
+        let mut genre = None;
+        let mut disc_number = None;
+        let mut track_number = None;
+        let mut year = None;
         let mut cover_art = None;
         let mut cover_uuid = None;
         if let Some(tag) = tagged_file.primary_tag() {
@@ -107,18 +376,26 @@ impl SongData {
             artist = tag.artist().map(|s| s.to_string());
             title = tag.title().map(|s| s.to_string());
             album = tag.album().map(|s| s.to_string());
-            if let Some(res) = cover_cache.cover_art(&path, tag) {
+            genre = tag.genre().map(|s| s.to_string());
+            disc_number = tag.disk();
+            track_number = tag.track();
+            year = tag.year();
+            if let Some(res) = CoverCache::cover_art(&file, tag) {
                 cover_art = Some(res.0);
                 cover_uuid = Some(res.1);
             }
         } else {
-            warn!("Unable to load primary tag for: {}", uri);
+            warn!("Unable to load primary tag for: {}", file.uri());
             for tag in tagged_file.tags() {
                 debug!("Found tag: {:?}", tag.tag_type());
                 artist = tag.artist().map(|s| s.to_string());
                 title = tag.title().map(|s| s.to_string());
                 album = tag.album().map(|s| s.to_string());
-                if let Some(res) = cover_cache.cover_art(&path, tag) {
+                genre = tag.genre().map(|s| s.to_string());
+                disc_number = tag.disk();
+                track_number = tag.track();
+                year = tag.year();
+                if let Some(res) = CoverCache::cover_art(&file, tag) {
                     cover_art = Some(res.0);
                     cover_uuid = Some(res.1);
                 }
@@ -129,50 +406,188 @@ impl SongData {
             }
         };
 
-        let uuid = match file.query_info(
-            "standard::display-name",
-            gio::FileQueryInfoFlags::NONE,
-            gio::Cancellable::NONE,
-        ) {
-            Ok(info) => {
-                let mut hasher = Sha256::new();
+        let properties = lofty::AudioFile::properties(tagged_file);
+        let duration = properties.duration().as_secs();
+
+        SongData {
+            artist,
+            title,
+            album,
+            genre,
+            disc_number,
+            track_number,
+            year,
+            cover_art,
+            cover_uuid,
+            uuid: None,
+            duration,
+            file,
+            stream: None,
+        }
+    }
 
-                hasher.update(info.display_name().as_str());
+    // Prerolls a decoding pipeline, to find out whether GStreamer can
+    // play the file, and to collect its tags and duration
+    fn discover(file: gio::File) -> Result<Self, SongError> {
+        let pipeline = gst::Pipeline::new();
+        let src = gst::ElementFactory::make("uridecodebin")
+            .property("uri", file.uri().as_str())
+            .build()
+            .map_err(|_| SongError::UnsupportedFormat)?;
+        let sink = gst::ElementFactory::make("fakesink")
+            .build()
+            .map_err(|_| SongError::UnsupportedFormat)?;
+        pipeline
+            .add_many([&src, &sink])
+            .map_err(|_| SongError::UnsupportedFormat)?;
+
+        // We only need the first audio stream
+        let sink_pad = sink.static_pad("sink").unwrap();
+        src.connect_pad_added(move |_, pad| {
+            let is_audio = pad
+                .current_caps()
+                .and_then(|caps| caps.structure(0).map(|s| s.name().starts_with("audio/")))
+                .unwrap_or(false);
+            if is_audio && !sink_pad.is_linked() {
+                let _ = pad.link(&sink_pad);
+            }
+        });
 
-                if let Some(ref artist) = artist {
-                    hasher.update(artist);
-                }
-                if let Some(ref title) = title {
-                    hasher.update(title);
+        let mut res = SongData {
+            file,
+            ..SongData::default()
+        };
+
+        if pipeline.set_state(gst::State::Paused).is_err() {
+            let _ = pipeline.set_state(gst::State::Null);
+            return Err(SongError::UnsupportedFormat);
+        }
+
+        let bus = pipeline.bus().unwrap();
+        let mut prerolled = false;
+        let mut error = None;
+        for msg in bus.iter_timed(gst::ClockTime::from_seconds(DISCOVER_TIMEOUT)) {
+            use gst::MessageView;
+
+            match msg.view() {
+                MessageView::AsyncDone(..) => {
+                    prerolled = true;
+                    break;
                 }
-                if let Some(ref album) = album {
-                    hasher.update(album);
+                MessageView::Error(err) => {
+                    error = Some(SongError::from(err.error()));
+                    break;
                 }
+                MessageView::Tag(tag) => res.merge_tags(&tag.tags()),
+                _ => (),
+            }
+        }
+
+        if prerolled {
+            res.duration = pipeline
+                .query_duration::<gst::ClockTime>()
+                .map(|d| d.seconds())
+                .unwrap_or(0);
+        }
 
-                Some(format!("{:x}", hasher.finalize()))
+        let _ = pipeline.set_state(gst::State::Null);
+
+        match (prerolled, error) {
+            (_, Some(e)) => Err(e),
+            (false, None) => Err(SongError::UnsupportedFormat),
+            (true, None) => Ok(res),
+        }
+    }
+
+    // Keeps the first value of each tag, like lofty's primary tag
+    fn merge_tags(&mut self, tags: &gst::TagList) {
+        use gst::tags;
+
+        if self.artist.is_none() {
+            self.artist = tags.get::<tags::Artist>().map(|t| t.get().to_string());
+        }
+        if self.title.is_none() {
+            self.title = tags.get::<tags::Title>().map(|t| t.get().to_string());
+        }
+        if self.album.is_none() {
+            self.album = tags.get::<tags::Album>().map(|t| t.get().to_string());
+        }
+        if self.genre.is_none() {
+            self.genre = tags.get::<tags::Genre>().map(|t| t.get().to_string());
+        }
+        if self.disc_number.is_none() {
+            self.disc_number = tags.get::<tags::AlbumVolumeNumber>().map(|t| t.get());
+        }
+        if self.track_number.is_none() {
+            self.track_number = tags.get::<tags::TrackNumber>().map(|t| t.get());
+        }
+        if self.year.is_none() {
+            self.year = tags
+                .get::<tags::DateTime>()
+                .and_then(|t| u32::try_from(t.get().year()).ok());
+        }
+    }
+
+    fn from_cache(file: gio::File, cached: CachedMetadata, reload_cover: bool) -> Self {
+        let mut cover_art = match (&cached.cover_uuid, cached.palette()) {
+            (Some(uuid), Some(palette)) if !reload_cover => {
+                CoverCache::cached_cover_art(uuid, palette)
             }
             _ => None,
         };
 
-        let properties = lofty::AudioFile::properties(&tagged_file);
-        let duration = properties.duration().as_secs();
-
-        debug!(
-            "Song {:?} ('{:?}') loading time: {} ms",
-            &uuid,
-            &title,
-            now.elapsed().as_millis()
-        );
+        // If the cover went missing from the cache we don't have a choice
+        // but to load it again from the file
+        let mut cover_uuid = cached.cover_uuid;
+        if reload_cover || (cover_art.is_none() && cover_uuid.is_some()) {
+            cover_uuid = None;
+            if let Ok(tagged_file) = read_tagged_file(&file) {
+                if let Some(tag) = tagged_file.primary_tag() {
+                    if let Some((cover, uuid)) = CoverCache::cover_art(&file, tag) {
+                        cover_art = Some(cover);
+                        cover_uuid = Some(uuid);
+                    }
+                }
+            }
+        }
 
         SongData {
-            artist,
-            title,
-            album,
+            artist: cached.artist,
+            title: cached.title,
+            album: cached.album,
+            genre: cached.genre,
+            disc_number: cached.disc_number,
+            track_number: cached.track_number,
+            year: cached.year,
             cover_art,
             cover_uuid,
-            uuid,
-            duration,
+            uuid: cached.uuid,
+            duration: cached.duration,
             file,
+            stream: None,
+        }
+    }
+
+    fn to_cache(&self, stamp: FileStamp, cover_options: String) -> CachedMetadata {
+        CachedMetadata {
+            stamp,
+            artist: self.artist.clone(),
+            title: self.title.clone(),
+            album: self.album.clone(),
+            genre: self.genre.clone(),
+            disc_number: self.disc_number,
+            track_number: self.track_number,
+            year: self.year,
+            duration: self.duration,
+            uuid: self.uuid.clone(),
+            cover_uuid: self.cover_uuid.clone(),
+            cover_options,
+            palette: self
+                .cover_palette()
+                .map(|p| metadata_cache::colors_to_cache(&p.light)),
+            dark_palette: self
+                .cover_palette()
+                .map(|p| metadata_cache::colors_to_cache(&p.dark)),
         }
     }
 
@@ -185,17 +600,47 @@ impl SongData {
     }
 }
 
+// We read the metadata through a GIO input stream instead of going through
+// the local path, so that songs on GVFS mounts (SMB, SFTP, MTP, etc) work
+// as well as local files
+pub(super) fn read_tagged_file(file: &gio::File) -> Result<lofty::TaggedFile, SongError> {
+    let stream = file.read(gio::Cancellable::NONE)?;
+    if stream.can_seek() {
+        let reader = stream.into_read();
+        Ok(lofty::Probe::new(reader).guess_file_type()?.read()?)
+    } else {
+        // Some GVFS backends cannot seek, and lofty needs to; in that case
+        // we have no choice but to buffer the whole file
+        debug!("Stream for {} is not seekable", file.uri());
+        let (contents, _) = file.load_contents(gio::Cancellable::NONE)?;
+        let reader = Cursor::new(contents.to_vec());
+        Ok(lofty::Probe::new(reader).guess_file_type()?.read()?)
+    }
+}
+
+// Uses the name of the file, without extension
+fn title_from_file(file: &gio::File) -> Option<String> {
+    file.basename()
+        .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().to_string()))
+}
+
+// The placeholder for songs that were not loaded yet
 impl Default for SongData {
     fn default() -> Self {
         SongData {
-            artist: Some("Invalid Artist".to_string()),
-            title: Some("Invalid Title".to_string()),
-            album: Some("Invalid Album".to_string()),
+            artist: None,
+            title: None,
+            album: None,
+            genre: None,
+            disc_number: None,
+            track_number: None,
+            year: None,
             cover_art: None,
             cover_uuid: None,
             uuid: None,
             duration: 0,
             file: gio::File::for_path("/does-not-exist"),
+            stream: None,
         }
     }
 }
@@ -208,6 +653,8 @@ mod imp {
         pub data: RefCell<SongData>,
         pub playing: Cell<bool>,
         pub selected: Cell<bool>,
+        pub broken: Cell<bool>,
+        pub search_fields: RefCell<Option<Rc<SearchFields>>>,
     }
 
     #[glib::object_subclass]
@@ -220,7 +667,7 @@ mod imp {
         fn properties() -> &'static [ParamSpec] {
             static PROPERTIES: Lazy<Vec<ParamSpec>> = Lazy::new(|| {
                 vec![
-                    ParamSpecString::builder("uri").construct_only().build(),
+                    ParamSpecString::builder("uri").read_only().build(),
                     ParamSpecString::builder("artist").read_only().build(),
                     ParamSpecString::builder("title").read_only().build(),
                     ParamSpecString::builder("album").read_only().build(),
@@ -228,8 +675,12 @@ mod imp {
                     ParamSpecObject::builder::<gdk::Texture>("cover")
                         .read_only()
                         .build(),
+                    ParamSpecBoxed::builder::<CoverArt>("cover-art")
+                        .read_only()
+                        .build(),
                     ParamSpecBoolean::builder("playing").build(),
                     ParamSpecBoolean::builder("selected").build(),
+                    ParamSpecBoolean::builder("broken").read_only().build(),
                 ]
             });
             PROPERTIES.as_ref()
@@ -237,17 +688,6 @@ mod imp {
 
         fn set_property(&self, _id: usize, value: &Value, pspec: &ParamSpec) {
             match pspec.name() {
-                "uri" => {
-                    let obj = self.obj();
-                    if let Ok(p) = value.get::<&str>() {
-                        self.data.replace(SongData::from_uri(p));
-                        obj.notify("artist");
-                        obj.notify("title");
-                        obj.notify("album");
-                        obj.notify("duration");
-                        obj.notify("cover");
-                    }
-                }
                 "playing" => {
                     let p = value.get::<bool>().expect("Value must be a boolean");
                     self.playing.set(p);
@@ -269,8 +709,10 @@ mod imp {
                 "duration" => obj.duration().to_value(),
                 "uri" => obj.uri().to_value(),
                 "cover" => obj.cover_texture().to_value(),
+                "cover-art" => obj.cover_art().to_value(),
                 "playing" => self.playing.get().to_value(),
                 "selected" => self.selected.get().to_value(),
+                "broken" => self.broken.get().to_value(),
                 _ => unimplemented!(),
             }
         }
@@ -282,23 +724,74 @@ glib::wrapper! {
 }
 
 impl Song {
-    pub fn new(uri: &str) -> Self {
-        glib::Object::builder::<Self>().property("uri", uri).build()
+    pub fn from_uri(uri: &str) -> Result<Song, SongError> {
+        SongData::from_uri(uri).map(Song::from_data)
     }
 
-    pub fn from_uri(uri: &str) -> Result<Song, &'static str> {
-        let res = Song::new(uri);
-        if res.equals(&Song::default()) {
-            Err("Invalid song")
-        } else {
-            Ok(res)
-        }
+    // Wraps the data loaded by the song loader threads
+    pub fn from_data(data: SongData) -> Song {
+        let res = Song::empty();
+        res.imp().data.replace(data);
+        res
     }
 
     pub fn empty() -> Self {
         glib::Object::new()
     }
 
+    pub fn is_stream(&self) -> bool {
+        self.imp().data.borrow().is_stream()
+    }
+
+    pub fn station(&self) -> Option<String> {
+        self.imp().data.borrow().station().map(|s| s.to_string())
+    }
+
+    // Updates the metadata of a stream with the tags sent by the
+    // station; ICY titles are usually in the "Artist - Title" form
+    pub fn set_stream_metadata(&self, title: Option<&str>, station: Option<&str>) -> bool {
+        let (artist, title) = match title.map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            Some(t) => match t.split_once(" - ") {
+                Some((artist, title)) => (Some(artist.to_string()), Some(title.to_string())),
+                None => (None, Some(t.to_string())),
+            },
+            None => (None, None),
+        };
+
+        let changed = {
+            let mut data = self.imp().data.borrow_mut();
+            let stream = match data.stream.as_mut() {
+                Some(stream) => stream,
+                None => return false,
+            };
+
+            let mut changed = false;
+            if let Some(station) = station {
+                if stream.station.as_deref() != Some(station) {
+                    stream.station = Some(station.to_string());
+                    changed = true;
+                }
+            }
+
+            if title.is_some() && (data.title != title || data.artist != artist) {
+                data.title = title;
+                data.artist = artist;
+                changed = true;
+            }
+
+            changed
+        };
+
+        if changed {
+            self.imp().search_fields.replace(None);
+            self.notify("artist");
+            self.notify("title");
+            self.notify("album");
+        }
+
+        changed
+    }
+
     pub fn equals(&self, other: &Self) -> bool {
         if self.uuid().is_some() && other.uuid().is_some() {
             self.uuid() == other.uuid()
@@ -312,8 +805,13 @@ impl Song {
     }
 
     pub fn artist(&self) -> String {
-        match self.imp().data.borrow().artist() {
+        let data = self.imp().data.borrow();
+        match data.artist() {
             Some(artist) => artist.to_string(),
+            None if data.is_stream() => data
+                .station()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| i18n("Internet radio")),
             None => i18n("Unknown artist"),
         }
     }
@@ -324,6 +822,16 @@ impl Song {
         data_reference
             .title()
             .map(|title| title.to_string())
+            .or_else(|| {
+                if data_reference.is_stream() {
+                    data_reference
+                        .station()
+                        .map(|s| s.to_string())
+                        .or_else(|| Some(data_reference.uri()))
+                } else {
+                    None
+                }
+            })
             .or_else(|| {
                 data_reference
                     .file()
@@ -334,21 +842,62 @@ impl Song {
     }
 
     pub fn album(&self) -> String {
-        match self.imp().data.borrow().album() {
+        let data = self.imp().data.borrow();
+        match data.album() {
             Some(album) => album.to_string(),
+            None if data.is_stream() => data
+                .station()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| i18n("Internet radio")),
             None => i18n("Unknown album"),
         }
     }
 
+    pub fn genre(&self) -> Option<String> {
+        self.imp().data.borrow().genre().map(|s| s.to_string())
+    }
+
+    pub fn disc_number(&self) -> Option<u32> {
+        self.imp().data.borrow().disc_number()
+    }
+
+    pub fn track_number(&self) -> Option<u32> {
+        self.imp().data.borrow().track_number()
+    }
+
+    pub fn year(&self) -> Option<u32> {
+        self.imp().data.borrow().year()
+    }
+
     pub fn cover_texture(&self) -> Option<gdk::Texture> {
-        self.imp().data.borrow().cover_texture().cloned()
+        self.imp().data.borrow().cover_texture()
+    }
+
+    pub fn cover_art(&self) -> Option<CoverArt> {
+        self.imp().data.borrow().cover_art().cloned()
+    }
+
+    // Takes the cover art from a freshly loaded copy of the song
+    pub fn update_cover(&self, data: SongData) {
+        {
+            let mut current = self.imp().data.borrow_mut();
+            current.cover_art = data.cover_art;
+            current.cover_uuid = data.cover_uuid;
+        }
+
+        self.notify("cover");
+        self.notify("cover-art");
     }
 
     pub fn cover_color(&self) -> Option<gdk::RGBA> {
-        self.imp().data.borrow().cover_palette().map(|p| p[0])
+        self.imp()
+            .data
+            .borrow()
+            .cover_palette()
+            .and_then(|p| p.light.first().copied())
     }
 
-    pub fn cover_palette(&self) -> Option<Vec<gdk::RGBA>> {
+    pub fn cover_palette(&self) -> Option<CoverPalette> {
         self.imp().data.borrow().cover_palette().cloned()
     }
 
@@ -386,14 +935,47 @@ impl Song {
         }
     }
 
+    // Whether the song failed to play
+    pub fn broken(&self) -> bool {
+        self.imp().broken.get()
+    }
+
+    pub fn set_broken(&self, broken: bool) {
+        let was_broken = self.imp().broken.replace(broken);
+        if was_broken != broken {
+            self.notify("broken");
+        }
+    }
+
     pub fn uuid(&self) -> Option<String> {
         self.imp().data.borrow().uuid().map(|s| s.to_string())
     }
 
+    // Whether the song has its own artist and title, instead of the
+    // placeholders we show
+    pub fn has_metadata(&self) -> bool {
+        let data = self.imp().data.borrow();
+        data.artist().is_some() && data.title().is_some()
+    }
+
     pub fn search_key(&self) -> String {
         format!("{} {} {}", self.artist(), self.album(), self.title())
     }
 
+    // Normalizing the fields is expensive when searching thousands of
+    // songs on every key press, so we keep them around until the
+    // metadata changes
+    pub fn search_fields(&self) -> Rc<SearchFields> {
+        let imp = self.imp();
+        if let Some(fields) = imp.search_fields.borrow().as_ref() {
+            return fields.clone();
+        }
+
+        let fields = Rc::new(SearchFields::new(self));
+        imp.search_fields.replace(Some(fields.clone()));
+        fields
+    }
+
     pub fn file(&self) -> gio::File {
         self.imp().data.borrow().file()
     }
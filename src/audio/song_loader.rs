@@ -0,0 +1,93 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use async_channel::Receiver;
+use gtk::{gio, prelude::*};
+use log::{debug, warn};
+
+use crate::audio::song::{SongData, SongError};
+
+// The maximum number of threads parsing tags and loading cover art
+const MAX_WORKERS: usize = 8;
+
+// The number of songs sent back to the main thread at once; small
+// enough to keep the progress reporting smooth, large enough to avoid
+// waking up the main loop for every file
+const BATCH_SIZE: usize = 32;
+
+// Each batch contains the position of the song in the list of files,
+// so that the main thread can restore the original order; files that
+// cannot be loaded are sent back with the reason why
+pub type SongBatch = Vec<(usize, Result<SongData, SongError>)>;
+
+// Loading metadata for thousands of files takes a long time, mostly
+// spent parsing tags and decoding cover art; we spread the work over a
+// pool of threads, and stream the results back to the main thread in
+// batches. Since GObjects cannot be moved across threads, the workers
+// only create the SongData, and the main thread wraps it into a Song.
+//
+// The returned receiver is closed once all files have been loaded, or
+// once the cancellable has been cancelled.
+pub fn load_songs(files: Vec<gio::File>, cancellable: &gio::Cancellable) -> Receiver<SongBatch> {
+    let (sender, receiver) = async_channel::unbounded();
+
+    let n_workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .clamp(1, MAX_WORKERS)
+        .min(files.len().max(1));
+
+    let uris: Vec<String> = files.iter().map(|f| f.uri().to_string()).collect();
+    let jobs = Arc::new(Mutex::new(uris.into_iter().enumerate()));
+
+    debug!("Loading {} files using {} workers", files.len(), n_workers);
+
+    for i in 0..n_workers {
+        let jobs = jobs.clone();
+        let sender = sender.clone();
+        let cancellable = cancellable.clone();
+
+        let res = thread::Builder::new()
+            .name(format!("song-loader-{i}"))
+            .spawn(move || {
+                let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+                loop {
+                    if cancellable.is_cancelled() {
+                        debug!("Song loading cancelled");
+                        return;
+                    }
+
+                    // Release the lock before loading the song
+                    let job = jobs.lock().unwrap().next();
+                    match job {
+                        Some((pos, uri)) => {
+                            batch.push((pos, SongData::from_uri(&uri)));
+                            if batch.len() == BATCH_SIZE
+                                && sender.send_blocking(std::mem::take(&mut batch)).is_err()
+                            {
+                                // The main thread is gone
+                                return;
+                            }
+                        }
+                        None => break,
+                    }
+                }
+
+                if !batch.is_empty() {
+                    let _ = sender.send_blocking(batch);
+                }
+            });
+
+        if let Err(e) = res {
+            warn!("Unable to spawn song loader thread: {e}");
+        }
+    }
+
+    receiver
+}
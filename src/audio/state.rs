@@ -9,8 +9,8 @@ use crate::audio::{PlaybackState, Song};
 
 mod imp {
     use glib::{
-        ParamSpec, ParamSpecBoolean, ParamSpecDouble, ParamSpecObject, ParamSpecString,
-        ParamSpecUInt64,
+        subclass::Signal, ParamSpec, ParamSpecBoolean, ParamSpecDouble, ParamSpecObject,
+        ParamSpecString, ParamSpecUInt64,
     };
     use once_cell::sync::Lazy;
 
@@ -22,6 +22,7 @@ mod imp {
         pub position: Cell<u64>,
         pub current_song: RefCell<Option<Song>>,
         pub volume: Cell<f64>,
+        pub spectrum: RefCell<Vec<f32>>,
     }
 
     #[glib::object_subclass]
@@ -35,6 +36,7 @@ mod imp {
                 position: Cell::new(0),
                 current_song: RefCell::new(None),
                 volume: Cell::new(1.0),
+                spectrum: RefCell::default(),
             }
         }
     }
@@ -81,6 +83,13 @@ mod imp {
                 _ => unimplemented!(),
             }
         }
+
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> =
+                Lazy::new(|| vec![Signal::builder("spectrum-changed").build()]);
+
+            SIGNALS.as_ref()
+        }
     }
 }
 
@@ -140,6 +149,10 @@ impl PlayerState {
 
     pub fn set_playback_state(&self, playback_state: &PlaybackState) -> bool {
         let old_state = self.imp().playback_state.replace(*playback_state);
+        if *playback_state == PlaybackState::Stopped {
+            self.set_spectrum(vec![]);
+        }
+
         if old_state != *playback_state {
             self.notify("playing");
             return true;
@@ -164,6 +177,14 @@ impl PlayerState {
         self.notify("position");
     }
 
+    // Notifies the song properties that can change while the song is
+    // playing, like the metadata of an internet radio stream
+    pub fn update_metadata(&self) {
+        self.notify("title");
+        self.notify("artist");
+        self.notify("album");
+    }
+
     pub fn position(&self) -> u64 {
         self.imp().position.get()
     }
@@ -188,6 +209,21 @@ impl PlayerState {
             self.notify("volume");
         }
     }
+
+    // The magnitude of each frequency band of the audio being played,
+    // between 0 and 1; this is only updated while the spectrum is enabled
+    pub fn spectrum(&self) -> Vec<f32> {
+        self.imp().spectrum.borrow().clone()
+    }
+
+    pub fn set_spectrum(&self, spectrum: Vec<f32>) {
+        if spectrum.is_empty() && self.imp().spectrum.borrow().is_empty() {
+            return;
+        }
+
+        self.imp().spectrum.replace(spectrum);
+        self.emit_by_name::<()>("spectrum-changed", &[]);
+    }
 }
 
 impl Default for PlayerState {
@@ -0,0 +1,434 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Waveforms are cached in a small binary format, one file per song:
+//
+// ┌───────────────────────────────────────────┐
+// │ magic: "AMBW"                    4 bytes  │
+// │ version: u16                     2 bytes  │
+// │ channels: u8                     1 byte   │
+// │ reserved: u8                     1 byte   │
+// │ interval, in nanoseconds: u64    8 bytes  │
+// │ source modification time: i64    8 bytes  │
+// │ source size: i64                 8 bytes  │
+// │ number of samples: u32           4 bytes  │
+// ├───────────────────────────────────────────┤
+// │ for each sample:                          │
+// │   peak: u16 per channel                   │
+// │   rms: u16 per channel                    │
+// └───────────────────────────────────────────┘
+//
+// All values are little endian; the levels are quantized from the [0, 1]
+// range.
+
+use std::{fmt, fs, path::PathBuf};
+
+use gtk::{gio, prelude::*};
+use log::{debug, warn};
+
+use crate::{audio::metadata_cache::FileStamp, utils};
+
+const MAGIC: &[u8; 4] = b"AMBW";
+
+// Bump this whenever the layout changes; older files are regenerated
+const VERSION: u16 = 2;
+
+const CHANNELS: u8 = 2;
+const HEADER_SIZE: usize = 36;
+// The peak and RMS of each channel
+const SAMPLE_SIZE: usize = 8;
+
+// Older versions stored the peaks as JSON, sampled every 250ms
+const LEGACY_INTERVAL: u64 = 250_000_000;
+
+#[derive(Debug, PartialEq)]
+pub enum WaveformError {
+    Truncated,
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    UnsupportedChannels(u8),
+}
+
+impl fmt::Display for WaveformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "truncated file"),
+            Self::InvalidMagic => write!(f, "not a waveform file"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            Self::UnsupportedChannels(n) => write!(f, "unsupported number of channels {}", n),
+        }
+    }
+}
+
+// The levels of both channels of a song, sampled at regular intervals
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WaveformData {
+    pub peaks: Vec<(f64, f64)>,
+    pub rms: Vec<(f64, f64)>,
+}
+
+impl WaveformData {
+    pub fn push(&mut self, peak: (f64, f64), rms: (f64, f64)) {
+        self.peaks.push(peak);
+        self.rms.push(rms);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peaks.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Waveform {
+    interval: u64,
+    stamp: Option<FileStamp>,
+    data: WaveformData,
+}
+
+fn quantize(value: f64) -> u16 {
+    (value.clamp(0.0, 1.0) * u16::MAX as f64).round() as u16
+}
+
+fn dequantize(value: u16) -> f64 {
+    value as f64 / u16::MAX as f64
+}
+
+fn encode(waveform: &Waveform) -> Vec<u8> {
+    let data = &waveform.data;
+    let n_samples = data.peaks.len().min(data.rms.len());
+    let mut res = Vec::with_capacity(HEADER_SIZE + n_samples * SAMPLE_SIZE);
+
+    // A file we could not query has no identity
+    let (mtime, size) = waveform
+        .stamp
+        .map_or((0, 0), |stamp| (stamp.mtime(), stamp.size()));
+
+    res.extend_from_slice(MAGIC);
+    res.extend_from_slice(&VERSION.to_le_bytes());
+    res.push(CHANNELS);
+    res.push(0);
+    res.extend_from_slice(&waveform.interval.to_le_bytes());
+    res.extend_from_slice(&mtime.to_le_bytes());
+    res.extend_from_slice(&size.to_le_bytes());
+    res.extend_from_slice(&(n_samples as u32).to_le_bytes());
+
+    for (peak, rms) in data.peaks.iter().zip(data.rms.iter()) {
+        for value in [peak.0, peak.1, rms.0, rms.1] {
+            res.extend_from_slice(&quantize(value).to_le_bytes());
+        }
+    }
+
+    res
+}
+
+// Converts the peaks written by older versions; they have no RMS, so we
+// use the one of a sine wave with the same peak
+fn decode_legacy(data: &[u8], stamp: Option<FileStamp>) -> serde_json::Result<Waveform> {
+    let peaks: Vec<(f64, f64)> = serde_json::from_slice(data)?;
+
+    let mut data = WaveformData::default();
+    for peak in peaks {
+        let rms = (
+            peak.0 / std::f64::consts::SQRT_2,
+            peak.1 / std::f64::consts::SQRT_2,
+        );
+        data.push(peak, rms);
+    }
+
+    Ok(Waveform {
+        interval: LEGACY_INTERVAL,
+        stamp,
+        data,
+    })
+}
+
+// Repeats the samples of a waveform measured at a coarser interval, so
+// that it can stand in for a finer one; finer data cannot be reduced
+// without losing the transients, so we ask for it to be regenerated
+fn resample(waveform: Waveform, interval: u64) -> Option<WaveformData> {
+    if waveform.interval == interval {
+        return Some(waveform.data);
+    }
+
+    if interval == 0 || waveform.interval < interval || waveform.interval % interval != 0 {
+        return None;
+    }
+
+    let factor = (waveform.interval / interval) as usize;
+    let mut res = WaveformData::default();
+    for (peak, rms) in waveform.data.peaks.iter().zip(waveform.data.rms.iter()) {
+        for _ in 0..factor {
+            res.push(*peak, *rms);
+        }
+    }
+
+    Some(res)
+}
+
+fn decode(data: &[u8]) -> Result<Waveform, WaveformError> {
+    if data.len() < HEADER_SIZE {
+        return Err(WaveformError::Truncated);
+    }
+
+    let (header, body) = data.split_at(HEADER_SIZE);
+    if &header[0..4] != MAGIC {
+        return Err(WaveformError::InvalidMagic);
+    }
+
+    let version = u16::from_le_bytes(header[4..6].try_into().unwrap());
+    if version != VERSION {
+        return Err(WaveformError::UnsupportedVersion(version));
+    }
+
+    let channels = header[6];
+    if channels != CHANNELS {
+        return Err(WaveformError::UnsupportedChannels(channels));
+    }
+
+    let interval = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let mtime = i64::from_le_bytes(header[16..24].try_into().unwrap());
+    let size = i64::from_le_bytes(header[24..32].try_into().unwrap());
+    let n_samples = u32::from_le_bytes(header[32..36].try_into().unwrap()) as usize;
+
+    if body.len() != n_samples * SAMPLE_SIZE {
+        return Err(WaveformError::Truncated);
+    }
+
+    let mut data = WaveformData::default();
+    for c in body.chunks_exact(SAMPLE_SIZE) {
+        let value = |i: usize| dequantize(u16::from_le_bytes([c[i], c[i + 1]]));
+        data.push((value(0), value(2)), (value(4), value(6)));
+    }
+
+    let stamp = if mtime == 0 && size == 0 {
+        None
+    } else {
+        Some(FileStamp::new(mtime, size))
+    };
+
+    Ok(Waveform {
+        interval,
+        stamp,
+        data,
+    })
+}
+
+// The song a waveform belongs to; all the methods block, so they must
+// be called off the main thread
+#[derive(Debug, Clone)]
+pub struct WaveformSource {
+    uuid: String,
+    stamp: Option<FileStamp>,
+}
+
+impl WaveformSource {
+    pub fn new(uuid: &str, uri: &str) -> Self {
+        let stamp = gio::File::for_uri(uri)
+            .query_info(
+                "standard::size,time::modified",
+                gio::FileQueryInfoFlags::NONE,
+                gio::Cancellable::NONE,
+            )
+            .ok()
+            .and_then(|info| FileStamp::from_info(&info));
+
+        WaveformSource {
+            uuid: uuid.to_string(),
+            stamp,
+        }
+    }
+
+    pub fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    fn path(&self, extension: &str) -> PathBuf {
+        let mut path = utils::cache_dir("waveforms");
+        path.push(format!("{}.{}", self.uuid, extension));
+        path
+    }
+
+    // The cached levels, if they were sampled at the given interval, or
+    // at a coarser one, and the song did not change since
+    pub fn load(&self, interval: u64) -> Option<WaveformData> {
+        let path = self.path("peaks");
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(_) => return self.migrate(interval),
+        };
+
+        let waveform = match decode(&data) {
+            Ok(w) => w,
+            Err(WaveformError::UnsupportedVersion(v)) => {
+                debug!("Waveform cache {:?} has old version {}", &path, v);
+                return None;
+            }
+            Err(e) => {
+                warn!("Discarding waveform cache {:?}: {}", &path, e);
+                let _ = fs::remove_file(&path);
+                return None;
+            }
+        };
+
+        if self.stamp.is_some() && waveform.stamp != self.stamp {
+            debug!("Waveform cache {:?} is out of date", &path);
+            return None;
+        }
+
+        let res = resample(waveform, interval);
+        if res.is_none() {
+            debug!("Waveform cache {:?} uses a different interval", &path);
+        }
+
+        res
+    }
+
+    pub fn save(&self, interval: u64, data: WaveformData) {
+        let path = self.path("peaks");
+        let waveform = Waveform {
+            interval,
+            stamp: self.stamp,
+            data,
+        };
+
+        match fs::write(&path, encode(&waveform)) {
+            Ok(_) => debug!("Waveform cached at: {:?}", &path),
+            Err(e) => warn!("Unable to write waveform cache {:?}: {}", &path, e),
+        }
+    }
+
+    // Converts the JSON file written by older versions, if any, keeping
+    // its interval
+    fn migrate(&self, interval: u64) -> Option<WaveformData> {
+        let legacy_path = self.path("json");
+        let data = fs::read(&legacy_path).ok()?;
+
+        let waveform = match decode_legacy(&data, self.stamp) {
+            Ok(w) => w,
+            Err(e) => {
+                warn!("Discarding waveform cache {:?}: {}", &legacy_path, e);
+                let _ = fs::remove_file(&legacy_path);
+                return None;
+            }
+        };
+
+        let path = self.path("peaks");
+        match fs::write(&path, encode(&waveform)) {
+            Ok(_) => {
+                debug!("Migrated waveform cache {:?}", &legacy_path);
+                let _ = fs::remove_file(&legacy_path);
+            }
+            Err(e) => warn!("Unable to write waveform cache {:?}: {}", &path, e),
+        }
+
+        resample(waveform, interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waveform() -> Waveform {
+        Waveform {
+            interval: 50_000_000,
+            stamp: Some(FileStamp::new(1654041600, 4096)),
+            data: WaveformData {
+                peaks: vec![(0.0, 1.0), (0.5, 0.25), (1.0, 0.0)],
+                rms: vec![(0.0, 0.5), (0.25, 0.125), (0.5, 0.0)],
+            },
+        }
+    }
+
+    fn assert_close(a: &[(f64, f64)], b: &[(f64, f64)]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a.0 - b.0).abs() < 1e-4);
+            assert!((a.1 - b.1).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let waveform = waveform();
+        let data = encode(&waveform);
+        assert_eq!(data.len(), HEADER_SIZE + 3 * SAMPLE_SIZE);
+
+        let res = decode(&data).unwrap();
+        assert_eq!(res.interval, waveform.interval);
+        assert_eq!(res.stamp, waveform.stamp);
+        assert_close(&res.data.peaks, &waveform.data.peaks);
+        assert_close(&res.data.rms, &waveform.data.rms);
+    }
+
+    #[test]
+    fn unknown_stamp() {
+        let mut waveform = waveform();
+        waveform.stamp = None;
+        assert_eq!(decode(&encode(&waveform)).unwrap().stamp, None);
+    }
+
+    #[test]
+    fn out_of_range_levels() {
+        let mut waveform = waveform();
+        waveform.data = WaveformData::default();
+        waveform.data.push((-1.0, 2.0), (0.0, 1.5));
+
+        let res = decode(&encode(&waveform)).unwrap();
+        assert_eq!(res.data.peaks, vec![(0.0, 1.0)]);
+        assert_eq!(res.data.rms, vec![(0.0, 1.0)]);
+    }
+
+    #[test]
+    fn legacy() {
+        let stamp = Some(FileStamp::new(1654041600, 4096));
+        let legacy = decode_legacy(b"[[0.0,1.0],[0.5,0.25]]", stamp).unwrap();
+
+        let res = decode(&encode(&legacy)).unwrap();
+        assert_eq!(res.interval, LEGACY_INTERVAL);
+        assert_eq!(res.stamp, stamp);
+        assert_close(&res.data.peaks, &[(0.0, 1.0), (0.5, 0.25)]);
+        assert_close(&res.data.rms, &[(0.0, 0.70711), (0.35355, 0.17678)]);
+
+        assert!(decode_legacy(b"[[0.5", stamp).is_err());
+    }
+
+    #[test]
+    fn resampling() {
+        let mut coarse = waveform();
+        coarse.interval = 100_000_000;
+        assert_eq!(resample(coarse, 100_000_000).unwrap().peaks.len(), 3);
+
+        let res = resample(waveform(), 10_000_000).unwrap();
+        assert_eq!(res.peaks.len(), 15);
+        assert_eq!(res.rms.len(), 15);
+        assert_eq!(res.peaks[4], (0.0, 1.0));
+        assert_eq!(res.peaks[5], (0.5, 0.25));
+
+        assert!(resample(waveform(), 100_000_000).is_none());
+        assert!(resample(waveform(), 30_000_000).is_none());
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = encode(&waveform());
+
+        assert_eq!(decode(&data[..10]), Err(WaveformError::Truncated));
+        assert_eq!(
+            decode(&data[..data.len() - 1]),
+            Err(WaveformError::Truncated)
+        );
+        assert_eq!(decode(b"[[0.5,0.5]]"), Err(WaveformError::Truncated));
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(decode(&bad_magic), Err(WaveformError::InvalidMagic));
+
+        let mut bad_version = data;
+        bad_version[4] = 42;
+        assert_eq!(
+            decode(&bad_version),
+            Err(WaveformError::UnsupportedVersion(42))
+        );
+    }
+}
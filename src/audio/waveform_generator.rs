@@ -8,7 +8,58 @@ use gst::prelude::*;
 use gtk::{gio, glib, prelude::*, subclass::prelude::*};
 use log::{debug, warn};
 
-use crate::audio::{Controller, PlaybackState, RepeatMode, Song};
+use crate::audio::{
+    waveform_cache::{WaveformData, WaveformSource},
+    Controller, PlaybackState, RepeatMode, Song, WaveformPrefetcher,
+};
+
+// How often we sample the level of the song, in nanoseconds; this is
+// fine enough to zoom into long songs
+pub const PEAK_INTERVAL: u64 = 50_000_000;
+
+// The pipeline measuring the level of a song as fast as possible; the
+// peaks are posted as "level" element messages on its bus
+pub fn analysis_pipeline(uri: &str) -> Result<gst::Element, glib::Error> {
+    let pipeline_str = format!(
+        "uridecodebin name=uridecodebin ! audioconvert ! audio/x-raw,channels=2 ! level name=level interval={} ! fakesink name=faked",
+        PEAK_INTERVAL
+    );
+    let pipeline = gst::parse::launch(&pipeline_str)?;
+
+    let uridecodebin = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("uridecodebin")
+        .unwrap();
+    uridecodebin.set_property("uri", uri);
+
+    let fakesink = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("faked")
+        .unwrap();
+    fakesink.set_property("qos", false);
+    fakesink.set_property("sync", false);
+
+    Ok(pipeline)
+}
+
+// The given field of both channels in a level message, between 0 and 1
+fn level_field(s: &gst::StructureRef, field: &str) -> Option<(f64, f64)> {
+    let array = s.get::<&glib::ValueArray>(field).ok()?;
+    let v1 = array.first()?.get::<f64>().ok()?;
+    let v2 = array.get(1)?.get::<f64>().ok()?;
+    Some((f64::powf(10.0, v1 / 20.0), f64::powf(10.0, v2 / 20.0)))
+}
+
+// The peak and RMS of both channels in a level message
+pub fn level_values(s: &gst::StructureRef) -> Option<((f64, f64), (f64, f64))> {
+    if !s.has_name("level") {
+        return None;
+    }
+
+    Some((level_field(s, "peak")?, level_field(s, "rms")?))
+}
 
 mod imp {
     use glib::{ParamSpec, ParamSpecBoolean, Value};
@@ -19,8 +70,10 @@ mod imp {
     #[derive(Debug, Default)]
     pub struct WaveformGenerator {
         pub song: RefCell<Option<Song>>,
-        pub peaks: RefCell<Option<Vec<(f64, f64)>>>,
+        pub peaks: RefCell<Option<WaveformData>>,
+        pub source: RefCell<Option<WaveformSource>>,
         pub pipeline: RefCell<Option<(gst::Element, gst::bus::BusWatchGuard)>>,
+        pub prefetcher: glib::WeakRef<WaveformPrefetcher>,
     }
 
     #[glib::object_subclass]
@@ -49,7 +102,7 @@ mod imp {
 
         fn property(&self, _id: usize, pspec: &ParamSpec) -> Value {
             match pspec.name() {
-                "has-peaks" => self.obj().peaks().is_some().to_value(),
+                "has-peaks" => self.peaks.borrow().is_some().to_value(),
                 _ => unimplemented!(),
             }
         }
@@ -74,6 +127,8 @@ impl Controller for WaveformGenerator {
         self.load_peaks();
     }
 
+    fn update_song_metadata(&self, _song: &Song) {}
+
     fn set_position(&self, _position: u64) {}
     fn set_repeat_mode(&self, _mode: RepeatMode) {}
 }
@@ -83,40 +138,41 @@ impl WaveformGenerator {
         WaveformGenerator::default()
     }
 
+    // Songs that are already being analysed by the prefetcher are loaded
+    // from the cache once it is done with them
+    pub fn set_prefetcher(&self, prefetcher: &WaveformPrefetcher) {
+        self.imp().prefetcher.set(Some(prefetcher));
+
+        prefetcher.connect_local(
+            "job-finished",
+            false,
+            clone!(@weak self as this => @default-return None, move |values| {
+                let uuid = values[1].get::<String>().unwrap();
+                let current_uuid = this.imp().song.borrow().as_ref().and_then(|s| s.uuid());
+                // Only reload the song if we are waiting for its waveform
+                let waiting = this.imp().peaks.borrow().is_none();
+                if waiting && current_uuid.as_deref() == Some(uuid.as_str()) {
+                    this.load_peaks();
+                }
+                None
+            }),
+        );
+    }
+
     pub fn peaks(&self) -> Option<Vec<(f64, f64)>> {
-        (*self.imp().peaks.borrow()).as_ref().cloned()
+        (*self.imp().peaks.borrow())
+            .as_ref()
+            .map(|d| d.peaks.clone())
     }
 
-    fn save_peaks(&self) {
-        if let Some(peaks) = self.peaks() {
-            let song = match self.imp().song.borrow().as_ref() {
-                Some(s) => s.clone(),
-                None => {
-                    self.notify("has-peaks");
-                    return;
-                }
-            };
+    pub fn rms(&self) -> Option<Vec<(f64, f64)>> {
+        (*self.imp().peaks.borrow()).as_ref().map(|d| d.rms.clone())
+    }
 
-            if let Some(uuid) = song.uuid() {
-                let mut cache = glib::user_cache_dir();
-                cache.push("amberol");
-                cache.push("waveforms");
-                glib::mkdir_with_parents(&cache, 0o755);
-
-                cache.push(format!("{}.json", uuid));
-
-                let j = serde_json::to_string(&peaks).unwrap();
-                let file = gio::File::for_path(&cache);
-                file.replace_contents_async(
-                    j,
-                    None,
-                    false,
-                    gio::FileCreateFlags::NONE,
-                    gio::Cancellable::NONE,
-                    move |_| {
-                        debug!("Waveform cached at: {:?}", &cache);
-                    },
-                );
+    fn save_peaks(&self) {
+        if let Some(data) = self.imp().peaks.borrow().clone() {
+            if let Some(source) = self.imp().source.borrow().clone() {
+                std::thread::spawn(move || source.save(PEAK_INTERVAL, data));
             }
         }
 
@@ -129,43 +185,75 @@ impl WaveformGenerator {
             None => return,
         };
 
+        self.stop_pipeline();
+        self.imp().source.replace(None);
+
+        // Internet radio streams are endless, so there's no waveform
+        if song.is_stream() {
+            self.imp().peaks.replace(None);
+            self.notify("has-peaks");
+            return;
+        }
+
         if let Some(uuid) = song.uuid() {
-            let mut cache = glib::user_cache_dir();
-            cache.push("amberol");
-            cache.push("waveforms");
-            cache.push(format!("{}.json", uuid));
-
-            let file = gio::File::for_path(&cache);
-            file.load_contents_async(
-                gio::Cancellable::NONE,
-                clone!(@strong self as this => move |res| {
-                    match res {
-                        Ok((bytes, _tag)) => {
-                            let p: Vec<(f64, f64)> = serde_json::from_slice(&bytes[..]).unwrap();
-                            this.imp().peaks.replace(Some(p));
+            let uri = song.uri();
+            glib::MainContext::default().spawn_local(clone!(@weak self as this => async move {
+                let res = gio::spawn_blocking(move || {
+                    let source = WaveformSource::new(&uuid, &uri);
+                    let peaks = source.load(PEAK_INTERVAL);
+                    (source, peaks)
+                })
+                .await;
+
+                let (source, peaks) = match res {
+                    Ok(res) => res,
+                    Err(_) => return,
+                };
+
+                // The song might have changed while we were loading
+                let current_uuid = this.imp().song.borrow().as_ref().and_then(|s| s.uuid());
+                if current_uuid.as_deref() != Some(source.uuid()) {
+                    return;
+                }
+
+                this.imp().source.replace(Some(source));
+                match peaks {
+                    Some(p) => {
+                        this.imp().peaks.replace(Some(p));
+                        this.notify("has-peaks");
+                    }
+                    None => {
+                        let prefetcher = this.imp().prefetcher.upgrade();
+                        let uuid = song.uuid().unwrap_or_default();
+                        if prefetcher.map_or(false, |p| p.is_prefetching(&uuid)) {
+                            debug!("Waiting for the prefetched waveform of '{}'", song.uri());
+                            this.imp().peaks.replace(None);
                             this.notify("has-peaks");
-                        }
-                        Err(err) => {
-                            debug!("Could not read waveform cache file: {}", err);
+                        } else {
+                            debug!("No cached waveform for '{}'", song.uri());
                             this.generate_peaks();
                         }
                     }
-                }),
-            );
+                }
+            }));
         }
     }
 
-    fn generate_peaks(&self) {
+    // Stops any running pipeline; dropping its bus watch ensures that
+    // the levels of the previous song do not end up in the waveform of
+    // the next one
+    fn stop_pipeline(&self) {
         if let Some((pipeline, _bus_watch)) = self.imp().pipeline.take() {
-            // Stop any running pipeline, and ensure that we have nothing to
-            // report
-            self.imp().peaks.replace(None);
             pipeline.send_event(gst::event::Eos::new());
             match pipeline.set_state(gst::State::Null) {
                 Ok(_) => {}
                 Err(err) => warn!("Unable to set existing pipeline to Null state: {}", err),
             }
         }
+    }
+
+    fn generate_peaks(&self) {
+        self.stop_pipeline();
 
         let song = match self.imp().song.borrow().as_ref() {
             Some(s) => s.clone(),
@@ -177,11 +265,9 @@ impl WaveformGenerator {
         };
 
         // Reset the peaks vector
-        let peaks: Vec<(f64, f64)> = Vec::new();
-        self.imp().peaks.replace(Some(peaks));
+        self.imp().peaks.replace(Some(WaveformData::default()));
 
-        let pipeline_str = "uridecodebin name=uridecodebin ! audioconvert ! audio/x-raw,channels=2 ! level name=level interval=250000000 ! fakesink name=faked";
-        let pipeline = match gst::parse::launch(pipeline_str) {
+        let pipeline = match analysis_pipeline(&song.uri()) {
             Ok(pipeline) => pipeline,
             Err(err) => {
                 warn!("Unable to generate the waveform: {}", err);
@@ -191,21 +277,6 @@ impl WaveformGenerator {
             }
         };
 
-        let uridecodebin = pipeline
-            .downcast_ref::<gst::Bin>()
-            .unwrap()
-            .by_name("uridecodebin")
-            .unwrap();
-        uridecodebin.set_property("uri", song.uri());
-
-        let fakesink = pipeline
-            .downcast_ref::<gst::Bin>()
-            .unwrap()
-            .by_name("faked")
-            .unwrap();
-        fakesink.set_property("qos", false);
-        fakesink.set_property("sync", false);
-
         let bus = pipeline
             .bus()
             .expect("Pipeline without bus. Shouldn't happen!");
@@ -226,23 +297,16 @@ impl WaveformGenerator {
                 MessageView::Error(err) => {
                     warn!("Pipeline error: {:?}", err);
                     pipeline.set_state(gst::State::Null).expect("Unable to set 'null' state");
-                    // We're done
+                    // The waveform is incomplete, so we do not cache it
                     this.imp().pipeline.replace(None);
-                    this.save_peaks();
+                    this.imp().peaks.replace(None);
+                    this.notify("has-peaks");
                     return glib::ControlFlow::Break;
                 }
                 MessageView::Element(element) => {
-                    if let Some(s) = element.structure() {
-                        if s.has_name("level") {
-                            let peaks_array = s.get::<&glib::ValueArray>("peak").unwrap();
-                            let v1 = peaks_array[0].get::<f64>().unwrap();
-                            let v2 = peaks_array[1].get::<f64>().unwrap();
-                            // Normalize peaks between 0 and 1
-                            let peak1 = f64::powf(10.0, v1 / 20.0);
-                            let peak2 = f64::powf(10.0, v2 / 20.0);
-                            if let Some(ref mut peaks) = *this.imp().peaks.borrow_mut() {
-                                peaks.push((peak1, peak2));
-                            }
+                    if let Some((peak, rms)) = element.structure().and_then(level_values) {
+                        if let Some(ref mut peaks) = *this.imp().peaks.borrow_mut() {
+                            peaks.push(peak, rms);
                         }
                     }
                 }
@@ -0,0 +1,395 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::{Cell, RefCell};
+
+use glib::clone;
+use gst::prelude::*;
+use gtk::{gio, glib, prelude::*, subclass::prelude::*};
+use log::{debug, warn};
+
+use crate::audio::{
+    waveform_cache::{WaveformData, WaveformSource},
+    waveform_generator::{analysis_pipeline, level_values, PEAK_INTERVAL},
+    Controller, PlaybackState, Queue, RepeatMode, Song,
+};
+
+// How many of the upcoming songs we look at
+const LOOKAHEAD: u32 = 5;
+
+// How many songs we analyse at the same time
+const MAX_JOBS: usize = 1;
+
+// A song whose waveform is being generated
+struct Job {
+    uuid: String,
+    source: Option<WaveformSource>,
+    pipeline: Option<(gst::Element, gst::bus::BusWatchGuard)>,
+    data: WaveformData,
+}
+
+impl Job {
+    fn stop(&mut self) {
+        if let Some((pipeline, _bus_watch)) = self.pipeline.take() {
+            if let Err(err) = pipeline.set_state(gst::State::Null) {
+                warn!("Unable to set waveform pipeline to Null state: {}", err);
+            }
+        }
+    }
+}
+
+mod imp {
+    use glib::subclass::Signal;
+    use once_cell::sync::Lazy;
+
+    use super::*;
+
+    #[derive(Default)]
+    pub struct WaveformPrefetcher {
+        pub queue: glib::WeakRef<Queue>,
+        pub pending: RefCell<Vec<Song>>,
+        pub jobs: RefCell<Vec<Job>>,
+        pub buffering: Cell<bool>,
+        pub idle_id: RefCell<Option<glib::SourceId>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for WaveformPrefetcher {
+        const NAME: &'static str = "AmberolWaveformPrefetcher";
+        type Type = super::WaveformPrefetcher;
+    }
+
+    impl ObjectImpl for WaveformPrefetcher {
+        fn dispose(&self) {
+            if let Some(id) = self.idle_id.take() {
+                id.remove();
+            }
+
+            for mut job in self.jobs.take() {
+                job.stop();
+            }
+        }
+
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                vec![Signal::builder("job-finished")
+                    .param_types([String::static_type()])
+                    .build()]
+            });
+
+            SIGNALS.as_ref()
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct WaveformPrefetcher(ObjectSubclass<imp::WaveformPrefetcher>);
+}
+
+// Fills the waveform cache for the songs coming up in the queue, so that
+// their waveform is ready by the time they play
+impl WaveformPrefetcher {
+    pub fn new(queue: &Queue) -> Self {
+        let res: Self = glib::Object::new();
+        res.imp().queue.set(Some(queue));
+
+        queue
+            .model()
+            .connect_items_changed(clone!(@weak res => move |_, _, _, _| {
+                res.schedule();
+            }));
+
+        res
+    }
+
+    // Pauses the analysis while the playback is buffering, so that we do
+    // not compete with it for the disk or the network
+    pub fn set_buffering(&self, buffering: bool) {
+        if self.imp().buffering.replace(buffering) == buffering {
+            return;
+        }
+
+        debug!("Waveform prefetching paused: {}", buffering);
+
+        let state = if buffering {
+            gst::State::Paused
+        } else {
+            gst::State::Playing
+        };
+        for job in self.imp().jobs.borrow().iter() {
+            if let Some((pipeline, _)) = &job.pipeline {
+                if let Err(err) = pipeline.set_state(state) {
+                    warn!("Unable to change waveform pipeline state: {}", err);
+                }
+            }
+        }
+
+        if !buffering {
+            self.schedule();
+        }
+    }
+
+    // Whether the waveform of the song is being generated; the cache is
+    // filled by the time the "job-finished" signal is emitted
+    pub fn is_prefetching(&self, uuid: &str) -> bool {
+        self.imp().jobs.borrow().iter().any(|j| j.uuid == uuid)
+    }
+
+    fn upcoming_songs(&self) -> Vec<Song> {
+        let queue = match self.imp().queue.upgrade() {
+            Some(q) => q,
+            None => return vec![],
+        };
+
+        let n_songs = queue.n_songs();
+        let current = match queue.current_song_index() {
+            Some(c) => c,
+            None => return vec![],
+        };
+
+        let mut res = vec![];
+        for i in 1..=LOOKAHEAD.min(n_songs.saturating_sub(1)) {
+            let pos = current + i;
+            let pos = match queue.repeat_mode() {
+                RepeatMode::RepeatAll => pos % n_songs,
+                _ if pos < n_songs => pos,
+                _ => break,
+            };
+
+            if let Some(song) = queue.song_at(pos) {
+                if !song.is_stream() && song.uuid().is_some() {
+                    res.push(song);
+                }
+            }
+        }
+
+        res
+    }
+
+    // Updates the list of songs to analyse; this happens at low priority,
+    // so that we do not hold up the playback or the UI
+    fn schedule(&self) {
+        if self.imp().idle_id.borrow().is_some() {
+            return;
+        }
+
+        let id = glib::idle_add_local_full(
+            glib::Priority::LOW,
+            clone!(@weak self as this => @default-return glib::ControlFlow::Break, move || {
+                this.imp().idle_id.replace(None);
+                this.update_pending();
+                this.start_jobs();
+                glib::ControlFlow::Break
+            }),
+        );
+        self.imp().idle_id.replace(Some(id));
+    }
+
+    fn update_pending(&self) {
+        let upcoming = self.upcoming_songs();
+        let mut uuids: Vec<String> = upcoming.iter().filter_map(|s| s.uuid()).collect();
+
+        // The job of a song that started playing keeps going, as the
+        // waveform generator waits for it instead of analysing the song
+        // a second time
+        let current = self.imp().queue.upgrade().and_then(|q| q.current_song());
+        if let Some(uuid) = current.and_then(|s| s.uuid()) {
+            uuids.push(uuid);
+        }
+
+        // Stop analysing songs that are not coming up any more
+        self.imp().jobs.borrow_mut().retain_mut(|job| {
+            if uuids.contains(&job.uuid) {
+                true
+            } else {
+                debug!("Cancelling waveform prefetch for '{}'", job.uuid);
+                job.stop();
+                false
+            }
+        });
+
+        let jobs = self.imp().jobs.borrow();
+        let pending = upcoming
+            .into_iter()
+            .filter(|s| !jobs.iter().any(|j| s.uuid().as_deref() == Some(&j.uuid)))
+            .collect();
+        self.imp().pending.replace(pending);
+    }
+
+    fn start_jobs(&self) {
+        let imp = self.imp();
+        while !imp.buffering.get() && imp.jobs.borrow().len() < MAX_JOBS {
+            if imp.pending.borrow().is_empty() {
+                break;
+            }
+
+            let song = imp.pending.borrow_mut().remove(0);
+            self.start_job(&song);
+        }
+    }
+
+    fn start_job(&self, song: &Song) {
+        let uuid = song.uuid().unwrap();
+        let uri = song.uri();
+
+        self.imp().jobs.borrow_mut().push(Job {
+            uuid: uuid.clone(),
+            source: None,
+            pipeline: None,
+            data: WaveformData::default(),
+        });
+
+        glib::MainContext::default().spawn_local(clone!(@weak self as this => async move {
+            let job_uuid = uuid.clone();
+            let job_uri = uri.clone();
+            let res = gio::spawn_blocking(move || {
+                let source = WaveformSource::new(&job_uuid, &job_uri);
+                let cached = source.load(PEAK_INTERVAL).is_some();
+                (source, cached)
+            })
+            .await;
+
+            match res {
+                Ok((_, true)) | Err(_) => this.finish_job(&uuid, false),
+                Ok((source, false)) => this.run_job(&uuid, &uri, source),
+            }
+        }));
+    }
+
+    fn run_job(&self, uuid: &str, uri: &str, source: WaveformSource) {
+        // The job was cancelled while we were checking the cache
+        if !self.imp().jobs.borrow().iter().any(|j| j.uuid == uuid) {
+            return;
+        }
+
+        debug!("Prefetching waveform for '{}'", uri);
+
+        let pipeline = match analysis_pipeline(uri) {
+            Ok(p) => p,
+            Err(err) => {
+                warn!("Unable to prefetch the waveform: {}", err);
+                self.finish_job(uuid, false);
+                return;
+            }
+        };
+
+        let bus = pipeline
+            .bus()
+            .expect("Pipeline without bus. Shouldn't happen!");
+
+        let job_uuid = uuid.to_string();
+        let bus_watch = bus.add_watch_local(
+            clone!(@weak self as this => @default-return glib::ControlFlow::Break, move |_, msg| {
+                use gst::MessageView;
+
+                match msg.view() {
+                    MessageView::Eos(..) => {
+                        this.finish_job(&job_uuid, true);
+                        return glib::ControlFlow::Break;
+                    }
+                    MessageView::Error(err) => {
+                        warn!("Waveform prefetch error: {:?}", err);
+                        this.finish_job(&job_uuid, false);
+                        return glib::ControlFlow::Break;
+                    }
+                    MessageView::Element(element) => {
+                        if let Some((peak, rms)) = element.structure().and_then(level_values) {
+                            let mut jobs = this.imp().jobs.borrow_mut();
+                            if let Some(job) = jobs.iter_mut().find(|j| j.uuid == job_uuid) {
+                                job.data.push(peak, rms);
+                            }
+                        }
+                    }
+                    _ => (),
+                };
+
+                glib::ControlFlow::Continue
+            }),
+        );
+
+        let bus_watch = match bus_watch {
+            Ok(w) => w,
+            Err(err) => {
+                warn!("Unable to prefetch the waveform: {}", err);
+                self.finish_job(uuid, false);
+                return;
+            }
+        };
+
+        let state = if self.imp().buffering.get() {
+            gst::State::Paused
+        } else {
+            gst::State::Playing
+        };
+
+        if let Err(err) = pipeline.set_state(state) {
+            warn!("Unable to prefetch the waveform: {}", err);
+            let _ = pipeline.set_state(gst::State::Null);
+            self.finish_job(uuid, false);
+            return;
+        }
+
+        let mut jobs = self.imp().jobs.borrow_mut();
+        if let Some(job) = jobs.iter_mut().find(|j| j.uuid == uuid) {
+            job.source = Some(source);
+            job.pipeline = Some((pipeline, bus_watch));
+        }
+    }
+
+    fn finish_job(&self, uuid: &str, completed: bool) {
+        let job = {
+            let mut jobs = self.imp().jobs.borrow_mut();
+            jobs.iter()
+                .position(|j| j.uuid == uuid)
+                .map(|pos| jobs.remove(pos))
+        };
+
+        if let Some(mut job) = job {
+            job.stop();
+
+            let source = job
+                .source
+                .take()
+                .filter(|_| completed && !job.data.is_empty());
+            match source {
+                Some(source) => {
+                    // Let the waveform generator know once the cache is filled
+                    let data = std::mem::take(&mut job.data);
+                    let uuid = uuid.to_string();
+                    glib::MainContext::default().spawn_local(
+                        clone!(@weak self as this => async move {
+                            let _ = gio::spawn_blocking(move || {
+                                source.save(PEAK_INTERVAL, data)
+                            })
+                            .await;
+                            this.emit_by_name::<()>("job-finished", &[&uuid]);
+                        }),
+                    );
+                }
+                None => self.emit_by_name::<()>("job-finished", &[&uuid]),
+            }
+        }
+
+        self.start_jobs();
+    }
+}
+
+impl Controller for WaveformPrefetcher {
+    fn set_playback_state(&self, playback_state: &PlaybackState) {
+        // A stopped pipeline will not tell us it's done buffering
+        if *playback_state == PlaybackState::Stopped {
+            self.set_buffering(false);
+        }
+    }
+
+    fn set_song(&self, _song: &Song) {
+        self.schedule();
+    }
+
+    fn update_song_metadata(&self, _song: &Song) {}
+    fn set_position(&self, _position: u64) {}
+
+    fn set_repeat_mode(&self, _mode: RepeatMode) {
+        self.schedule();
+    }
+}
@@ -0,0 +1,160 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::RefCell;
+
+use adw::subclass::prelude::*;
+use glib::{clone, subclass::Signal};
+use gtk::{gio, glib, prelude::*, CompositeTemplate};
+
+use crate::{audio::LibraryGroup, cover_picture::CoverPicture};
+
+mod imp {
+    use glib::{ParamSpec, ParamSpecObject, Value};
+    use once_cell::sync::Lazy;
+
+    use super::*;
+
+    #[derive(Debug, Default, CompositeTemplate)]
+    #[template(resource = "/io/bassi/Amberol/browse-row.ui")]
+    pub struct BrowseRow {
+        // Template widgets
+        #[template_child]
+        pub cover_stack: TemplateChild<gtk::Stack>,
+        #[template_child]
+        pub cover_image: TemplateChild<CoverPicture>,
+        #[template_child]
+        pub title_label: TemplateChild<gtk::Inscription>,
+        #[template_child]
+        pub subtitle_label: TemplateChild<gtk::Inscription>,
+        #[template_child]
+        pub play_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub queue_button: TemplateChild<gtk::Button>,
+
+        pub group: RefCell<Option<LibraryGroup>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for BrowseRow {
+        const NAME: &'static str = "AmberolBrowseRow";
+        type Type = super::BrowseRow;
+        type ParentType = gtk::Widget;
+
+        fn class_init(klass: &mut Self::Class) {
+            Self::bind_template(klass);
+
+            klass.set_layout_manager_type::<gtk::BinLayout>();
+            // Share the style of the rows in the playlist
+            klass.set_css_name("queuerow");
+            klass.set_accessible_role(gtk::AccessibleRole::Group);
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for BrowseRow {
+        fn dispose(&self) {
+            while let Some(child) = self.obj().first_child() {
+                child.unparent();
+            }
+        }
+
+        fn constructed(&self) {
+            self.parent_constructed();
+
+            let obj = self.obj();
+            self.play_button
+                .connect_clicked(clone!(@weak obj => move |_| {
+                    obj.emit_by_name::<()>("play", &[]);
+                }));
+            self.queue_button
+                .connect_clicked(clone!(@weak obj => move |_| {
+                    obj.emit_by_name::<()>("queue", &[]);
+                }));
+        }
+
+        fn properties() -> &'static [ParamSpec] {
+            static PROPERTIES: Lazy<Vec<ParamSpec>> =
+                Lazy::new(|| vec![ParamSpecObject::builder::<LibraryGroup>("group").build()]);
+            PROPERTIES.as_ref()
+        }
+
+        fn set_property(&self, _id: usize, value: &Value, pspec: &ParamSpec) {
+            match pspec.name() {
+                "group" => self
+                    .obj()
+                    .set_group(value.get::<Option<LibraryGroup>>().unwrap()),
+                _ => unimplemented!(),
+            }
+        }
+
+        fn property(&self, _id: usize, pspec: &ParamSpec) -> Value {
+            match pspec.name() {
+                "group" => self.group.borrow().to_value(),
+                _ => unimplemented!(),
+            }
+        }
+
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                vec![
+                    Signal::builder("play").build(),
+                    Signal::builder("queue").build(),
+                ]
+            });
+
+            SIGNALS.as_ref()
+        }
+    }
+
+    impl WidgetImpl for BrowseRow {}
+}
+
+// A row for an artist or a genre in the library, styled like the rows
+// of the playlist; the buttons play the songs, or add them to the
+// playlist, in one click
+glib::wrapper! {
+    pub struct BrowseRow(ObjectSubclass<imp::BrowseRow>)
+        @extends gtk::Widget,
+        @implements gio::ActionGroup, gio::ActionMap;
+}
+
+impl Default for BrowseRow {
+    fn default() -> Self {
+        glib::Object::new()
+    }
+}
+
+impl BrowseRow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn group(&self) -> Option<LibraryGroup> {
+        self.imp().group.borrow().clone()
+    }
+
+    fn set_group(&self, group: Option<LibraryGroup>) {
+        let imp = self.imp();
+
+        let title = group.as_ref().map(|g| g.title());
+        let subtitle = group.as_ref().map(|g| g.subtitle());
+        let cover = group.as_ref().and_then(|g| g.cover_art());
+
+        imp.title_label.set_text(title.as_deref());
+        imp.subtitle_label.set_text(subtitle.as_deref());
+        if let Some(cover) = cover {
+            imp.cover_image.set_cover_art(Some(&cover));
+            imp.cover_stack.set_visible_child_name("cover");
+        } else {
+            imp.cover_image.set_cover_art(None);
+            imp.cover_stack.set_visible_child_name("no-cover");
+        }
+
+        imp.group.replace(group);
+        self.notify("group");
+    }
+}
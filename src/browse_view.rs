@@ -0,0 +1,481 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{cell::RefCell, time::Duration};
+
+use adw::subclass::prelude::*;
+use glib::{clone, subclass::Signal};
+use gtk::{gio, glib, prelude::*, CompositeTemplate};
+use log::debug;
+
+use crate::{
+    album_tile::AlbumTile,
+    audio::{Library, LibraryGroup, SmartPlaylist, Song},
+    browse_row::BrowseRow,
+    i18n::i18n,
+    queue_row::QueueRow,
+    smart_playlist_editor::SmartPlaylistEditor,
+};
+
+// Library changes come in bursts, while indexing or while copying a
+// folder; we only rebuild the views once things settle down
+const REFRESH_DELAY: Duration = Duration::from_millis(500);
+
+mod imp {
+    use once_cell::sync::Lazy;
+
+    use super::*;
+
+    #[derive(Debug, Default, CompositeTemplate)]
+    #[template(resource = "/io/bassi/Amberol/browse-view.ui")]
+    pub struct BrowseView {
+        // Template widgets
+        #[template_child]
+        pub new_playlist_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub view_stack: TemplateChild<adw::ViewStack>,
+        #[template_child]
+        pub artists_view: TemplateChild<gtk::ListView>,
+        #[template_child]
+        pub albums_view: TemplateChild<gtk::GridView>,
+        #[template_child]
+        pub genres_view: TemplateChild<gtk::ListView>,
+        #[template_child]
+        pub playlists_view: TemplateChild<gtk::ListView>,
+
+        pub artists: RefCell<Option<gio::ListStore>>,
+        pub albums: RefCell<Option<gio::ListStore>>,
+        pub genres: RefCell<Option<gio::ListStore>>,
+        pub playlists: RefCell<Option<gio::ListStore>>,
+
+        pub library: RefCell<Option<Library>>,
+        pub notify_nsongs_id: RefCell<Option<glib::SignalHandlerId>>,
+        pub refresh_source: RefCell<Option<glib::SourceId>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for BrowseView {
+        const NAME: &'static str = "AmberolBrowseView";
+        type Type = super::BrowseView;
+        type ParentType = gtk::Widget;
+
+        fn class_init(klass: &mut Self::Class) {
+            Self::bind_template(klass);
+
+            klass.set_layout_manager_type::<gtk::BinLayout>();
+            klass.set_css_name("browseview");
+            klass.set_accessible_role(gtk::AccessibleRole::Group);
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for BrowseView {
+        fn dispose(&self) {
+            if let Some(source) = self.refresh_source.take() {
+                source.remove();
+            }
+
+            if let Some(library) = self.library.take() {
+                if let Some(id) = self.notify_nsongs_id.take() {
+                    library.disconnect(id);
+                }
+            }
+
+            while let Some(child) = self.obj().first_child() {
+                child.unparent();
+            }
+        }
+
+        fn constructed(&self) {
+            self.parent_constructed();
+
+            self.obj().setup_views();
+        }
+
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                vec![
+                    Signal::builder("play-songs")
+                        .param_types([gio::ListModel::static_type(), u32::static_type()])
+                        .build(),
+                    Signal::builder("queue-songs")
+                        .param_types([gio::ListModel::static_type()])
+                        .build(),
+                ]
+            });
+
+            SIGNALS.as_ref()
+        }
+    }
+
+    impl WidgetImpl for BrowseView {}
+}
+
+// The library browser lists the artists, albums, genres, and smart
+// playlists in the library; selecting any of them shows its songs in a
+// new page of the sidebar.
+//
+// The view does not control playback: it emits the "play-songs" and
+// "queue-songs" signals, and lets the window deal with the queue.
+glib::wrapper! {
+    pub struct BrowseView(ObjectSubclass<imp::BrowseView>)
+        @extends gtk::Widget,
+        @implements gio::ActionGroup, gio::ActionMap;
+}
+
+impl Default for BrowseView {
+    fn default() -> Self {
+        glib::Object::new()
+    }
+}
+
+impl BrowseView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn setup_views(&self) {
+        let imp = self.imp();
+
+        let artists = gio::ListStore::new::<LibraryGroup>();
+        imp.artists_view
+            .set_factory(Some(&self.row_factory().upcast::<gtk::ListItemFactory>()));
+        imp.artists_view
+            .set_model(Some(&gtk::NoSelection::new(Some(artists.clone()))));
+        imp.artists_view
+            .connect_activate(clone!(@weak self as view => move |_, pos| {
+                view.show_group_at(view.imp().artists.borrow().as_ref(), pos);
+            }));
+        imp.artists.replace(Some(artists));
+
+        let albums = gio::ListStore::new::<LibraryGroup>();
+        imp.albums_view
+            .set_factory(Some(&self.tile_factory().upcast::<gtk::ListItemFactory>()));
+        imp.albums_view
+            .set_model(Some(&gtk::NoSelection::new(Some(albums.clone()))));
+        imp.albums_view
+            .connect_activate(clone!(@weak self as view => move |_, pos| {
+                view.show_group_at(view.imp().albums.borrow().as_ref(), pos);
+            }));
+        imp.albums.replace(Some(albums));
+
+        let genres = gio::ListStore::new::<LibraryGroup>();
+        imp.genres_view
+            .set_factory(Some(&self.row_factory().upcast::<gtk::ListItemFactory>()));
+        imp.genres_view
+            .set_model(Some(&gtk::NoSelection::new(Some(genres.clone()))));
+        imp.genres_view
+            .connect_activate(clone!(@weak self as view => move |_, pos| {
+                view.show_group_at(view.imp().genres.borrow().as_ref(), pos);
+            }));
+        imp.genres.replace(Some(genres));
+
+        let playlists = gio::ListStore::new::<LibraryGroup>();
+        imp.playlists_view
+            .set_factory(Some(&self.row_factory().upcast::<gtk::ListItemFactory>()));
+        imp.playlists_view
+            .set_model(Some(&gtk::NoSelection::new(Some(playlists.clone()))));
+        imp.playlists_view
+            .connect_activate(clone!(@weak self as view => move |_, pos| {
+                view.show_group_at(view.imp().playlists.borrow().as_ref(), pos);
+            }));
+        imp.playlists.replace(Some(playlists));
+
+        // New playlists can only be created from the playlists page
+        imp.view_stack.connect_visible_child_name_notify(
+            clone!(@weak self as view => move |stack| {
+                let is_playlists = stack.visible_child_name().is_some_and(|n| n == "playlists");
+                view.imp().new_playlist_button.set_visible(is_playlists);
+            }),
+        );
+        imp.new_playlist_button
+            .connect_clicked(clone!(@weak self as view => move |_| {
+                view.edit_smart_playlist(None);
+            }));
+    }
+
+    fn row_factory(&self) -> gtk::SignalListItemFactory {
+        let factory = gtk::SignalListItemFactory::new();
+        factory.connect_setup(clone!(@weak self as view => move |_, item| {
+            let row = BrowseRow::default();
+            let list_item = item.downcast_ref::<gtk::ListItem>().unwrap();
+            list_item.set_child(Some(&row));
+
+            list_item
+                .bind_property("item", &row, "group")
+                .flags(glib::BindingFlags::DEFAULT | glib::BindingFlags::SYNC_CREATE)
+                .build();
+
+            row.connect_local("play", false, clone!(@weak view => @default-return None, move |v| {
+                let row = v[0].get::<BrowseRow>().unwrap();
+                view.play_group(row.group().as_ref());
+                None
+            }));
+            row.connect_local("queue", false, clone!(@weak view => @default-return None, move |v| {
+                let row = v[0].get::<BrowseRow>().unwrap();
+                view.queue_group(row.group().as_ref());
+                None
+            }));
+        }));
+
+        factory
+    }
+
+    fn tile_factory(&self) -> gtk::SignalListItemFactory {
+        let factory = gtk::SignalListItemFactory::new();
+        factory.connect_setup(clone!(@weak self as view => move |_, item| {
+            let tile = AlbumTile::default();
+            let list_item = item.downcast_ref::<gtk::ListItem>().unwrap();
+            list_item.set_child(Some(&tile));
+
+            list_item
+                .bind_property("item", &tile, "group")
+                .flags(glib::BindingFlags::DEFAULT | glib::BindingFlags::SYNC_CREATE)
+                .build();
+
+            tile.connect_local("play", false, clone!(@weak view => @default-return None, move |v| {
+                let tile = v[0].get::<AlbumTile>().unwrap();
+                view.play_group(tile.group().as_ref());
+                None
+            }));
+            tile.connect_local("queue", false, clone!(@weak view => @default-return None, move |v| {
+                let tile = v[0].get::<AlbumTile>().unwrap();
+                view.queue_group(tile.group().as_ref());
+                None
+            }));
+        }));
+
+        factory
+    }
+
+    fn song_factory(&self) -> gtk::SignalListItemFactory {
+        let factory = gtk::SignalListItemFactory::new();
+        factory.connect_setup(|_, item| {
+            let row = QueueRow::default();
+            let list_item = item.downcast_ref::<gtk::ListItem>().unwrap();
+            list_item.set_child(Some(&row));
+
+            list_item
+                .bind_property("item", &row, "song")
+                .flags(glib::BindingFlags::DEFAULT | glib::BindingFlags::SYNC_CREATE)
+                .build();
+
+            list_item
+                .property_expression("item")
+                .chain_property::<Song>("artist")
+                .bind(&row, "song-artist", gtk::Widget::NONE);
+            list_item
+                .property_expression("item")
+                .chain_property::<Song>("title")
+                .bind(&row, "song-title", gtk::Widget::NONE);
+            list_item
+                .property_expression("item")
+                .chain_property::<Song>("cover-art")
+                .bind(&row, "song-cover", gtk::Widget::NONE);
+            list_item
+                .property_expression("item")
+                .chain_property::<Song>("playing")
+                .bind(&row, "playing", gtk::Widget::NONE);
+        });
+
+        factory
+    }
+
+    pub fn set_library(&self, library: &Library) {
+        let imp = self.imp();
+
+        let notify_nsongs_id = library.connect_notify_local(
+            Some("n-songs"),
+            clone!(@weak self as view => move |_, _| {
+                view.queue_refresh();
+            }),
+        );
+
+        if let Some(old) = imp.library.replace(Some(library.clone())) {
+            if let Some(id) = imp.notify_nsongs_id.take() {
+                old.disconnect(id);
+            }
+        }
+        imp.notify_nsongs_id.replace(Some(notify_nsongs_id));
+
+        self.refresh();
+    }
+
+    fn queue_refresh(&self) {
+        if self.imp().refresh_source.borrow().is_some() {
+            return;
+        }
+
+        let source = glib::timeout_add_local_once(
+            REFRESH_DELAY,
+            clone!(@weak self as view => move || {
+                view.imp().refresh_source.replace(None);
+                view.refresh();
+            }),
+        );
+        self.imp().refresh_source.replace(Some(source));
+    }
+
+    fn refresh(&self) {
+        let imp = self.imp();
+
+        let library = match imp.library.borrow().as_ref() {
+            Some(library) => library.clone(),
+            None => return,
+        };
+
+        debug!("Refreshing library views ({} songs)", library.n_songs());
+
+        let update = |store: &RefCell<Option<gio::ListStore>>, groups: Vec<LibraryGroup>| {
+            if let Some(store) = store.borrow().as_ref() {
+                store.splice(0, store.n_items(), &groups);
+            }
+        };
+
+        update(&imp.artists, library.artists());
+        update(&imp.albums, library.albums());
+        update(&imp.genres, library.genres());
+        update(&imp.playlists, library.smart_playlists());
+    }
+
+    fn edit_smart_playlist(&self, playlist: Option<&SmartPlaylist>) {
+        let parent = self.root().and_downcast::<gtk::Window>();
+        let editor = SmartPlaylistEditor::new(parent.as_ref(), playlist);
+
+        // The songs page of an edited playlist is out of date, so we
+        // go back to the list of playlists
+        editor.connect_local(
+            "changed",
+            false,
+            clone!(@weak self as view => @default-return None, move |_| {
+                view.refresh();
+                let nav_view = view
+                    .ancestor(adw::NavigationView::static_type())
+                    .and_downcast::<adw::NavigationView>();
+                let page = view
+                    .ancestor(adw::NavigationPage::static_type())
+                    .and_downcast::<adw::NavigationPage>();
+                if let (Some(nav_view), Some(page)) = (nav_view, page) {
+                    nav_view.pop_to_page(&page);
+                }
+                None
+            }),
+        );
+
+        editor.present();
+    }
+
+    // Smart playlists are evaluated again right before playing them,
+    // as the library and the play stats may have changed since the
+    // view was refreshed
+    fn songs_for_group(&self, group: &LibraryGroup) -> gio::ListStore {
+        let library = self.imp().library.borrow().clone();
+        match (group.smart_playlist(), library) {
+            (Some(playlist), Some(library)) => library.evaluate(&playlist).model(),
+            _ => group.model(),
+        }
+    }
+
+    fn play_group(&self, group: Option<&LibraryGroup>) {
+        if let Some(group) = group {
+            let model = self.songs_for_group(group);
+            self.emit_by_name::<()>("play-songs", &[&model, &0u32]);
+        }
+    }
+
+    fn queue_group(&self, group: Option<&LibraryGroup>) {
+        if let Some(group) = group {
+            let model = self.songs_for_group(group);
+            self.emit_by_name::<()>("queue-songs", &[&model]);
+        }
+    }
+
+    fn show_group_at(&self, store: Option<&gio::ListStore>, pos: u32) {
+        let group = store
+            .and_then(|s| s.item(pos))
+            .and_downcast::<LibraryGroup>();
+        if let Some(group) = group {
+            self.show_group(&group);
+        }
+    }
+
+    // Pushes a new page with the songs of the group to the sidebar
+    fn show_group(&self, group: &LibraryGroup) {
+        let nav_view = match self
+            .ancestor(adw::NavigationView::static_type())
+            .and_downcast::<adw::NavigationView>()
+        {
+            Some(nav_view) => nav_view,
+            None => return,
+        };
+
+        let model = group.model();
+
+        let play_button = gtk::Button::builder()
+            .icon_name("media-playback-start-symbolic")
+            .tooltip_text(i18n("Play"))
+            .build();
+        play_button.connect_clicked(clone!(@weak self as view, @weak group => move |_| {
+            view.play_group(Some(&group));
+        }));
+
+        let queue_button = gtk::Button::builder()
+            .icon_name("list-add-symbolic")
+            .tooltip_text(i18n("Add to Playlist"))
+            .build();
+        queue_button.connect_clicked(clone!(@weak self as view, @weak group => move |_| {
+            view.queue_group(Some(&group));
+        }));
+
+        let header_bar = adw::HeaderBar::builder()
+            .show_end_title_buttons(false)
+            .build();
+        header_bar.pack_end(&play_button);
+        header_bar.pack_end(&queue_button);
+
+        if let Some(playlist) = group.smart_playlist() {
+            let edit_button = gtk::Button::builder()
+                .icon_name("document-edit-symbolic")
+                .tooltip_text(i18n("Edit Smart Playlist"))
+                .build();
+            edit_button.connect_clicked(clone!(@weak self as view => move |_| {
+                view.edit_smart_playlist(Some(&playlist));
+            }));
+            header_bar.pack_end(&edit_button);
+        }
+
+        let list_view = gtk::ListView::builder()
+            .model(&gtk::NoSelection::new(Some(model.clone())))
+            .factory(&self.song_factory())
+            .single_click_activate(true)
+            .build();
+        list_view.add_css_class("navigation-sidebar");
+        list_view.connect_activate(clone!(@weak self as view, @weak model => move |_, pos| {
+            view.emit_by_name::<()>("play-songs", &[&model, &pos]);
+        }));
+
+        let scrolled_window = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .vexpand(true)
+            .child(&list_view)
+            .build();
+
+        let toolbar_view = adw::ToolbarView::builder()
+            .content(&scrolled_window)
+            .build();
+        toolbar_view.add_top_bar(&header_bar);
+        toolbar_view.add_css_class("playlist-background");
+        toolbar_view.add_css_class("darken");
+
+        let page = adw::NavigationPage::builder()
+            .title(group.title())
+            .child(&toolbar_view)
+            .build();
+        page.add_css_class("sidebar-page");
+
+        nav_view.push(&page);
+    }
+}
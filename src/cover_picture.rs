@@ -6,12 +6,15 @@ use std::cell::{Cell, RefCell};
 use glib::clone;
 use gtk::{gdk, gio, glib, graphene, gsk, prelude::*, subclass::prelude::*};
 
+use crate::{audio::CoverArt, utils};
+
 #[derive(Clone, Copy, Debug, glib::Enum, PartialEq, Default)]
 #[enum_type(name = "AmberolCoverSize")]
 pub enum CoverSize {
     #[default]
     Large = 0,
     Small = 1,
+    Medium = 2,
 }
 
 impl AsRef<str> for CoverSize {
@@ -19,23 +22,30 @@ impl AsRef<str> for CoverSize {
         match self {
             CoverSize::Large => "large",
             CoverSize::Small => "small",
+            CoverSize::Medium => "medium",
         }
     }
 }
 
 mod imp {
-    use glib::{ParamSpec, ParamSpecEnum, ParamSpecObject, Value};
+    use glib::{ParamSpec, ParamSpecBoxed, ParamSpecEnum, ParamSpecObject, Value};
     use once_cell::sync::Lazy;
 
     use super::*;
 
     const LARGE_SIZE: i32 = 192;
     const SMALL_SIZE: i32 = 48;
+    const MEDIUM_SIZE: i32 = 128;
 
     #[derive(Debug, Default)]
     pub struct CoverPicture {
         pub cover: RefCell<Option<gdk::Texture>>,
+        // The same cover at different sizes, if available
+        pub cover_art: RefCell<Option<CoverArt>>,
         pub cover_size: Cell<CoverSize>,
+        // The size of the cover we draw, and the one we are loading
+        pub sized_cover: RefCell<Option<(i32, gdk::Texture)>>,
+        pub loading_size: Cell<Option<i32>>,
     }
 
     #[glib::object_subclass]
@@ -69,6 +79,7 @@ mod imp {
             static PROPERTIES: Lazy<Vec<ParamSpec>> = Lazy::new(|| {
                 vec![
                     ParamSpecObject::builder::<gdk::Texture>("cover").build(),
+                    ParamSpecBoxed::builder::<CoverArt>("cover-art").build(),
                     ParamSpecEnum::builder::<CoverSize>("cover-size").build(),
                 ]
             });
@@ -78,6 +89,7 @@ mod imp {
         fn property(&self, _id: usize, pspec: &ParamSpec) -> Value {
             match pspec.name() {
                 "cover" => self.cover.borrow().to_value(),
+                "cover-art" => self.cover_art.borrow().to_value(),
                 "cover-size" => self.cover_size.get().to_value(),
                 _ => unimplemented!(),
             }
@@ -88,6 +100,9 @@ mod imp {
                 "cover" => self
                     .obj()
                     .set_cover(value.get::<gdk::Texture>().ok().as_ref()),
+                "cover-art" => self
+                    .obj()
+                    .set_cover_art(value.get::<CoverArt>().ok().as_ref()),
                 "cover-size" => self
                     .obj()
                     .set_cover_size(value.get::<CoverSize>().expect("Required CoverSize")),
@@ -97,6 +112,13 @@ mod imp {
     }
 
     impl WidgetImpl for CoverPicture {
+        // The larger sizes are only kept while we draw them
+        fn unmap(&self) {
+            self.parent_unmap();
+            self.sized_cover.replace(None);
+            self.loading_size.set(None);
+        }
+
         fn request_mode(&self) -> gtk::SizeRequestMode {
             gtk::SizeRequestMode::ConstantSize
         }
@@ -105,15 +127,25 @@ mod imp {
             match self.cover_size.get() {
                 CoverSize::Large => (LARGE_SIZE, LARGE_SIZE, -1, -1),
                 CoverSize::Small => (SMALL_SIZE, SMALL_SIZE, -1, -1),
+                CoverSize::Medium => (MEDIUM_SIZE, MEDIUM_SIZE, -1, -1),
             }
         }
 
         fn snapshot(&self, snapshot: &gtk::Snapshot) {
-            if let Some(ref cover) = *self.cover.borrow() {
-                let widget = self.obj();
-                let scale_factor = widget.scale_factor() as f64;
-                let width = widget.width() as f64 * scale_factor;
-                let height = widget.height() as f64 * scale_factor;
+            let widget = self.obj();
+            let scale_factor = widget.scale_factor() as f64;
+            let width = widget.width() as f64 * scale_factor;
+            let height = widget.height() as f64 * scale_factor;
+
+            // Pick the smallest texture that fills the widget on the
+            // current display, so that we only ever scale down
+            let size = width.max(height).ceil() as i32;
+            let cover = match *self.cover_art.borrow() {
+                Some(ref cover_art) => Some(widget.texture_for_size(cover_art, size)),
+                None => self.cover.borrow().clone(),
+            };
+
+            if let Some(ref cover) = cover {
                 let ratio = cover.intrinsic_aspect_ratio();
                 let w;
                 let h;
@@ -170,10 +202,74 @@ impl CoverPicture {
             self.imp().cover.replace(None);
         }
 
+        self.imp().sized_cover.replace(None);
+        if self.imp().cover_art.take().is_some() {
+            self.notify("cover-art");
+        }
+
         self.queue_draw();
         self.notify("cover");
     }
 
+    pub fn cover_art(&self) -> Option<CoverArt> {
+        self.imp().cover_art.borrow().clone()
+    }
+
+    // Sets the cover along with all its sizes, so that we can draw the
+    // one that best fits the widget
+    pub fn set_cover_art(&self, cover_art: Option<&CoverArt>) {
+        self.imp()
+            .cover
+            .replace(cover_art.map(|c| c.small_texture().clone()));
+        self.imp().cover_art.replace(cover_art.cloned());
+        self.imp().sized_cover.replace(None);
+        self.imp().loading_size.set(None);
+
+        self.queue_draw();
+        self.notify("cover");
+        self.notify("cover-art");
+    }
+
+    // Decoding the larger sizes takes a while, so we draw the smallest
+    // one until they are ready
+    fn texture_for_size(&self, cover_art: &CoverArt, size: i32) -> gdk::Texture {
+        let imp = self.imp();
+        let size = utils::cover_size(size);
+        if let Some((s, ref texture)) = *imp.sized_cover.borrow() {
+            if s == size {
+                return texture.clone();
+            }
+        }
+
+        if let Some(texture) = cover_art.loaded_texture_for_size(size) {
+            imp.sized_cover.replace(Some((size, texture.clone())));
+            return texture;
+        }
+
+        if imp.loading_size.replace(Some(size)) != Some(size) {
+            let cover_art = cover_art.clone();
+            glib::MainContext::default().spawn_local(clone!(@weak self as this => async move {
+                let c = cover_art.clone();
+                let res = gio::spawn_blocking(move || c.texture_for_size(size)).await;
+
+                // The cover or the size might have changed in the meantime
+                let imp = this.imp();
+                let same_cover = imp.cover_art.borrow().as_ref() == Some(&cover_art);
+                if !same_cover || imp.loading_size.get() != Some(size) {
+                    return;
+                }
+
+                imp.loading_size.set(None);
+                if let Ok(texture) = res {
+                    imp.sized_cover.replace(Some((size, texture)));
+                    this.queue_draw();
+                }
+            }));
+        }
+
+        cover_art.small_texture().clone()
+    }
+
     pub fn set_cover_size(&self, cover_size: CoverSize) {
         self.imp().cover_size.replace(cover_size);
         self.queue_resize();
@@ -0,0 +1,210 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::RefCell;
+
+use adw::subclass::prelude::*;
+use glib::clone;
+use gtk::{gio, glib, prelude::*, CompositeTemplate};
+use log::debug;
+
+use crate::{
+    audio::Library,
+    i18n::{i18n, ni18n_f},
+    utils,
+};
+
+mod imp {
+    use super::*;
+
+    #[derive(Debug, Default, CompositeTemplate)]
+    #[template(resource = "/io/bassi/Amberol/library-window.ui")]
+    pub struct LibraryWindow {
+        // Template widgets
+        #[template_child]
+        pub folders_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub status_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub indexing_spinner: TemplateChild<gtk::Spinner>,
+        #[template_child]
+        pub follow_symlinks_row: TemplateChild<adw::SwitchRow>,
+
+        pub library: RefCell<Option<Library>>,
+        pub signal_ids: RefCell<Vec<glib::SignalHandlerId>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for LibraryWindow {
+        const NAME: &'static str = "AmberolLibraryWindow";
+        type Type = super::LibraryWindow;
+        type ParentType = adw::PreferencesWindow;
+
+        fn class_init(klass: &mut Self::Class) {
+            Self::bind_template(klass);
+
+            klass.install_action("library.add-folder", None, move |win, _, _| {
+                debug!("LibraryWindow::library.add-folder()");
+                win.add_folder();
+            });
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for LibraryWindow {
+        fn constructed(&self) {
+            self.parent_constructed();
+
+            // The library re-indexes itself when the setting changes
+            utils::settings_manager()
+                .bind("follow-symlinks", &*self.follow_symlinks_row, "active")
+                .build();
+        }
+
+        fn dispose(&self) {
+            if let Some(library) = self.library.take() {
+                for id in self.signal_ids.take() {
+                    library.disconnect(id);
+                }
+            }
+        }
+    }
+
+    impl WidgetImpl for LibraryWindow {}
+    impl WindowImpl for LibraryWindow {}
+    impl AdwWindowImpl for LibraryWindow {}
+    impl PreferencesWindowImpl for LibraryWindow {}
+}
+
+glib::wrapper! {
+    pub struct LibraryWindow(ObjectSubclass<imp::LibraryWindow>)
+        @extends gtk::Widget, gtk::Window, adw::Window, adw::PreferencesWindow;
+}
+
+impl LibraryWindow {
+    pub fn new<P: IsA<gtk::Window>>(parent: &P, library: &Library) -> Self {
+        let win = glib::Object::builder::<LibraryWindow>()
+            .property("transient-for", parent)
+            .build();
+
+        win.bind_library(library);
+
+        win
+    }
+
+    fn bind_library(&self, library: &Library) {
+        let imp = self.imp();
+
+        // The list of folders changes every time the library is
+        // re-indexed
+        let enabled_id = library.connect_notify_local(
+            Some("enabled"),
+            clone!(@weak self as win => move |_, _| {
+                win.update_folders();
+            }),
+        );
+        let n_songs_id = library.connect_notify_local(
+            Some("n-songs"),
+            clone!(@weak self as win => move |_, _| {
+                win.update_status();
+            }),
+        );
+        let indexing_id = library.connect_notify_local(
+            Some("indexing"),
+            clone!(@weak self as win => move |_, _| {
+                win.update_status();
+            }),
+        );
+
+        imp.signal_ids
+            .replace(vec![enabled_id, n_songs_id, indexing_id]);
+        imp.library.replace(Some(library.clone()));
+
+        self.update_folders();
+        self.update_status();
+    }
+
+    fn library(&self) -> Library {
+        self.imp().library.borrow().as_ref().unwrap().clone()
+    }
+
+    fn update_folders(&self) {
+        let list = self.imp().folders_list.get();
+        while let Some(child) = list.first_child() {
+            list.remove(&child);
+        }
+
+        let folders = self.library().folders();
+        if folders.is_empty() {
+            let row = adw::ActionRow::builder().title(i18n("No folders")).build();
+            row.add_css_class("dim-label");
+            list.append(&row);
+            return;
+        }
+
+        for folder in folders {
+            let title = folder
+                .basename()
+                .map(|b| b.to_string_lossy().to_string())
+                .unwrap_or_else(|| folder.parse_name().to_string());
+
+            let row = adw::ActionRow::builder()
+                .title(glib::markup_escape_text(&title).as_str())
+                .subtitle(glib::markup_escape_text(&folder.parse_name()).as_str())
+                .build();
+
+            let button = gtk::Button::builder()
+                .icon_name("app-remove-symbolic")
+                .valign(gtk::Align::Center)
+                .tooltip_text(i18n("Remove Folder"))
+                .build();
+            button.add_css_class("flat");
+            button.connect_clicked(clone!(@weak self as win => move |_| {
+                win.library().remove_folder(&folder);
+            }));
+            row.add_suffix(&button);
+
+            list.append(&row);
+        }
+    }
+
+    fn update_status(&self) {
+        let imp = self.imp();
+        let library = self.library();
+
+        let n_songs = library.n_songs();
+        let msg = ni18n_f(
+            // Translators: the `{}` must be left unmodified;
+            // it will be expanded to the number of songs
+            "One song",
+            "{} songs",
+            n_songs,
+            &[&n_songs.to_string()],
+        );
+        imp.status_row.set_subtitle(&msg);
+
+        let indexing = library.is_indexing();
+        imp.indexing_spinner.set_visible(indexing);
+        imp.indexing_spinner.set_spinning(indexing);
+    }
+
+    fn add_folder(&self) {
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let dialog = gtk::FileDialog::builder()
+                .accept_label(i18n("_Add Folder"))
+                .modal(true)
+                .title(i18n("Add Music Folder"))
+                .build();
+
+            if let Ok(files) = dialog.select_multiple_folders_future(Some(&win)).await {
+                let folders: Vec<gio::File> =
+                    files.iter::<gio::File>().filter_map(|f| f.ok()).collect();
+                win.library().add_folders(&folders);
+            }
+        }));
+    }
+}
@@ -1,16 +1,22 @@
 // SPDX-FileCopyrightText: 2022  Emmanuele Bassi
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+mod album_tile;
 mod application;
 mod audio;
+mod browse_row;
+mod browse_view;
 mod config;
 mod cover_picture;
 mod drag_overlay;
 mod i18n;
+mod library_window;
 mod playback_control;
 mod playlist_view;
+mod query;
 mod queue_row;
 mod search;
+mod smart_playlist_editor;
 mod song_cover;
 mod song_details;
 mod sort;
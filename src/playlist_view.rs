@@ -13,6 +13,8 @@ mod imp {
         #[template_child]
         pub back_button: TemplateChild<gtk::Button>,
         #[template_child]
+        pub browse_button: TemplateChild<gtk::Button>,
+        #[template_child]
         pub queue_view: TemplateChild<gtk::ListView>,
         #[template_child]
         pub queue_length_label: TemplateChild<gtk::Label>,
@@ -27,6 +29,8 @@ mod imp {
         #[template_child]
         pub playlist_progress: TemplateChild<gtk::ProgressBar>,
         #[template_child]
+        pub playlist_cancel_button: TemplateChild<gtk::Button>,
+        #[template_child]
         pub playlist_searchbar: TemplateChild<gtk::SearchBar>,
         #[template_child]
         pub playlist_searchentry: TemplateChild<gtk::SearchEntry>,
@@ -94,6 +98,10 @@ impl PlaylistView {
         self.imp().back_button.get()
     }
 
+    pub fn browse_button(&self) -> gtk::Button {
+        self.imp().browse_button.get()
+    }
+
     pub fn queue_actionbar(&self) -> gtk::ActionBar {
         self.imp().queue_actionbar.get()
     }
@@ -129,10 +137,18 @@ impl PlaylistView {
     pub fn begin_loading(&self) {
         self.imp().playlist_progress.set_fraction(0.0);
         self.imp().playlist_progress.set_visible(true);
+        self.imp().playlist_cancel_button.set_visible(true);
     }
 
     pub fn end_loading(&self) {
         self.imp().playlist_progress.set_visible(false);
+        self.imp().playlist_cancel_button.set_visible(false);
+    }
+
+    // We do not know how many files a folder contains until we are done
+    // scanning it
+    pub fn pulse_loading(&self) {
+        self.imp().playlist_progress.pulse();
     }
 
     pub fn update_loading(&self, cur: u32, max: u32) {
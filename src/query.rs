@@ -0,0 +1,735 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// A search query is a list of terms separated by white space:
+//
+// - `field:value` matches the songs whose field contains the value; the fields
+//   are `artist`, `album`, `title`, and `genre`
+// - `year:` and `duration:` take a number, a comparison like `>5m` or `<=1970`,
+//   or an inclusive range like `1960..1970`; durations are in seconds, unless
+//   they have units, like `1m30s`, or are written like `3:20`
+// - `"..."` groups words, both in bare terms and in field values
+// - `-` in front of a term excludes the songs matching it
+// - everything else is fuzzy matched against the artist, album, and title of
+//   the song
+//
+// Terms that cannot be parsed, like `year:soon` or `mood:happy`, are
+// treated as bare text.
+
+use std::ops::{Bound, RangeBounds};
+
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+
+use crate::audio::Song;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Artist,
+    Album,
+    Title,
+    Genre,
+    Year,
+    Duration,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name.to_lowercase().as_str() {
+            "artist" => Some(Field::Artist),
+            "album" => Some(Field::Album),
+            "title" => Some(Field::Title),
+            "genre" => Some(Field::Genre),
+            "year" => Some(Field::Year),
+            "duration" => Some(Field::Duration),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Field::Year | Field::Duration)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    // A case-insensitive sub-string
+    Contains(String),
+    // A range of numbers
+    Range(Bound<u64>, Bound<u64>),
+}
+
+impl Condition {
+    fn parse(field: Field, value: &str) -> Option<Condition> {
+        if !field.is_numeric() {
+            let value = value.trim().to_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+            return Some(Condition::Contains(value));
+        }
+
+        let number = |s: &str| match field {
+            Field::Duration => parse_duration(s),
+            _ => s.trim().parse::<u64>().ok(),
+        };
+
+        let value = value.trim();
+        let (min, max) = if let Some(v) = value.strip_prefix(">=") {
+            (Bound::Included(number(v)?), Bound::Unbounded)
+        } else if let Some(v) = value.strip_prefix("<=") {
+            (Bound::Unbounded, Bound::Included(number(v)?))
+        } else if let Some(v) = value.strip_prefix('>') {
+            (Bound::Excluded(number(v)?), Bound::Unbounded)
+        } else if let Some(v) = value.strip_prefix('<') {
+            (Bound::Unbounded, Bound::Excluded(number(v)?))
+        } else if let Some((a, b)) = value.split_once("..") {
+            let min = match a {
+                "" => Bound::Unbounded,
+                a => Bound::Included(number(a)?),
+            };
+            let max = match b {
+                "" => Bound::Unbounded,
+                b => Bound::Included(number(b)?),
+            };
+            if min == Bound::Unbounded && max == Bound::Unbounded {
+                return None;
+            }
+            (min, max)
+        } else {
+            let n = number(value)?;
+            (Bound::Included(n), Bound::Included(n))
+        };
+
+        Some(Condition::Range(min, max))
+    }
+}
+
+// Parses `90`, `90s`, `1m30s`, `2h`, `3:20`, or `1:02:03` into seconds
+fn parse_duration(s: &str) -> Option<u64> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+
+    if s.contains(':') {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() > 3 {
+            return None;
+        }
+        return parts.iter().try_fold(0u64, |acc, p| {
+            let n = p.parse::<u64>().ok()?;
+            Some(acc * 60 + n)
+        });
+    }
+
+    let mut total = 0;
+    let mut digits = String::new();
+    for c in s.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                digits.push(c);
+                continue;
+            }
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        total += digits.parse::<u64>().ok()? * unit;
+        digits.clear();
+    }
+
+    // Trailing numbers without a unit are seconds
+    if !digits.is_empty() {
+        total += digits.parse::<u64>().ok()?;
+    }
+
+    Some(total)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TermKind {
+    Field(Field, Condition),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Term {
+    pub negated: bool,
+    pub kind: TermKind,
+}
+
+impl Term {
+    fn parse(token: &str) -> Option<Term> {
+        let (negated, rest) = match token.strip_prefix('-') {
+            Some(rest) if !rest.is_empty() => (true, rest),
+            _ => (false, token),
+        };
+
+        // A quoted term is always text, even if it contains a colon
+        if !rest.starts_with('"') {
+            if let Some((name, value)) = rest.split_once(':') {
+                let condition = Field::from_name(name)
+                    .and_then(|field| Some((field, Condition::parse(field, &unquote(value))?)));
+                if let Some((field, condition)) = condition {
+                    return Some(Term {
+                        negated,
+                        kind: TermKind::Field(field, condition),
+                    });
+                }
+            }
+        }
+
+        let text = unquote(rest).trim().to_lowercase();
+        if text.is_empty() {
+            return None;
+        }
+
+        Some(Term {
+            negated,
+            kind: TermKind::Text(text),
+        })
+    }
+
+    // Negated bare terms exclude songs containing the text; a fuzzy
+    // match would exclude far too many songs
+    fn matches(&self, fields: &SearchFields) -> bool {
+        match &self.kind {
+            TermKind::Text(text) => fields.key.contains(text.as_str()),
+            TermKind::Field(field, Condition::Contains(text)) => {
+                let value = match field {
+                    Field::Artist => &fields.artist,
+                    Field::Album => &fields.album,
+                    Field::Title => &fields.title,
+                    Field::Genre => &fields.genre,
+                    Field::Year | Field::Duration => return false,
+                };
+                value.contains(text.as_str())
+            }
+            TermKind::Field(field, Condition::Range(min, max)) => {
+                let value = match field {
+                    Field::Year => fields.year.map(u64::from),
+                    Field::Duration => Some(fields.duration),
+                    _ => None,
+                };
+                value.is_some_and(|v| (*min, *max).contains(&v))
+            }
+        }
+    }
+}
+
+fn unquote(s: &str) -> String {
+    s.chars().filter(|c| *c != '"').collect()
+}
+
+// Splits the query on white space, except inside quotes; an unterminated
+// quote extends to the end of the query. The quotes are kept, so that
+// terms can tell quoted text from field names
+fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in s.chars() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c.is_whitespace() && !quoted {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        current.push(c);
+    }
+
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+// The normalized fields of a song that can be searched
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchFields {
+    pub artist: String,
+    pub album: String,
+    pub title: String,
+    pub genre: String,
+    pub year: Option<u32>,
+    pub duration: u64,
+    // The text used for fuzzy matching
+    pub key: String,
+}
+
+impl SearchFields {
+    pub fn new(song: &Song) -> Self {
+        SearchFields {
+            artist: song.artist().to_lowercase(),
+            album: song.album().to_lowercase(),
+            title: song.title().to_lowercase(),
+            genre: song.genre().unwrap_or_default().to_lowercase(),
+            year: song.year(),
+            duration: song.duration(),
+            key: song.search_key().to_lowercase(),
+        }
+    }
+}
+
+// The characters of the song fields matching a query, as indices of
+// the characters in each field
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Highlights {
+    pub artist: Vec<usize>,
+    pub album: Vec<usize>,
+    pub title: Vec<usize>,
+}
+
+impl Highlights {
+    pub fn is_empty(&self) -> bool {
+        self.artist.is_empty() && self.album.is_empty() && self.title.is_empty()
+    }
+}
+
+// The character indices of every case-insensitive occurrence of the
+// needle; the needle is already lower case
+fn substring_indices(text: &str, needle: &str) -> Vec<usize> {
+    let lower: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+    let needle: Vec<char> = needle.chars().collect();
+
+    // Lower casing may change the number of characters, in which case
+    // we cannot map the indices back to the text
+    if needle.is_empty() || lower.len() != text.chars().count() {
+        return vec![];
+    }
+
+    let mut res = vec![];
+    for start in 0..lower.len() {
+        if lower[start..].starts_with(&needle) {
+            res.extend(start..start + needle.len());
+        }
+    }
+
+    res
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    terms: Vec<Term>,
+    // The positive bare terms, matched together as a single fuzzy
+    // pattern
+    pattern: Option<String>,
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Query {
+        let terms: Vec<Term> = tokenize(s).iter().filter_map(|t| Term::parse(t)).collect();
+
+        let words: Vec<&str> = terms
+            .iter()
+            .filter_map(|t| match &t.kind {
+                TermKind::Text(text) if !t.negated => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        let pattern = if words.is_empty() {
+            None
+        } else {
+            Some(words.join(" "))
+        };
+
+        Query { terms, pattern }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub fn fuzzy_pattern(&self) -> Option<&str> {
+        self.pattern.as_deref()
+    }
+
+    // Whether the query only contains positive bare terms
+    pub fn is_plain_text(&self) -> bool {
+        self.terms
+            .iter()
+            .all(|t| !t.negated && matches!(t.kind, TermKind::Text(_)))
+    }
+
+    pub fn matches(&self, fields: &SearchFields, matcher: &SkimMatcherV2) -> bool {
+        let scoped = self.terms.iter().filter(|t| {
+            // Positive bare terms are part of the fuzzy pattern
+            t.negated || !matches!(t.kind, TermKind::Text(_))
+        });
+        for term in scoped {
+            if term.matches(fields) == term.negated {
+                return false;
+            }
+        }
+
+        match self.fuzzy_pattern() {
+            Some(pattern) => matcher.fuzzy_match(&fields.key, pattern).is_some(),
+            None => true,
+        }
+    }
+
+    // The characters matching the bare terms, or the positive terms for
+    // the same field; the fields are the ones shown to the user, so
+    // they are not normalized
+    pub fn highlights(
+        &self,
+        artist: &str,
+        album: &str,
+        title: &str,
+        matcher: &SkimMatcherV2,
+    ) -> Highlights {
+        let mut res = Highlights::default();
+
+        // The indices of the fuzzy match are relative to the whole key,
+        // which is built like Song::search_key()
+        if let Some(pattern) = self.fuzzy_pattern() {
+            let key = format!("{} {} {}", artist, album, title);
+            if let Some((_, indices)) = matcher.fuzzy_indices(&key, pattern) {
+                let album_start = artist.chars().count() + 1;
+                let title_start = album_start + album.chars().count() + 1;
+                for i in indices {
+                    if i >= title_start {
+                        res.title.push(i - title_start);
+                    } else if i >= album_start {
+                        res.album.push(i - album_start);
+                    } else {
+                        res.artist.push(i);
+                    }
+                }
+            }
+        }
+
+        for term in self.terms.iter().filter(|t| !t.negated) {
+            if let TermKind::Field(field, Condition::Contains(text)) = &term.kind {
+                match field {
+                    Field::Artist => res.artist.extend(substring_indices(artist, text)),
+                    Field::Album => res.album.extend(substring_indices(album, text)),
+                    Field::Title => res.title.extend(substring_indices(title, text)),
+                    _ => {}
+                }
+            }
+        }
+
+        for indices in [&mut res.artist, &mut res.album, &mut res.title] {
+            indices.sort_unstable();
+            indices.dedup();
+        }
+
+        res
+    }
+
+    // The fuzzy score of the song; None if the query has no bare terms,
+    // or if the song does not match them
+    pub fn score(&self, fields: &SearchFields, matcher: &SkimMatcherV2) -> Option<i64> {
+        let pattern = self.fuzzy_pattern()?;
+        matcher.fuzzy_match(&fields.key, pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(negated: bool, s: &str) -> Term {
+        Term {
+            negated,
+            kind: TermKind::Text(s.to_string()),
+        }
+    }
+
+    fn field(negated: bool, field: Field, condition: Condition) -> Term {
+        Term {
+            negated,
+            kind: TermKind::Field(field, condition),
+        }
+    }
+
+    fn contains(s: &str) -> Condition {
+        Condition::Contains(s.to_string())
+    }
+
+    fn song() -> SearchFields {
+        SearchFields {
+            artist: "the beatles".to_string(),
+            album: "abbey road".to_string(),
+            title: "come together".to_string(),
+            genre: "rock".to_string(),
+            year: Some(1969),
+            duration: 259,
+            key: "the beatles abbey road come together".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize(""), Vec::<String>::new());
+        assert_eq!(tokenize("  a   b "), vec!["a", "b"]);
+        assert_eq!(
+            tokenize(r#"album:"abbey road" -live"#),
+            vec![r#"album:"abbey road""#, "-live"]
+        );
+        assert_eq!(tokenize(r#""let it" be"#), vec![r#""let it""#, "be"]);
+        assert_eq!(
+            tokenize(r#"title:"unterminated quote"#),
+            vec![r#"title:"unterminated quote"#]
+        );
+    }
+
+    #[test]
+    fn test_bare_terms() {
+        let q = Query::parse("Beatles help");
+        assert_eq!(&q.terms[..], &[text(false, "beatles"), text(false, "help")]);
+        assert_eq!(q.fuzzy_pattern().as_deref(), Some("beatles help"));
+
+        let q = Query::parse(r#""Let It Be""#);
+        assert_eq!(&q.terms[..], &[text(false, "let it be")]);
+
+        assert!(Query::parse("").is_empty());
+        assert!(Query::parse("   ").is_empty());
+        assert!(Query::parse(r#""""#).is_empty());
+    }
+
+    #[test]
+    fn test_plain_text() {
+        assert!(Query::parse("").is_plain_text());
+        assert!(Query::parse("come together").is_plain_text());
+        assert!(!Query::parse("come -live").is_plain_text());
+        assert!(!Query::parse("come year:1969").is_plain_text());
+    }
+
+    #[test]
+    fn test_negation() {
+        let q = Query::parse("-live -\"demo version\"");
+        assert_eq!(
+            &q.terms[..],
+            &[text(true, "live"), text(true, "demo version")]
+        );
+        assert_eq!(q.fuzzy_pattern(), None);
+
+        // A lone dash is just text
+        let q = Query::parse("-");
+        assert_eq!(&q.terms[..], &[text(false, "-")]);
+
+        let q = Query::parse("-artist:beatles");
+        assert_eq!(
+            &q.terms[..],
+            &[field(true, Field::Artist, contains("beatles"))]
+        );
+    }
+
+    #[test]
+    fn test_text_fields() {
+        let q = Query::parse(r#"artist:Beatles album:"abbey road" TITLE:come genre:rock"#);
+        assert_eq!(
+            &q.terms[..],
+            &[
+                field(false, Field::Artist, contains("beatles")),
+                field(false, Field::Album, contains("abbey road")),
+                field(false, Field::Title, contains("come")),
+                field(false, Field::Genre, contains("rock")),
+            ]
+        );
+
+        // Unknown fields, empty values, and quoted terms are text
+        let q = Query::parse(r#"mood:happy artist: "title:foo""#);
+        assert_eq!(
+            &q.terms[..],
+            &[
+                text(false, "mood:happy"),
+                text(false, "artist:"),
+                text(false, "title:foo")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_numeric_fields() {
+        let range = |min, max| Condition::Range(min, max);
+
+        let q = Query::parse("year:1969 year:1960..1970 year:>=1960 year:<1970");
+        assert_eq!(
+            &q.terms[..],
+            &[
+                field(
+                    false,
+                    Field::Year,
+                    range(Bound::Included(1969), Bound::Included(1969))
+                ),
+                field(
+                    false,
+                    Field::Year,
+                    range(Bound::Included(1960), Bound::Included(1970))
+                ),
+                field(
+                    false,
+                    Field::Year,
+                    range(Bound::Included(1960), Bound::Unbounded)
+                ),
+                field(
+                    false,
+                    Field::Year,
+                    range(Bound::Unbounded, Bound::Excluded(1970))
+                ),
+            ]
+        );
+
+        let q = Query::parse("year:1960.. year:..1970 year:>1960 year:<=1970");
+        assert_eq!(
+            &q.terms[..],
+            &[
+                field(
+                    false,
+                    Field::Year,
+                    range(Bound::Included(1960), Bound::Unbounded)
+                ),
+                field(
+                    false,
+                    Field::Year,
+                    range(Bound::Unbounded, Bound::Included(1970))
+                ),
+                field(
+                    false,
+                    Field::Year,
+                    range(Bound::Excluded(1960), Bound::Unbounded)
+                ),
+                field(
+                    false,
+                    Field::Year,
+                    range(Bound::Unbounded, Bound::Included(1970))
+                ),
+            ]
+        );
+
+        let q = Query::parse("duration:>5m duration:3:20..1m30s");
+        assert_eq!(
+            &q.terms[..],
+            &[
+                field(
+                    false,
+                    Field::Duration,
+                    range(Bound::Excluded(300), Bound::Unbounded)
+                ),
+                field(
+                    false,
+                    Field::Duration,
+                    range(Bound::Included(200), Bound::Included(90))
+                ),
+            ]
+        );
+
+        // Invalid numbers fall back to text
+        let q = Query::parse("year:soon year:.. duration:5x");
+        assert_eq!(
+            &q.terms[..],
+            &[
+                text(false, "year:soon"),
+                text(false, "year:.."),
+                text(false, "duration:5x")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("5m"), Some(300));
+        assert_eq!(parse_duration("1m30s"), Some(90));
+        assert_eq!(parse_duration("1m30"), Some(90));
+        assert_eq!(parse_duration("1h2m"), Some(3720));
+        assert_eq!(parse_duration("3:20"), Some(200));
+        assert_eq!(parse_duration("1:02:03"), Some(3723));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("1:2:3:4"), None);
+        assert_eq!(parse_duration("five"), None);
+    }
+
+    #[test]
+    fn test_matches() {
+        let matcher = SkimMatcherV2::default();
+        let song = song();
+        let matches = |q: &str| Query::parse(q).matches(&song, &matcher);
+
+        assert!(matches(""));
+        assert!(matches("beatles"));
+        assert!(matches("btls cmtgthr"));
+        assert!(!matches("stones"));
+
+        assert!(matches(r#"artist:beatles album:"abbey road""#));
+        assert!(!matches("artist:stones"));
+        assert!(!matches("-artist:beatles"));
+        assert!(matches("-live"));
+        assert!(!matches("-abbey"));
+        assert!(matches("genre:rock -genre:jazz"));
+
+        assert!(matches("year:1969"));
+        assert!(matches("year:1960..1970"));
+        assert!(!matches("year:<1969"));
+        assert!(matches("year:<=1969"));
+        assert!(matches("duration:>4m"));
+        assert!(!matches("duration:>5m"));
+        assert!(matches("duration:4:19"));
+
+        assert!(matches(
+            r#"artist:beatles album:"abbey road" -live year:1960..1970 together"#
+        ));
+        assert!(!matches("artist:beatles help"));
+    }
+
+    #[test]
+    fn test_substring_indices() {
+        assert_eq!(substring_indices("Abbey Road", "road"), vec![6, 7, 8, 9]);
+        assert_eq!(
+            substring_indices("La La Land", "la"),
+            vec![0, 1, 3, 4, 6, 7]
+        );
+        assert_eq!(substring_indices("Help!", "yesterday"), Vec::<usize>::new());
+        assert_eq!(substring_indices("Help!", ""), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_highlights() {
+        let matcher = SkimMatcherV2::default();
+        let highlights = |q: &str| {
+            Query::parse(q).highlights("The Beatles", "Abbey Road", "Come Together", &matcher)
+        };
+
+        assert!(highlights("").is_empty());
+        assert!(highlights("year:1969").is_empty());
+        assert!(highlights("-beatles").is_empty());
+
+        let h = highlights("artist:beat title:\"come t\"");
+        assert_eq!(h.artist, vec![4, 5, 6, 7]);
+        assert!(h.album.is_empty());
+        assert_eq!(h.title, vec![0, 1, 2, 3, 4, 5]);
+
+        // Fuzzy matches are split across fields
+        let h = highlights("together");
+        assert!(h.artist.is_empty());
+        assert!(h.album.is_empty());
+        assert_eq!(h.title, (5..13).collect::<Vec<usize>>());
+
+        let h = highlights("road");
+        assert_eq!(h.album.len(), 4);
+        assert!(h.title.is_empty());
+    }
+
+    #[test]
+    fn test_missing_values() {
+        let matcher = SkimMatcherV2::default();
+        let song = SearchFields {
+            year: None,
+            ..song()
+        };
+
+        assert!(!Query::parse("year:1969").matches(&song, &matcher));
+        assert!(!Query::parse("year:..2000").matches(&song, &matcher));
+        assert!(Query::parse("-year:1969").matches(&song, &matcher));
+    }
+}
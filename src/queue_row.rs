@@ -4,13 +4,21 @@
 use std::cell::{Cell, RefCell};
 
 use adw::subclass::prelude::*;
+use fuzzy_matcher::skim::SkimMatcherV2;
 use glib::clone;
-use gtk::{gdk, gio, glib, prelude::*, CompositeTemplate};
+use gtk::{gio, glib, prelude::*, CompositeTemplate};
 
-use crate::{audio::Song, cover_picture::CoverPicture};
+use crate::{
+    audio::{CoverArt, Song},
+    cover_picture::CoverPicture,
+    i18n::i18n,
+    query::{Highlights, Query},
+};
 
 mod imp {
-    use glib::{ParamSpec, ParamSpecBoolean, ParamSpecObject, ParamSpecString, Value};
+    use glib::{
+        ParamSpec, ParamSpecBoolean, ParamSpecBoxed, ParamSpecObject, ParamSpecString, Value,
+    };
     use once_cell::sync::Lazy;
 
     use super::*;
@@ -42,7 +50,10 @@ mod imp {
 
         pub song: RefCell<Option<Song>>,
         pub playing: Cell<bool>,
+        pub broken: Cell<bool>,
         pub selection_mode: Cell<bool>,
+        pub search: RefCell<Option<String>>,
+        pub query: RefCell<Query>,
     }
 
     #[glib::object_subclass]
@@ -83,10 +94,12 @@ mod imp {
                     ParamSpecObject::builder::<Song>("song").build(),
                     ParamSpecString::builder("song-artist").build(),
                     ParamSpecString::builder("song-title").build(),
-                    ParamSpecObject::builder::<gdk::Texture>("song-cover").build(),
+                    ParamSpecBoxed::builder::<CoverArt>("song-cover").build(),
                     ParamSpecBoolean::builder("playing").build(),
+                    ParamSpecBoolean::builder("broken").build(),
                     ParamSpecBoolean::builder("selection-mode").build(),
                     ParamSpecBoolean::builder("selected").build(),
+                    ParamSpecString::builder("search").build(),
                 ]
             });
             PROPERTIES.as_ref()
@@ -97,6 +110,7 @@ mod imp {
                 "song" => {
                     let song = value.get::<Option<Song>>().unwrap();
                     self.song.replace(song);
+                    self.obj().update_highlights();
                 }
                 "song-artist" => {
                     let p = value.get::<&str>().expect("The value needs to be a string");
@@ -107,7 +121,7 @@ mod imp {
                     self.obj().set_song_title(p);
                 }
                 "song-cover" => {
-                    let p = value.get::<gdk::Texture>().ok();
+                    let p = value.get::<CoverArt>().ok();
                     self.obj().set_song_cover(p);
                 }
                 "playing" => {
@@ -116,6 +130,12 @@ mod imp {
                         .expect("The value needs to be a boolean");
                     self.obj().set_playing(p);
                 }
+                "broken" => {
+                    let p = value
+                        .get::<bool>()
+                        .expect("The value needs to be a boolean");
+                    self.obj().set_broken(p);
+                }
                 "selection-mode" => {
                     let p = value
                         .get::<bool>()
@@ -128,6 +148,10 @@ mod imp {
                         .expect("The value needs to be a boolean");
                     self.selected_button.set_active(p);
                 }
+                "search" => {
+                    let p = value.get::<Option<String>>().unwrap();
+                    self.obj().set_search(p);
+                }
                 _ => unimplemented!(),
             }
         }
@@ -137,10 +161,12 @@ mod imp {
                 "song" => self.song.borrow().to_value(),
                 "song-artist" => self.song_artist_label.text().to_value(),
                 "song-title" => self.song_title_label.text().to_value(),
-                "song-cover" => self.song_cover_image.cover().to_value(),
+                "song-cover" => self.song_cover_image.cover_art().to_value(),
                 "playing" => self.playing.get().to_value(),
+                "broken" => self.broken.get().to_value(),
                 "selection-mode" => self.selection_mode.get().to_value(),
                 "selected" => self.selected_button.is_active().to_value(),
+                "search" => self.search.borrow().to_value(),
                 _ => unimplemented!(),
             }
         }
@@ -184,6 +210,19 @@ impl QueueRow {
         }
     }
 
+    fn set_broken(&self, broken: bool) {
+        if broken != self.imp().broken.replace(broken) {
+            if broken {
+                self.add_css_class("broken");
+            } else {
+                self.remove_css_class("broken");
+            }
+            self.update_mode();
+            self.update_highlights();
+            self.notify("broken");
+        }
+    }
+
     fn set_selection_mode(&self, selection_mode: bool) {
         if selection_mode != self.imp().selection_mode.replace(selection_mode) {
             self.update_mode();
@@ -193,36 +232,119 @@ impl QueueRow {
 
     fn update_mode(&self) {
         let imp = self.imp();
-        if imp.selection_mode.get() {
+
+        // Broken songs show a warning instead of the playing indicator
+        let (icon_name, tooltip) = if imp.broken.get() {
+            (
+                "dialog-warning-symbolic",
+                Some(i18n("Unable to play this song")),
+            )
+        } else {
+            ("audio-only-symbolic", None)
+        };
+        let opacity = if imp.playing.get() || imp.broken.get() {
+            1.0
+        } else {
+            0.0
+        };
+
+        let image = if imp.selection_mode.get() {
             imp.row_stack.set_visible_child_name("selection-mode");
-            let opacity = if imp.playing.get() { 1.0 } else { 0.0 };
-            imp.selection_playing_image.set_opacity(opacity);
+            &imp.selection_playing_image
         } else {
             imp.row_stack.set_visible_child_name("song-details");
-            let opacity = if imp.playing.get() { 1.0 } else { 0.0 };
-            imp.song_playing_image.set_opacity(opacity);
-        }
+            &imp.song_playing_image
+        };
+        image.set_icon_name(Some(icon_name));
+        image.set_tooltip_text(tooltip.as_deref());
+        image.set_opacity(opacity);
     }
 
     fn set_song_title(&self, title: &str) {
         let imp = self.imp();
         imp.song_title_label.set_text(Some(title));
         imp.selection_title_label.set_text(Some(title));
+        self.update_highlights();
     }
 
     fn set_song_artist(&self, artist: &str) {
         let imp = self.imp();
         imp.song_artist_label.set_text(Some(artist));
         imp.selection_artist_label.set_text(Some(artist));
+        self.update_highlights();
+    }
+
+    fn set_search(&self, search: Option<String>) {
+        let imp = self.imp();
+        if *imp.search.borrow() != search {
+            let query = Query::parse(search.as_deref().unwrap_or_default());
+            imp.query.replace(query);
+            imp.search.replace(search);
+            self.update_highlights();
+            self.notify("search");
+        }
+    }
+
+    // Shows which characters of the title and artist matched the
+    // current search
+    fn update_highlights(&self) {
+        let imp = self.imp();
+
+        let highlights = match imp.song.borrow().as_ref() {
+            Some(song) if !imp.query.borrow().is_empty() => imp.query.borrow().highlights(
+                &song.artist(),
+                &song.album(),
+                &song.title(),
+                &SkimMatcherV2::default(),
+            ),
+            _ => Highlights::default(),
+        };
+
+        let labels = [
+            (&imp.song_title_label, &highlights.title),
+            (&imp.selection_title_label, &highlights.title),
+            (&imp.song_artist_label, &highlights.artist),
+            (&imp.selection_artist_label, &highlights.artist),
+        ];
+        for (label, indices) in labels {
+            let text = label.text().map(|t| t.to_string()).unwrap_or_default();
+            if indices.is_empty() {
+                label.set_text(Some(&text));
+            } else {
+                label.set_markup(Some(&highlight_markup(&text, indices)));
+            }
+        }
+
+        if highlights.is_empty() {
+            if imp.broken.get() {
+                let description = i18n("Unable to play this song");
+                self.update_property(&[gtk::accessible::Property::Description(&description)]);
+            } else {
+                self.reset_property(gtk::AccessibleProperty::Description);
+            }
+        } else {
+            let description = match (
+                highlights.artist.is_empty(),
+                highlights.album.is_empty(),
+                highlights.title.is_empty(),
+            ) {
+                (false, _, false) => i18n("Matches the artist and title"),
+                (false, _, true) => i18n("Matches the artist"),
+                (true, _, false) => i18n("Matches the title"),
+                (true, false, true) => i18n("Matches the album"),
+                (true, true, true) => unreachable!(),
+            };
+            self.update_property(&[gtk::accessible::Property::Description(&description)]);
+        }
     }
 
-    fn set_song_cover(&self, cover: Option<gdk::Texture>) {
+    fn set_song_cover(&self, cover: Option<CoverArt>) {
         let imp = self.imp();
-        if let Some(texture) = cover {
-            imp.song_cover_image.set_cover(Some(&texture));
+        if let Some(cover) = cover {
+            imp.song_cover_image.set_cover_art(Some(&cover));
             imp.song_cover_stack.set_visible_child_name("cover");
         } else {
-            imp.song_cover_image.set_cover(None);
+            imp.song_cover_image.set_cover_art(None);
             imp.song_cover_stack.set_visible_child_name("no-cover");
         }
     }
@@ -231,3 +353,35 @@ impl QueueRow {
         self.imp().song.borrow().clone()
     }
 }
+
+// Underlines the characters at the given indices
+fn highlight_markup(text: &str, indices: &[usize]) -> String {
+    let mut res = String::new();
+    let mut run = String::new();
+    let mut in_match = false;
+
+    let mut flush = |run: &mut String, in_match: bool| {
+        if run.is_empty() {
+            return;
+        }
+        let escaped = glib::markup_escape_text(run);
+        if in_match {
+            res.push_str(&format!("<u>{}</u>", escaped));
+        } else {
+            res.push_str(&escaped);
+        }
+        run.clear();
+    };
+
+    for (i, c) in text.chars().enumerate() {
+        let matched = indices.binary_search(&i).is_ok();
+        if matched != in_match {
+            flush(&mut run, in_match);
+            in_match = matched;
+        }
+        run.push(c);
+    }
+    flush(&mut run, in_match);
+
+    res
+}
@@ -3,11 +3,13 @@
 
 use gtk::{glib, prelude::*, subclass::prelude::*};
 
+use crate::query::Query;
+
 mod imp {
 
     use std::cell::RefCell;
 
-    use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+    use fuzzy_matcher::skim::SkimMatcherV2;
     use gtk::{
         glib::{self, ParamSpec, ParamSpecString, Value},
         prelude::*,
@@ -15,11 +17,13 @@ mod imp {
     };
     use once_cell::sync::Lazy;
 
-    use crate::audio::Song;
+    use crate::{audio::Song, query::Query};
 
     #[derive(Default)]
     pub struct FuzzyFilter {
         pub search: RefCell<Option<String>>,
+        pub query: RefCell<Query>,
+        pub matcher: SkimMatcherV2,
     }
 
     #[glib::object_subclass]
@@ -47,23 +51,29 @@ mod imp {
                 _ => unimplemented!(),
             }
         }
+
+        fn property(&self, _id: usize, pspec: &ParamSpec) -> Value {
+            match pspec.name() {
+                "search" => self.obj().search().to_value(),
+                _ => unimplemented!(),
+            }
+        }
     }
 
     impl FilterImpl for FuzzyFilter {
         fn strictness(&self) -> gtk::FilterMatch {
-            gtk::FilterMatch::Some
+            if self.query.borrow().is_empty() {
+                gtk::FilterMatch::All
+            } else {
+                gtk::FilterMatch::Some
+            }
         }
 
         fn match_(&self, song: &glib::Object) -> bool {
             let song = song.downcast_ref::<Song>().unwrap();
 
-            if let Some(search) = self.search.borrow().as_ref() {
-                let key = song.search_key();
-                let matcher = SkimMatcherV2::default();
-                matcher.fuzzy_match(&key, search).is_some() || search.is_empty()
-            } else {
-                true
-            }
+            let query = self.query.borrow();
+            query.is_empty() || query.matches(&song.search_fields(), &self.matcher)
         }
     }
 }
@@ -91,8 +101,38 @@ impl FuzzyFilter {
 
     pub fn set_search(&self, search: Option<String>) {
         if *self.imp().search.borrow() != search {
-            *self.imp().search.borrow_mut() = search.map(|x| x.to_lowercase());
-            self.changed(gtk::FilterChange::Different);
+            let query = Query::parse(search.as_deref().unwrap_or_default());
+            *self.imp().search.borrow_mut() = search;
+
+            let old_query = self.imp().query.replace(query);
+            let change = filter_change(&old_query, &self.imp().query.borrow());
+            if let Some(change) = change {
+                self.changed(change);
+            }
+
+            self.notify("search");
+        }
+    }
+}
+
+// Typing more characters of a fuzzy pattern can only remove matches,
+// and deleting them can only add matches; this lets the filter model
+// avoid checking every song again
+fn filter_change(old: &Query, new: &Query) -> Option<gtk::FilterChange> {
+    if old == new {
+        return None;
+    }
+
+    if old.is_plain_text() && new.is_plain_text() {
+        let old_pattern = old.fuzzy_pattern().unwrap_or_default();
+        let new_pattern = new.fuzzy_pattern().unwrap_or_default();
+        if new_pattern.starts_with(old_pattern) {
+            return Some(gtk::FilterChange::MoreStrict);
+        }
+        if old_pattern.starts_with(new_pattern) {
+            return Some(gtk::FilterChange::LessStrict);
         }
     }
+
+    Some(gtk::FilterChange::Different)
 }
@@ -0,0 +1,381 @@
+// SPDX-FileCopyrightText: 2022  Emmanuele Bassi
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::{Cell, RefCell};
+
+use adw::subclass::prelude::*;
+use glib::{clone, subclass::Signal};
+use gtk::{gio, glib, prelude::*, CompositeTemplate};
+use log::{debug, warn};
+
+use crate::{
+    audio::{FieldKind, Rule, RuleField, RuleOperator, SmartPlaylist},
+    i18n::{i18n, i18n_f},
+};
+
+// The widgets editing a single rule
+#[derive(Debug)]
+pub struct RuleWidgets {
+    row: gtk::ListBoxRow,
+    field: gtk::DropDown,
+    op: gtk::DropDown,
+    value: gtk::Entry,
+}
+
+impl RuleWidgets {
+    fn field(&self) -> RuleField {
+        RuleField::ALL[self.field.selected() as usize]
+    }
+
+    fn rule(&self) -> Rule {
+        let field = self.field();
+        let ops = RuleOperator::for_kind(field.kind());
+        Rule {
+            field,
+            op: ops[(self.op.selected() as usize).min(ops.len() - 1)],
+            value: self.value.text().to_string(),
+        }
+    }
+
+    // The operators depend on the kind of field
+    fn update_operators(&self) {
+        let kind = self.field().kind();
+        let labels: Vec<String> = RuleOperator::for_kind(kind)
+            .iter()
+            .map(|op| op.label())
+            .collect();
+        let labels: Vec<&str> = labels.iter().map(|s| s.as_str()).collect();
+        self.op.set_model(Some(&gtk::StringList::new(&labels)));
+        self.op.set_selected(0);
+
+        let placeholder = match (self.field(), kind) {
+            (RuleField::Duration, _) => i18n("Seconds, or mm:ss"),
+            (_, FieldKind::Text) => i18n("Text"),
+            (_, FieldKind::Number) => i18n("Number"),
+            (_, FieldKind::Date) => i18n("Days"),
+        };
+        self.value.set_placeholder_text(Some(&placeholder));
+    }
+}
+
+mod imp {
+    use once_cell::sync::Lazy;
+
+    use super::*;
+
+    #[derive(Debug, Default, CompositeTemplate)]
+    #[template(resource = "/io/bassi/Amberol/smart-playlist-editor.ui")]
+    pub struct SmartPlaylistEditor {
+        // Template widgets
+        #[template_child]
+        pub toast_overlay: TemplateChild<adw::ToastOverlay>,
+        #[template_child]
+        pub save_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub delete_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub name_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub match_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub limit_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub rules_list: TemplateChild<gtk::ListBox>,
+
+        pub playlist: RefCell<SmartPlaylist>,
+        pub is_new: Cell<bool>,
+        pub rules: RefCell<Vec<RuleWidgets>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for SmartPlaylistEditor {
+        const NAME: &'static str = "AmberolSmartPlaylistEditor";
+        type Type = super::SmartPlaylistEditor;
+        type ParentType = adw::Window;
+
+        fn class_init(klass: &mut Self::Class) {
+            Self::bind_template(klass);
+
+            klass.install_action("editor.save", None, move |win, _, _| {
+                debug!("SmartPlaylistEditor::editor.save()");
+                win.save();
+            });
+            klass.install_action("editor.delete", None, move |win, _, _| {
+                debug!("SmartPlaylistEditor::editor.delete()");
+                win.delete();
+            });
+            klass.install_action("editor.add-rule", None, move |win, _, _| {
+                win.add_rule(&Rule::default());
+            });
+            klass.install_action("editor.import", None, move |win, _, _| {
+                win.import();
+            });
+            klass.install_action("editor.export", None, move |win, _, _| {
+                win.export();
+            });
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for SmartPlaylistEditor {
+        fn constructed(&self) {
+            self.parent_constructed();
+
+            let obj = self.obj();
+            self.name_row
+                .connect_changed(clone!(@weak obj => move |row| {
+                    obj.action_set_enabled("editor.save", !row.text().trim().is_empty());
+                }));
+        }
+
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> =
+                Lazy::new(|| vec![Signal::builder("changed").build()]);
+
+            SIGNALS.as_ref()
+        }
+    }
+
+    impl WidgetImpl for SmartPlaylistEditor {}
+    impl WindowImpl for SmartPlaylistEditor {}
+    impl AdwWindowImpl for SmartPlaylistEditor {}
+}
+
+// The editor for the name and rules of a smart playlist; the playlist
+// is stored when saving, and the "changed" signal is emitted whenever
+// a playlist is saved or deleted
+glib::wrapper! {
+    pub struct SmartPlaylistEditor(ObjectSubclass<imp::SmartPlaylistEditor>)
+        @extends gtk::Widget, gtk::Window, adw::Window;
+}
+
+impl SmartPlaylistEditor {
+    pub fn new<P: IsA<gtk::Window>>(parent: Option<&P>, playlist: Option<&SmartPlaylist>) -> Self {
+        let win = glib::Object::builder::<SmartPlaylistEditor>()
+            .property("transient-for", parent)
+            .build();
+
+        let imp = win.imp();
+        imp.is_new.set(playlist.is_none());
+        imp.delete_button.set_visible(playlist.is_some());
+
+        let playlist = match playlist {
+            Some(pls) => pls.clone(),
+            None => SmartPlaylist::new(&i18n("New Smart Playlist")),
+        };
+        win.set_playlist(&playlist);
+        imp.playlist.replace(playlist);
+
+        win
+    }
+
+    fn set_playlist(&self, playlist: &SmartPlaylist) {
+        let imp = self.imp();
+
+        imp.name_row.set_text(&playlist.name);
+        imp.match_row
+            .set_selected(if playlist.match_any { 1 } else { 0 });
+        imp.limit_row.set_value(playlist.limit.unwrap_or(0) as f64);
+
+        for rule in imp.rules.take() {
+            imp.rules_list.remove(&rule.row);
+        }
+        for rule in &playlist.rules {
+            self.add_rule(rule);
+        }
+    }
+
+    // Returns the playlist being edited, with the current contents of
+    // the editor
+    fn playlist(&self) -> SmartPlaylist {
+        let imp = self.imp();
+
+        let limit = imp.limit_row.value() as u32;
+        let mut playlist = imp.playlist.borrow().clone();
+        playlist.name = imp.name_row.text().trim().to_string();
+        playlist.match_any = imp.match_row.selected() == 1;
+        playlist.rules = imp.rules.borrow().iter().map(|r| r.rule()).collect();
+        playlist.limit = if limit > 0 { Some(limit) } else { None };
+        playlist
+    }
+
+    fn add_rule(&self, rule: &Rule) {
+        let imp = self.imp();
+
+        let labels: Vec<String> = RuleField::ALL.iter().map(|f| f.label()).collect();
+        let labels: Vec<&str> = labels.iter().map(|s| s.as_str()).collect();
+        let field = gtk::DropDown::new(Some(gtk::StringList::new(&labels)), gtk::Expression::NONE);
+        field.set_valign(gtk::Align::Center);
+        field.update_property(&[gtk::accessible::Property::Label(&i18n("Field"))]);
+
+        let op = gtk::DropDown::new(gio::ListModel::NONE, gtk::Expression::NONE);
+        op.set_valign(gtk::Align::Center);
+        op.update_property(&[gtk::accessible::Property::Label(&i18n("Condition"))]);
+
+        let value = gtk::Entry::builder()
+            .hexpand(true)
+            .valign(gtk::Align::Center)
+            .activates_default(true)
+            .build();
+        value.update_property(&[gtk::accessible::Property::Label(&i18n("Value"))]);
+
+        let remove_button = gtk::Button::builder()
+            .icon_name("app-remove-symbolic")
+            .valign(gtk::Align::Center)
+            .tooltip_text(i18n("Remove Rule"))
+            .build();
+        remove_button.add_css_class("flat");
+
+        let hbox = gtk::Box::builder()
+            .spacing(6)
+            .margin_top(6)
+            .margin_bottom(6)
+            .margin_start(12)
+            .margin_end(6)
+            .build();
+        hbox.append(&field);
+        hbox.append(&op);
+        hbox.append(&value);
+        hbox.append(&remove_button);
+
+        let row = gtk::ListBoxRow::builder()
+            .activatable(false)
+            .child(&hbox)
+            .build();
+
+        let widgets = RuleWidgets {
+            row: row.clone(),
+            field: field.clone(),
+            op: op.clone(),
+            value: value.clone(),
+        };
+
+        let field_pos = RuleField::ALL
+            .iter()
+            .position(|f| *f == rule.field)
+            .unwrap_or(0);
+        field.set_selected(field_pos as u32);
+        widgets.update_operators();
+        let op_pos = RuleOperator::for_kind(rule.field.kind())
+            .iter()
+            .position(|o| *o == rule.op)
+            .unwrap_or(0);
+        op.set_selected(op_pos as u32);
+        value.set_text(&rule.value);
+
+        field.connect_selected_notify(clone!(@weak self as win, @weak row => move |_| {
+            if let Some(widgets) = win.imp().rules.borrow().iter().find(|r| r.row == row) {
+                widgets.update_operators();
+            }
+        }));
+        remove_button.connect_clicked(clone!(@weak self as win, @weak row => move |_| {
+            win.imp().rules.borrow_mut().retain(|r| r.row != row);
+            win.imp().rules_list.remove(&row);
+        }));
+
+        imp.rules_list.append(&row);
+        imp.rules.borrow_mut().push(widgets);
+    }
+
+    fn add_toast(&self, msg: &str) {
+        let toast = adw::Toast::new(msg);
+        self.imp().toast_overlay.add_toast(toast);
+    }
+
+    fn save(&self) {
+        let playlist = self.playlist();
+
+        if playlist.rules.is_empty() {
+            self.add_toast(&i18n("A smart playlist needs at least one rule"));
+            return;
+        }
+
+        if let Some(rule) = playlist.rules.iter().find(|r| !r.is_valid()) {
+            self.add_toast(&i18n_f(
+                // Translators: the `{}` must be left unmodified, and
+                // it will be expanded to the name of a field
+                "Invalid value for “{}”",
+                &[&rule.field.label()],
+            ));
+            return;
+        }
+
+        playlist.save();
+        self.emit_by_name::<()>("changed", &[]);
+        self.close();
+    }
+
+    fn delete(&self) {
+        if !self.imp().is_new.get() {
+            self.imp().playlist.borrow().delete();
+            self.emit_by_name::<()>("changed", &[]);
+        }
+        self.close();
+    }
+
+    fn export(&self) {
+        let playlist = self.playlist();
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let dialog = gtk::FileDialog::builder()
+                .accept_label(i18n("_Export"))
+                .modal(true)
+                .title(i18n("Export Smart Playlist"))
+                .initial_name(format!("{}.smart.json", playlist.name))
+                .build();
+
+            if let Ok(file) = dialog.save_future(Some(&win)).await {
+                let res = file.replace_contents(
+                    &playlist.to_json(),
+                    None,
+                    false,
+                    gio::FileCreateFlags::REPLACE_DESTINATION,
+                    gio::Cancellable::NONE,
+                );
+                if let Err(e) = res {
+                    warn!("Unable to export smart playlist: {}", e);
+                    win.add_toast(&i18n("Unable to export the playlist"));
+                }
+            }
+        }));
+    }
+
+    // Importing replaces the contents of the editor; the playlist is
+    // only stored once saved
+    fn import(&self) {
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let filter = gtk::FileFilter::new();
+            filter.add_pattern("*.json");
+            filter.set_name(Some(&i18n("Smart playlists")));
+
+            let filters = gio::ListStore::new::<gtk::FileFilter>();
+            filters.append(&filter);
+
+            let dialog = gtk::FileDialog::builder()
+                .accept_label(i18n("_Import"))
+                .modal(true)
+                .title(i18n("Import Smart Playlist"))
+                .filters(&filters)
+                .build();
+
+            if let Ok(file) = dialog.open_future(Some(&win)).await {
+                let res = file
+                    .load_contents(gio::Cancellable::NONE)
+                    .map_err(|e| e.to_string())
+                    .and_then(|(data, _)| SmartPlaylist::from_json(&data));
+                match res {
+                    Ok(playlist) => win.set_playlist(&playlist),
+                    Err(e) => {
+                        warn!("Unable to import smart playlist: {}", e);
+                        win.add_toast(&i18n("Unable to import the playlist"));
+                    }
+                }
+            }
+        }));
+    }
+}
@@ -3,11 +3,13 @@
 
 use gtk::{glib, prelude::*, subclass::prelude::*};
 
+use crate::query::Query;
+
 mod imp {
 
-    use std::cell::RefCell;
+    use std::{cell::RefCell, collections::HashMap};
 
-    use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+    use fuzzy_matcher::skim::SkimMatcherV2;
     use gtk::{
         glib::{self, ParamSpec, ParamSpecString, Value},
         prelude::*,
@@ -15,11 +17,31 @@ mod imp {
     };
     use once_cell::sync::Lazy;
 
-    use crate::{audio::Song, utils::cmp_two_files};
+    use crate::{audio::Song, query::Query, utils::cmp_two_files};
 
     #[derive(Default)]
     pub struct FuzzySorter {
         pub search: RefCell<Option<String>>,
+        pub query: RefCell<Query>,
+        pub matcher: SkimMatcherV2,
+        // Sorting compares each song many times, so we only score it
+        // once per query
+        pub scores: RefCell<HashMap<Song, Option<i64>>>,
+    }
+
+    impl FuzzySorter {
+        fn score(&self, song: &Song) -> Option<i64> {
+            if let Some(score) = self.scores.borrow().get(song) {
+                return *score;
+            }
+
+            let score = self
+                .query
+                .borrow()
+                .score(&song.search_fields(), &self.matcher);
+            self.scores.borrow_mut().insert(song.clone(), score);
+            score
+        }
     }
 
     #[glib::object_subclass]
@@ -54,12 +76,15 @@ mod imp {
             let item1 = item1.downcast_ref::<Song>().unwrap();
             let item2 = item2.downcast_ref::<Song>().unwrap();
 
-            if let Some(search) = self.search.borrow().as_ref() {
-                let matcher = SkimMatcherV2::default();
-                let item1_key = item1.search_key();
-                let item2_key = item2.search_key();
-                let item1_score = matcher.fuzzy_match(&item1_key, search);
-                let item2_score = matcher.fuzzy_match(&item2_key, search);
+            if self.search.borrow().is_some() {
+                // Only bare terms have a score; songs matching a query
+                // without them keep their order
+                if self.query.borrow().fuzzy_pattern().is_none() {
+                    return gtk::Ordering::Equal;
+                }
+
+                let item1_score = self.score(item1);
+                let item2_score = self.score(item2);
                 item1_score.cmp(&item2_score).reverse().into()
             } else {
                 cmp_two_files(None, &item1.file(), &item2.file()).into()
@@ -67,7 +92,11 @@ mod imp {
         }
 
         fn order(&self) -> gtk::SorterOrder {
-            gtk::SorterOrder::Partial
+            if self.search.borrow().is_some() && self.query.borrow().fuzzy_pattern().is_none() {
+                gtk::SorterOrder::None
+            } else {
+                gtk::SorterOrder::Partial
+            }
         }
     }
 }
@@ -95,8 +124,21 @@ impl FuzzySorter {
 
     pub fn set_search(&self, search: Option<String>) {
         if *self.imp().search.borrow() != search {
-            *self.imp().search.borrow_mut() = search;
-            self.changed(gtk::SorterChange::Different);
+            let imp = self.imp();
+            let query = Query::parse(search.as_deref().unwrap_or_default());
+
+            // Only the fuzzy pattern affects the order of the songs
+            let unchanged = imp.search.borrow().is_some()
+                && search.is_some()
+                && imp.query.borrow().fuzzy_pattern() == query.fuzzy_pattern();
+
+            *imp.query.borrow_mut() = query;
+            *imp.search.borrow_mut() = search;
+
+            if !unchanged {
+                imp.scores.borrow_mut().clear();
+                self.changed(gtk::SorterChange::Different);
+            }
         }
     }
 }
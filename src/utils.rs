@@ -4,13 +4,12 @@
 use core::cmp::Ordering;
 use std::path::PathBuf;
 
-use color_thief::{get_palette, ColorFormat};
 use glib::clone;
-use gtk::{gdk, gio, glib, prelude::*};
+use gtk::{gio, glib, prelude::*};
 use log::{debug, warn};
 
 use crate::{
-    audio::{Queue, Song},
+    audio::{CoverCache, Queue, Song},
     config::APPLICATION_ID,
 };
 
@@ -31,62 +30,312 @@ pub fn format_time(t: i64) -> String {
     format!("{}:{:02}", (t - (t % 60)) / 60, t % 60)
 }
 
-// The base cover size is 192px, but we need to account for HiDPI;
-// better to scale down when rendering on displays with a scaling
-// factor of 1 than having to scale up on displays with a scaling
-// factor of 2.
-const COVER_SIZE: i32 = 192 * 2;
+// Bump this whenever the format of anything stored in the cache changes
+// in an incompatible way; the previous cache will be left behind
+const CACHE_VERSION: &str = "v1";
+
+// All the data we cache lives under a single, versioned directory:
+//
+// $XDG_CACHE_HOME/amberol/<version>
+// ├── covers/
+// ├── metadata/
+// ├── playlists/
+// ╰── waveforms/
+fn cache_root() -> PathBuf {
+    let mut cache_dir = glib::user_cache_dir();
+    cache_dir.push("amberol");
+    cache_dir.push(CACHE_VERSION);
+    cache_dir
+}
 
-pub fn load_cover_texture(buffer: &glib::Bytes) -> Option<gdk_pixbuf::Pixbuf> {
-    let stream = gio::MemoryInputStream::from_bytes(buffer);
+pub fn cache_dir(name: &str) -> PathBuf {
+    let mut cache_dir = cache_root();
+    cache_dir.push(name);
+    glib::mkdir_with_parents(&cache_dir, 0o755);
+    cache_dir
+}
 
-    if let Ok(pixbuf) = gdk_pixbuf::Pixbuf::from_stream(&stream, gio::Cancellable::NONE) {
-        let width = pixbuf.width();
-        let height = pixbuf.height();
-        let ratio = width as f32 / height as f32;
+// The data that cannot be recreated, like the play statistics and the
+// smart playlists, lives outside of the cache:
+//
+// $XDG_DATA_HOME/amberol
+// ├── smart-playlists/
+// ╰── stats/
+pub fn data_dir(name: &str) -> PathBuf {
+    let mut data_dir = glib::user_data_dir();
+    data_dir.push("amberol");
+    data_dir.push(name);
+    glib::mkdir_with_parents(&data_dir, 0o755);
+    data_dir
+}
 
-        let w: i32;
-        let h: i32;
+// Older versions stored each cache directly under the amberol cache
+// directory; we move them into the versioned layout, since their
+// contents are still valid
+pub fn migrate_cache() {
+    let mut legacy_root = glib::user_cache_dir();
+    legacy_root.push("amberol");
+
+    let new_root = cache_root();
+    glib::mkdir_with_parents(&new_root, 0o755);
+
+    for name in ["covers", "playlists", "waveforms"] {
+        let legacy = gio::File::for_path(legacy_root.join(name));
+        let new = gio::File::for_path(new_root.join(name));
+        if !legacy.query_exists(gio::Cancellable::NONE) || new.query_exists(gio::Cancellable::NONE)
+        {
+            continue;
+        }
 
-        if ratio > 1.0 {
-            w = COVER_SIZE;
-            h = (COVER_SIZE as f32 / ratio) as i32;
-        } else {
-            w = (COVER_SIZE as f32 * ratio) as i32;
-            h = COVER_SIZE;
+        match legacy.move_(
+            &new,
+            gio::FileCopyFlags::NOFOLLOW_SYMLINKS,
+            gio::Cancellable::NONE,
+            None,
+        ) {
+            Ok(_) => debug!("Migrated cache directory '{}'", name),
+            Err(e) => warn!("Unable to migrate cache directory '{}': {}", name, e),
+        }
+    }
+
+    migrate_user_data();
+}
+
+// Older versions stored the play statistics and the smart playlists in
+// the cache, where they could be removed at any time
+fn migrate_user_data() {
+    let cache_root = cache_root();
+
+    let mut moves = vec![(
+        cache_root.join("stats").join("songs.json"),
+        data_dir("stats").join("songs.json"),
+    )];
+    if let Ok(entries) = std::fs::read_dir(cache_root.join("playlists")) {
+        let smart_playlists = data_dir("smart-playlists");
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if name.to_string_lossy().ends_with(".smart.json") {
+                moves.push((entry.path(), smart_playlists.join(name)));
+            }
+        }
+    }
+
+    for (legacy, new) in moves {
+        if !legacy.exists() || new.exists() {
+            continue;
         }
 
-        debug!("Cover size {width} x {height} (ratio: {ratio}), scaled: {w} x {h}");
+        match std::fs::rename(&legacy, &new).or_else(|_| {
+            // The data directory may be on a different file system
+            std::fs::copy(&legacy, &new).and_then(|_| std::fs::remove_file(&legacy))
+        }) {
+            Ok(_) => debug!("Migrated {:?} to {:?}", &legacy, &new),
+            Err(e) => warn!("Unable to migrate {:?}: {}", &legacy, e),
+        }
+    }
+}
+
+// Covers and waveforms are cheap to regenerate, so we keep their caches
+// under a size limit, dropping the least recently used files first; the
+// current playlist is never dropped
+const TRIMMED_CACHES: [(&str, u64); 2] = [
+    ("covers", 128 * 1024 * 1024),
+    ("waveforms", 32 * 1024 * 1024),
+];
+
+// Files we have not used in this many days are dropped regardless of the
+// size of the cache
+const MAX_CACHE_AGE_DAYS: u64 = 90;
+
+struct CacheEntry {
+    file: gio::File,
+    size: u64,
+    last_used: u64,
+}
 
-        pixbuf.scale_simple(w, h, gdk_pixbuf::InterpType::Nearest)
+// The covers that are still in use are never removed, as the MPRIS
+// controller may point to them
+fn cache_entries(name: &str) -> Vec<CacheEntry> {
+    let in_use = if name == "covers" {
+        CoverCache::global().lock().unwrap().cache_files()
     } else {
-        warn!("Unable to load cover art");
-        None
+        vec![]
+    };
+
+    let dir = gio::File::for_path(cache_dir(name));
+    let enumerator = match dir.enumerate_children(
+        "standard::name,standard::type,standard::size,time::access,time::modified",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        gio::Cancellable::NONE,
+    ) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("Unable to enumerate cache directory '{}': {}", name, e);
+            return vec![];
+        }
+    };
+
+    enumerator
+        .filter_map(|info| info.ok())
+        .filter(|info| info.file_type() == gio::FileType::Regular)
+        .filter(|info| {
+            !in_use
+                .iter()
+                .any(|p| p.file_name() == Some(info.name().as_os_str()))
+        })
+        .map(|info| {
+            // The access time is not updated on every read, but it's
+            // close enough for our purposes
+            let modified = info.attribute_uint64("time::modified");
+            let accessed = info.attribute_uint64("time::access");
+            CacheEntry {
+                file: dir.child(info.name()),
+                size: info.size() as u64,
+                last_used: modified.max(accessed),
+            }
+        })
+        .collect()
+}
+
+fn remove_cache_entry(entry: &CacheEntry) -> u64 {
+    match entry.file.delete(gio::Cancellable::NONE) {
+        Ok(_) => entry.size,
+        Err(e) => {
+            warn!("Unable to remove cache file '{}': {}", entry.file.uri(), e);
+            0
+        }
     }
 }
 
-pub fn cache_cover_art(uuid: &str, pixbuf: &gdk_pixbuf::Pixbuf) -> Option<PathBuf> {
-    let mut cache_dir = glib::user_cache_dir();
-    cache_dir.push("amberol");
-    cache_dir.push("covers");
-    glib::mkdir_with_parents(&cache_dir, 0o755);
+// Drops old files, and then the least recently used ones until each
+// cache fits its size limit; this blocks, so it should be called off the
+// main thread. Returns the number of bytes freed
+pub fn trim_cache() -> u64 {
+    let now = glib::real_time() as u64 / 1_000_000;
+    let max_age = MAX_CACHE_AGE_DAYS * 24 * 60 * 60;
+
+    let mut freed = 0;
+    for (name, max_size) in TRIMMED_CACHES {
+        let mut entries = cache_entries(name);
+        entries.sort_by_key(|e| e.last_used);
+
+        let mut size: u64 = entries.iter().map(|e| e.size).sum();
+        for entry in entries {
+            if size <= max_size && now.saturating_sub(entry.last_used) < max_age {
+                break;
+            }
+
+            let removed = remove_cache_entry(&entry);
+            size -= removed;
+            freed += removed;
+        }
+    }
+
+    debug!("Trimmed caches: {} bytes freed", freed);
+
+    freed
+}
+
+// Drops every file in the caches that can be regenerated; returns the
+// number of bytes freed
+pub fn clear_cache() -> u64 {
+    let freed = TRIMMED_CACHES
+        .iter()
+        .flat_map(|(name, _)| cache_entries(name))
+        .map(|e| remove_cache_entry(&e))
+        .sum();
+
+    debug!("Cleared caches: {} bytes freed", freed);
+
+    freed
+}
+
+// The sizes at which we draw the cover art: the thumbnails in the
+// playlist, the cover of the current song, and the cover of the current
+// song in large windows. We account for HiDPI, as it's better to scale
+// down when rendering on displays with a scaling factor of 1 than having
+// to scale up on displays with a scaling factor of 2. Only the smallest
+// size is kept in memory; the others are decoded from the cached cover
+// when needed
+const COVER_SIZES: [i32; 3] = [48 * 2, 192 * 2, 384 * 2];
+
+// The size of the cover we use to compute the palette; larger images do
+// not give us better colors, but they take longer to process
+const PALETTE_SIZE: i32 = 192 * 2;
+
+// The smallest cover size that covers the given size, in pixels; the
+// largest one if none does
+pub fn cover_size(size: i32) -> i32 {
+    COVER_SIZES
+        .into_iter()
+        .find(|s| *s >= size)
+        .unwrap_or(COVER_SIZES[COVER_SIZES.len() - 1])
+}
+
+pub fn small_cover_size() -> i32 {
+    COVER_SIZES[0]
+}
+
+pub fn large_cover_size() -> i32 {
+    COVER_SIZES[COVER_SIZES.len() - 1]
+}
+
+// Scales the pixel buffer down to the given size, preserving its aspect
+// ratio; the original is kept if it would require scaling it up
+pub fn scale_cover_pixbuf(pixbuf: &gdk_pixbuf::Pixbuf, size: i32) -> gdk_pixbuf::Pixbuf {
+    let width = pixbuf.width();
+    let height = pixbuf.height();
+    if width.max(height) <= size {
+        return pixbuf.clone();
+    }
+
+    let ratio = width as f32 / height as f32;
+    let (w, h) = if ratio > 1.0 {
+        (size, ((size as f32 / ratio) as i32).max(1))
+    } else {
+        (((size as f32 * ratio) as i32).max(1), size)
+    };
+
+    debug!("Cover size {width} x {height} (ratio: {ratio}), scaled: {w} x {h}");
+
+    pixbuf
+        .scale_simple(w, h, gdk_pixbuf::InterpType::Bilinear)
+        .unwrap_or_else(|| pixbuf.clone())
+}
+
+pub fn load_cover_pixbuf(buffer: &glib::Bytes) -> Option<gdk_pixbuf::Pixbuf> {
+    let stream = gio::MemoryInputStream::from_bytes(buffer);
+
+    match gdk_pixbuf::Pixbuf::from_stream(&stream, gio::Cancellable::NONE) {
+        Ok(pixbuf) => Some(pixbuf),
+        Err(_) => {
+            warn!("Unable to load cover art");
+            None
+        }
+    }
+}
+
+pub fn palette_pixbuf(pixbuf: &gdk_pixbuf::Pixbuf) -> gdk_pixbuf::Pixbuf {
+    scale_cover_pixbuf(pixbuf, PALETTE_SIZE)
+}
 
+pub fn cache_cover_art(uuid: &str, pixbuf: &gdk_pixbuf::Pixbuf) -> Option<PathBuf> {
+    let mut cache_dir = cache_dir("covers");
     cache_dir.push(format!("{}.png", &uuid));
     let file = gio::File::for_path(&cache_dir);
     match file.create(gio::FileCreateFlags::NONE, gio::Cancellable::NONE) {
         Ok(stream) => {
+            // Covers are loaded by the song loader threads, so we can
+            // block until the data has been written
             debug!("Creating cover data cache at {:?}", &cache_dir);
-            pixbuf.save_to_streamv_async(
+            if let Err(e) = pixbuf.save_to_streamv(
                 &stream,
                 "png",
                 &[("tEXt::Software", "amberol")],
                 gio::Cancellable::NONE,
-                move |res| {
-                    if let Err(e) = res {
-                        warn!("Unable to cache cover data: {}", e);
-                    }
-                },
-            );
+            ) {
+                warn!("Unable to cache cover data: {}", e);
+            }
         }
         Err(e) => {
             if let Some(file_error) = e.kind::<glib::FileError>() {
@@ -104,73 +353,6 @@ pub fn cache_cover_art(uuid: &str, pixbuf: &gdk_pixbuf::Pixbuf) -> Option<PathBu
     Some(cache_dir)
 }
 
-fn color_format(has_alpha: bool) -> ColorFormat {
-    if has_alpha {
-        ColorFormat::Rgba
-    } else {
-        ColorFormat::Rgb
-    }
-}
-
-pub fn load_palette(pixbuf: &gdk_pixbuf::Pixbuf) -> Option<Vec<gdk::RGBA>> {
-    if let Ok(palette) = get_palette(
-        pixbuf.pixel_bytes().unwrap().as_ref(),
-        color_format(pixbuf.has_alpha()),
-        5,
-        4,
-    ) {
-        let colors: Vec<gdk::RGBA> = palette
-            .iter()
-            .map(|c| {
-                gdk::RGBA::new(
-                    c.r as f32 / 255.0,
-                    c.g as f32 / 255.0,
-                    c.b as f32 / 255.0,
-                    1.0,
-                )
-            })
-            .collect();
-
-        return Some(colors);
-    }
-
-    None
-}
-
-fn load_files_from_folder_internal(
-    base: &gio::File,
-    folder: &gio::File,
-    recursive: bool,
-) -> Vec<gio::File> {
-    let mut enumerator = folder
-        .enumerate_children(
-            "standard::name,standard::type",
-            gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
-            None::<&gio::Cancellable>,
-        )
-        .expect("Unable to enumerate");
-
-    let mut files = Vec::new();
-    while let Some(info) = enumerator.next().and_then(|s| s.ok()) {
-        let child = enumerator.child(&info);
-        if recursive && info.file_type() == gio::FileType::Directory {
-            let mut res = load_files_from_folder_internal(base, &child, recursive);
-            files.append(&mut res);
-        } else if info.file_type() == gio::FileType::Regular {
-            files.push(child.clone());
-        }
-    }
-
-    // gio::FileEnumerator has no guaranteed order, so we should
-    // rely on the basename being formatted in a way that gives us an
-    // implicit order; if anything, this will queue songs in the same
-    // order in which they appear in the directory when browsing its
-    // contents
-    files.sort_by(|a, b| cmp_two_files(Some(base), a, b));
-
-    files
-}
-
 pub fn cmp_two_files(base: Option<&gio::File>, a: &gio::File, b: &gio::File) -> Ordering {
     let parent_a = a.parent().unwrap();
     let parent_b = b.parent().unwrap();
@@ -226,19 +408,138 @@ fn cmp_like_nautilus(filename_a: &str, filename_b: &str) -> Ordering {
     order
 }
 
-pub fn load_files_from_folder(folder: &gio::File, recursive: bool) -> Vec<gio::File> {
-    use std::time::Instant;
+// Internet radio streams are the only remote locations that we play
+// directly, instead of going through GVFS
+pub fn is_stream_uri(uri: &str) -> bool {
+    matches!(
+        glib::Uri::peek_scheme(uri).as_deref(),
+        Some("http") | Some("https")
+    )
+}
 
-    let now = Instant::now();
-    let res = load_files_from_folder_internal(folder, folder, recursive);
-    debug!(
-        "Folder enumeration: {} us (recursive: {}), total files: {}",
-        now.elapsed().as_micros(),
-        recursive,
-        res.len()
-    );
+// Songs can go missing, or become unreadable, after being added to
+// the playlist
+pub fn is_readable_uri(uri: &str) -> bool {
+    let file = gio::File::for_uri(uri);
+    match file.query_info(
+        "standard::type,access::can-read",
+        gio::FileQueryInfoFlags::NONE,
+        gio::Cancellable::NONE,
+    ) {
+        Ok(info) => {
+            info.file_type() == gio::FileType::Regular
+                && (!info.has_attribute("access::can-read") || info.boolean("access::can-read"))
+        }
+        Err(_) => false,
+    }
+}
+
+pub fn is_radio_playlist(file: &gio::File, content_type: Option<&str>) -> bool {
+    const PLAYLIST_TYPES: [&str; 5] = [
+        "audio/x-scpls",
+        "audio/x-mpegurl",
+        "audio/mpegurl",
+        "application/x-mpegurl",
+        "application/vnd.apple.mpegurl",
+    ];
+
+    if let Some(content_type) = content_type {
+        return PLAYLIST_TYPES
+            .iter()
+            .any(|t| gio::content_type_is_mime_type(content_type, t));
+    }
+
+    // Remote playlists have no content type until we download them
+    match file.basename() {
+        Some(name) => {
+            let name = name.to_string_lossy().to_lowercase();
+            name.ends_with(".pls") || name.ends_with(".m3u") || name.ends_with(".m3u8")
+        }
+        None => false,
+    }
+}
+
+fn parse_pls(contents: &str) -> Vec<String> {
+    let pls = glib::KeyFile::new();
+    if let Err(e) = pls.load_from_data(contents, glib::KeyFileFlags::NONE) {
+        warn!("Unable to parse playlist: {e}");
+        return vec![];
+    }
 
-    res
+    // The group name is "playlist", but some stations use "Playlist"
+    let group = if pls.has_group("playlist") {
+        "playlist"
+    } else {
+        "Playlist"
+    };
+
+    // NumberOfEntries is often wrong, and the entries are not always in
+    // order, so we sort them by their index
+    let mut entries: Vec<(u32, String)> = match pls.keys(group) {
+        Ok(keys) => keys
+            .iter()
+            .filter_map(|k| {
+                let index = k.as_str().strip_prefix("File")?.parse::<u32>().ok()?;
+                let value = pls.value(group, k.as_str()).ok()?;
+                Some((index, value.to_string()))
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    entries.sort_by_key(|(index, _)| *index);
+    entries.into_iter().map(|(_, value)| value).collect()
+}
+
+fn parse_m3u(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect()
+}
+
+fn parse_radio_playlist(contents: &[u8]) -> Vec<String> {
+    let contents = String::from_utf8_lossy(contents);
+    // Playlists saved on Windows often start with a byte order mark
+    let contents = contents.trim_start_matches('\u{feff}').trim_start();
+
+    if contents.starts_with("[playlist]") || contents.starts_with("[Playlist]") {
+        parse_pls(contents)
+    } else {
+        parse_m3u(contents)
+    }
+}
+
+// Entries are either URIs or paths relative to the playlist
+fn resolve_playlist_entries(file: &gio::File, entries: &[String]) -> Vec<gio::File> {
+    let parent = file.parent();
+    entries
+        .iter()
+        .filter_map(|e| {
+            if glib::Uri::peek_scheme(e).is_some() {
+                Some(gio::File::for_uri(e))
+            } else {
+                parent.as_ref().map(|p| p.resolve_relative_path(e))
+            }
+        })
+        .collect()
+}
+
+// Remote playlists are downloaded, so this blocks for as long as the
+// server takes to answer; it must be called off the main thread
+pub fn load_radio_playlist(file: &gio::File, cancellable: &gio::Cancellable) -> Vec<gio::File> {
+    let contents = match file.load_contents(Some(cancellable)) {
+        Ok((contents, _)) => contents,
+        Err(e) => {
+            if !e.matches(gio::IOErrorEnum::Cancelled) {
+                warn!("Unable to load playlist {}: {e}", file.uri());
+            }
+            return vec![];
+        }
+    };
+
+    resolve_playlist_entries(file, &parse_radio_playlist(&contents))
 }
 
 async fn store_current_pls(queue: &Queue) {
@@ -251,16 +552,12 @@ async fn store_current_pls(queue: &Queue) {
     for i in 0..model.n_items() {
         let item = model.item(i).unwrap();
         let song = item.downcast_ref::<Song>().unwrap();
-        let path = song.file().path().expect("Unknown file");
-        let path_str = path.to_string_lossy();
-        pls.set_value("playlist", &format!("File{i}"), &path_str);
+        // We store URIs, so that songs that do not have a local path,
+        // like the ones on GVFS mounts, can be restored as well
+        pls.set_value("playlist", &format!("File{i}"), &song.uri());
     }
 
-    let mut pls_cache = glib::user_cache_dir();
-    pls_cache.push("amberol");
-    pls_cache.push("playlists");
-    glib::mkdir_with_parents(&pls_cache, 0o755);
-
+    let mut pls_cache = cache_dir("playlists");
     pls_cache.push("current.pls");
     match pls.save_to_file(&pls_cache) {
         Ok(_) => debug!("Current playlist updated to: {:?}", &pls_cache),
@@ -276,9 +573,7 @@ pub fn store_playlist(queue: &Queue) {
 }
 
 pub fn load_cached_songs() -> Option<Vec<gio::File>> {
-    let mut pls_cache = glib::user_cache_dir();
-    pls_cache.push("amberol");
-    pls_cache.push("playlists");
+    let mut pls_cache = cache_dir("playlists");
     pls_cache.push("current.pls");
 
     let pls = glib::KeyFile::new();
@@ -296,7 +591,9 @@ pub fn load_cached_songs() -> Option<Vec<gio::File>> {
 
     for i in 0..n_entries {
         match pls.value("playlist", &format!("File{i}")) {
-            Ok(p) => res.push(gio::File::for_path(p)),
+            // Older playlists stored paths instead of URIs, and
+            // for_commandline_arg() handles both
+            Ok(p) => res.push(gio::File::for_commandline_arg(p)),
             Err(e) => debug!("Skipping File{i} from playlist: {e}"),
         }
     }
@@ -305,10 +602,90 @@ pub fn load_cached_songs() -> Option<Vec<gio::File>> {
 }
 
 pub fn has_cached_playlist() -> bool {
-    let mut pls_cache = glib::user_cache_dir();
-    pls_cache.push("amberol");
-    pls_cache.push("playlists");
+    let mut pls_cache = cache_dir("playlists");
     pls_cache.push("current.pls");
 
     pls_cache.exists()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pls() {
+        let contents = "[playlist]\n\
+            NumberOfEntries=2\n\
+            File2=http://example.com/second\n\
+            Title2=Second\n\
+            File1=http://example.com/first\n\
+            File3=http://example.com/third\n\
+            Version=2\n";
+        assert_eq!(
+            parse_radio_playlist(contents.as_bytes()),
+            vec![
+                "http://example.com/first",
+                "http://example.com/second",
+                "http://example.com/third"
+            ]
+        );
+
+        let contents = "[Playlist]\nFile1=stream.mp3\n";
+        assert_eq!(
+            parse_radio_playlist(contents.as_bytes()),
+            vec!["stream.mp3"]
+        );
+    }
+
+    #[test]
+    fn m3u() {
+        let contents = "#EXTM3U\n\
+            #EXTINF:-1,Some Radio\n\
+            http://example.com/stream\n\
+            \n  \n\
+            # A comment\n\
+            music/song.ogg  \r\n";
+        assert_eq!(
+            parse_radio_playlist(contents.as_bytes()),
+            vec!["http://example.com/stream", "music/song.ogg"]
+        );
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        let pls = "\u{feff}[playlist]\nFile1=http://example.com/stream\n";
+        assert_eq!(
+            parse_radio_playlist(pls.as_bytes()),
+            vec!["http://example.com/stream"]
+        );
+
+        let m3u = "\u{feff}http://example.com/stream\n";
+        assert_eq!(
+            parse_radio_playlist(m3u.as_bytes()),
+            vec!["http://example.com/stream"]
+        );
+    }
+
+    #[test]
+    fn relative_entries() {
+        let file = gio::File::for_path("/music/radio/stations.m3u");
+        let entries = vec![
+            "http://example.com/stream".to_string(),
+            "local/song.ogg".to_string(),
+            "/absolute/song.ogg".to_string(),
+        ];
+
+        let res: Vec<String> = resolve_playlist_entries(&file, &entries)
+            .iter()
+            .map(|f| f.uri().to_string())
+            .collect();
+        assert_eq!(
+            res,
+            vec![
+                "http://example.com/stream",
+                "file:///music/radio/local/song.ogg",
+                "file:///absolute/song.ogg"
+            ]
+        );
+    }
+}
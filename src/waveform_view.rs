@@ -15,9 +15,51 @@ use std::{
 
 use adw::subclass::prelude::*;
 use glib::clone;
-use gtk::{gdk, glib, graphene, prelude::*};
+use gtk::{gdk, gio, glib, graphene, prelude::*};
 use log::{debug, warn};
 
+use crate::{i18n::i18n, utils};
+
+// The most we can zoom in, regardless of the number of peaks
+const MAX_ZOOM: f64 = 64.0;
+// How much a single step of the scroll wheel zooms
+const ZOOM_STEP: f64 = 1.25;
+// The lowest resolution we keep for the waveform
+const MIN_LEVEL_PEAKS: usize = 64;
+
+#[derive(Clone, Copy, Debug, glib::Enum, PartialEq, Default)]
+#[enum_type(name = "AmberolWaveformMode")]
+pub enum WaveformMode {
+    #[default]
+    #[enum_value(name = "peaks")]
+    Peaks,
+    #[enum_value(name = "rms")]
+    Rms,
+    #[enum_value(name = "spectrum")]
+    Spectrum,
+}
+
+impl From<i32> for WaveformMode {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::Peaks,
+            1 => Self::Rms,
+            2 => Self::Spectrum,
+            _ => panic!("invalid WaveformMode enum key"),
+        }
+    }
+}
+
+impl From<WaveformMode> for i32 {
+    fn from(value: WaveformMode) -> Self {
+        match value {
+            WaveformMode::Peaks => 0,
+            WaveformMode::Rms => 1,
+            WaveformMode::Spectrum => 2,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct PeakPair {
     pub left: f64,
@@ -37,6 +79,110 @@ impl DivAssign<f64> for PeakPair {
     }
 }
 
+// How the pairs of levels are combined into the next, coarser one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LevelReduction {
+    // The loudest of the two, so that the transients are not lost
+    Max,
+    // The average of the two, which is fine for the loudness
+    Mean,
+}
+
+impl LevelReduction {
+    fn reduce(&self, a: &PeakPair, b: &PeakPair) -> PeakPair {
+        match self {
+            LevelReduction::Max => PeakPair::new(a.left.max(b.left), a.right.max(b.right)),
+            LevelReduction::Mean => {
+                PeakPair::new((a.left + b.left) / 2.0, (a.right + b.right) / 2.0)
+            }
+        }
+    }
+}
+
+// The peaks of a song at decreasing resolutions, each level combining
+// pairs of peaks of the previous one; this way, drawing a long song
+// does not require going through all of its peaks
+#[derive(Debug)]
+pub struct PeakLevels {
+    levels: Vec<Vec<PeakPair>>,
+}
+
+impl PeakLevels {
+    pub fn new(peaks: Vec<PeakPair>, reduction: LevelReduction) -> Self {
+        let mut levels = vec![peaks];
+        while let Some(last) = levels.last().filter(|l| l.len() > MIN_LEVEL_PEAKS) {
+            let next = last
+                .chunks(2)
+                .map(|c| match c {
+                    [a, b] => reduction.reduce(a, b),
+                    _ => PeakPair::new(c[0].left, c[0].right),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        Self { levels }
+    }
+
+    fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    // The peaks between the start and end fractions of the song, at the
+    // lowest resolution that still has at least one peak for each bar
+    pub fn range(&self, start: f64, end: f64, n_bars: usize) -> &[PeakPair] {
+        let visible = (end - start) * self.len() as f64;
+        let level = (visible / n_bars.max(1) as f64).log2().floor().max(0.0) as usize;
+        let peaks = &self.levels[level.min(self.levels.len() - 1)];
+
+        let n_peaks = peaks.len();
+        let first = ((start * n_peaks as f64).floor() as usize).min(n_peaks);
+        let last = ((end * n_peaks as f64).ceil() as usize).clamp(first, n_peaks);
+        &peaks[first..last]
+    }
+}
+
+// The peaks and the loudness of a song
+#[derive(Debug)]
+pub struct WaveformLevels {
+    peaks: PeakLevels,
+    rms: PeakLevels,
+}
+
+impl WaveformLevels {
+    fn len(&self) -> usize {
+        self.peaks.len()
+    }
+
+    fn get(&self, mode: WaveformMode) -> &PeakLevels {
+        match mode {
+            WaveformMode::Rms => &self.rms,
+            _ => &self.peaks,
+        }
+    }
+}
+
+// Maps the bands of the spectrum to the given number of bars; the low
+// frequencies get more bars, to match how we hear them
+fn spectrum_bars(bands: &[f32], n_bars: usize) -> Vec<f32> {
+    let n_bands = bands.len();
+    if n_bands == 0 {
+        return vec![];
+    }
+
+    (0..n_bars)
+        .map(|i| {
+            let band_at = |bar: usize| {
+                let fraction = bar as f32 / n_bars as f32;
+                ((fraction * fraction * n_bands as f32) as usize).min(n_bands - 1)
+            };
+            let first = band_at(i);
+            let last = band_at(i + 1).max(first + 1).min(n_bands);
+            bands[first..last].iter().copied().fold(0.0, f32::max)
+        })
+        .collect()
+}
+
 mod imp {
     use glib::{subclass::Signal, ParamSpec, ParamSpecDouble, Value};
     use once_cell::sync::Lazy;
@@ -47,12 +193,29 @@ mod imp {
     pub struct WaveformView {
         pub position: Cell<f64>,
         pub hover_position: Cell<Option<f64>>,
-        // left and right channel peaks, normalised between 0 and 1
-        pub peaks: RefCell<Option<Vec<PeakPair>>>,
-        pub next_peaks: RefCell<Option<Vec<PeakPair>>>,
+        // left and right channel levels, normalised between 0 and 1
+        pub peaks: RefCell<Option<WaveformLevels>>,
+        pub next_peaks: RefCell<Option<WaveformLevels>>,
+        pub mode: Cell<WaveformMode>,
+        // The magnitude of each frequency band, between 0 and 1
+        pub spectrum: RefCell<Vec<f32>>,
         pub tick_id: RefCell<Option<gtk::TickCallbackId>>,
         pub first_frame_time: Cell<Option<i64>>,
         pub factor: Cell<Option<f64>>,
+        pub seekable: Cell<bool>,
+        // The song duration, in seconds, for the time popover
+        pub duration: Cell<u64>,
+        // The visible part of the song: the zoom factor, and the
+        // position of the left edge
+        pub zoom: Cell<f64>,
+        pub offset: Cell<f64>,
+        pub zoom_start: Cell<f64>,
+        // Whether the view scrolls to keep the position visible
+        pub follow: Cell<bool>,
+        pub dragging: Cell<bool>,
+        pub time_popover: RefCell<Option<gtk::Popover>>,
+        pub time_label: RefCell<Option<gtk::Label>>,
+        pub context_menu: RefCell<Option<gtk::PopoverMenu>>,
     }
 
     #[glib::object_subclass]
@@ -107,9 +270,14 @@ mod imp {
         fn constructed(&self) {
             self.parent_constructed();
 
+            self.seekable.set(true);
+            self.zoom.set(1.0);
+            self.follow.set(true);
             self.obj().set_focusable(true);
 
             self.obj().setup_gesture();
+            self.obj().setup_time_popover();
+            self.obj().setup_context_menu();
 
             self.obj()
                 .upcast_ref::<gtk::Accessible>()
@@ -124,6 +292,14 @@ mod imp {
             if let Some(tick_id) = self.tick_id.replace(None) {
                 tick_id.remove();
             }
+
+            if let Some(popover) = self.time_popover.take() {
+                popover.unparent();
+            }
+
+            if let Some(menu) = self.context_menu.take() {
+                menu.unparent();
+            }
         }
     }
 
@@ -136,6 +312,10 @@ mod imp {
                 return true;
             }
 
+            if !self.seekable.get() {
+                return false;
+            }
+
             let pos = self.position.get();
 
             match direction {
@@ -147,6 +327,18 @@ mod imp {
             }
         }
 
+        fn size_allocate(&self, width: i32, height: i32, baseline: i32) {
+            self.parent_size_allocate(width, height, baseline);
+
+            if let Some(popover) = self.time_popover.borrow().as_ref() {
+                popover.present();
+            }
+
+            if let Some(menu) = self.context_menu.borrow().as_ref() {
+                menu.present();
+            }
+        }
+
         fn request_mode(&self) -> gtk::SizeRequestMode {
             gtk::SizeRequestMode::ConstantSize
         }
@@ -201,7 +393,85 @@ mod imp {
             let block_size = bar_size + space_size;
             let available_width = w;
 
-            if let Some(ref peaks) = *self.peaks.borrow() {
+            if self.mode.get() == WaveformMode::Spectrum {
+                // The spectrum is live, so we draw it for streams as well
+                let n_bars = ((w - space_size) / block_size).max(0) as usize;
+                let bars = spectrum_bars(&self.spectrum.borrow(), n_bars);
+                let position = self.position.get() * w as f64;
+                let hover = self.hover_position.get().map(|h| {
+                    let h = h * w as f64;
+                    if is_rtl {
+                        w as f64 - h
+                    } else {
+                        h
+                    }
+                });
+                // Same as the waveform: full color up to the position,
+                // dimmed up to the hover position
+                let (full, dimmed) = match hover {
+                    Some(h) if h < position => (h, position),
+                    Some(h) => (position, h),
+                    None => (position, position),
+                };
+                let hover_color = gdk::RGBA::new(
+                    color.red(),
+                    color.green(),
+                    color.blue(),
+                    color.alpha() * hover_opacity,
+                );
+
+                for i in 0..n_bars {
+                    let offset = space_size + i as i32 * block_size;
+                    let x = if is_rtl {
+                        (w - offset - bar_size) as f32
+                    } else {
+                        offset as f32
+                    };
+
+                    let magnitude = bars.get(i).copied().unwrap_or(0.0);
+                    let height = f32::clamp(magnitude * h as f32, 2.0, h as f32);
+                    let y = center_y - height / 2.0;
+
+                    // Only songs have a position to show
+                    let offset = offset as f64;
+                    let bar_color = if !self.seekable.get() || offset < full {
+                        &color
+                    } else if offset < dimmed {
+                        &hover_color
+                    } else {
+                        &empty_color
+                    };
+
+                    snapshot.append_color(
+                        bar_color,
+                        &graphene::Rect::new(x, y, bar_size as f32, height),
+                    );
+                }
+            } else if !self.seekable.get() {
+                // Non-seekable streams get a dimmed flat line
+                let mut offset = space_size;
+                while offset < w - space_size {
+                    let x = offset as f32;
+                    let y = center_y - 1.0;
+                    let width = bar_size as f32;
+                    let height: f32 = 2.0;
+                    snapshot.append_color(&empty_color, &graphene::Rect::new(x, y, width, height));
+
+                    offset += block_size;
+                }
+            } else if let Some(ref levels) = *self.peaks.borrow() {
+                // We only draw the visible part of the song
+                let zoom = self.zoom.get();
+                let start = self.offset.get();
+                let end = (start + 1.0 / zoom).min(1.0);
+                let peaks =
+                    levels
+                        .get(self.mode.get())
+                        .range(start, end, (w / block_size) as usize);
+                if peaks.is_empty() {
+                    return;
+                }
+
                 let n_peaks = peaks.len() as i32;
                 let waveform_width = w as f64;
 
@@ -220,11 +490,8 @@ mod imp {
                 // shown as a full cursor color; and the area between the hover
                 // position and the end of the waveform is meant to be shown as a
                 // current foreground color.
-                let position = if is_rtl {
-                    1.0 - self.position.get()
-                } else {
-                    self.position.get()
-                };
+                let position = (self.position.get() - start) * zoom;
+                let position = if is_rtl { 1.0 - position } else { position };
                 let mut cursor_pos: [f64; 2] =
                     [position * waveform_width, position * waveform_width];
                 if let Some(hover) = self.hover_position.get() {
@@ -390,45 +657,149 @@ impl WaveformView {
         drag_gesture.set_button(0);
         drag_gesture.connect_drag_begin(
             clone!(@strong self as this => move |gesture, start_x, _| {
+                // The secondary button opens the context menu
+                if !this.seekable() || gesture.current_button() == gdk::BUTTON_SECONDARY {
+                    gesture.set_state(gtk::EventSequenceState::Denied);
+                    return;
+                }
                 if !this.has_focus() {
                     this.grab_focus();
                 }
                 gesture.set_state(gtk::EventSequenceState::Claimed);
+                this.imp().dragging.set(true);
+                this.imp().follow.set(true);
                 this.seek_to_coord(start_x);
+                this.show_time(start_x);
             }),
         );
         drag_gesture.connect_drag_update(
             clone!(@strong self as this => move |gesture, offset_x, _| {
+                if !this.seekable() {
+                    return;
+                }
                 if !this.has_focus() {
                     this.grab_focus();
                 }
                 gesture.set_state(gtk::EventSequenceState::Claimed);
-                this.seek_to_coord(gesture.start_point().unwrap().0 + offset_x);
+                let x = gesture.start_point().unwrap().0 + offset_x;
+                this.seek_to_coord(x);
+                this.show_time(x);
             }),
         );
+        drag_gesture.connect_drag_end(clone!(@strong self as this => move |_, _, _| {
+            this.imp().dragging.set(false);
+            if this.imp().hover_position.get().is_none() {
+                this.hide_time();
+            }
+        }));
         self.add_controller(drag_gesture);
 
         let motion_gesture = gtk::EventControllerMotion::new();
         motion_gesture.set_name(Some("waveform-motion"));
         motion_gesture.connect_motion(clone!(@strong self as this => move |_, x, _| {
+            if !this.seekable() {
+                return;
+            }
             let width = this.width() as f64;
             let position = x / width;
             this.imp().hover_position.replace(Some(position));
+            this.show_time(x);
             this.queue_draw();
         }));
         motion_gesture.connect_leave(clone!(@strong self as this => move |_| {
             this.imp().hover_position.replace(None);
+            if !this.imp().dragging.get() {
+                this.hide_time();
+            }
             this.queue_draw();
         }));
         self.add_controller(motion_gesture);
 
+        // Scrolling vertically zooms around the pointer, and scrolling
+        // horizontally pans the view
+        let scroll_controller =
+            gtk::EventControllerScroll::new(gtk::EventControllerScrollFlags::BOTH_AXES);
+        scroll_controller.set_name(Some("waveform-scroll"));
+        scroll_controller.connect_scroll(
+            clone!(@weak self as this => @default-return glib::Propagation::Proceed, move |controller, dx, dy| {
+                if !this.seekable() || this.max_zoom() <= 1.0 {
+                    return glib::Propagation::Proceed;
+                }
+
+                let state = controller.current_event_state();
+                let (dx, dy) = if state.contains(gdk::ModifierType::SHIFT_MASK) {
+                    (dy, 0.0)
+                } else {
+                    (dx, dy)
+                };
+
+                // Touchpads scroll by pixels, instead of steps
+                let (dx, dy) = if controller.unit() == gdk::ScrollUnit::Surface {
+                    (dx * 4.0 / this.width().max(1) as f64, dy / 20.0)
+                } else {
+                    (dx * 0.1, dy)
+                };
+
+                if dy != 0.0 {
+                    let anchor = this.imp().hover_position.get().unwrap_or(0.5);
+                    this.set_zoom(this.imp().zoom.get() * ZOOM_STEP.powf(-dy), anchor);
+                }
+                if dx != 0.0 {
+                    this.pan(dx);
+                }
+
+                glib::Propagation::Stop
+            }),
+        );
+        self.add_controller(scroll_controller);
+
+        let zoom_gesture = gtk::GestureZoom::new();
+        zoom_gesture.set_name(Some("waveform-zoom"));
+        zoom_gesture.connect_begin(clone!(@strong self as this => move |gesture, _| {
+            if !this.seekable() {
+                gesture.set_state(gtk::EventSequenceState::Denied);
+                return;
+            }
+            gesture.set_state(gtk::EventSequenceState::Claimed);
+            this.imp().zoom_start.set(this.imp().zoom.get());
+        }));
+        zoom_gesture.connect_scale_changed(clone!(@strong self as this => move |gesture, scale| {
+            let anchor = gesture
+                .bounding_box_center()
+                .map(|(x, _)| x / this.width().max(1) as f64)
+                .unwrap_or(0.5);
+            this.set_zoom(this.imp().zoom_start.get() * scale, anchor);
+        }));
+        self.add_controller(zoom_gesture);
+
         let key_controller = gtk::EventControllerKey::new();
         key_controller.set_name(Some("waveform-key"));
         key_controller.connect_key_released(
-            clone!(@strong self as this => move |_, keyval, _, _| {
+            clone!(@strong self as this => move |_, keyval, _, state| {
+                let shift = state.contains(gdk::ModifierType::SHIFT_MASK);
+                if keyval == gdk::Key::Menu || (shift && keyval == gdk::Key::F10) {
+                    this.popup_context_menu(None);
+                    return;
+                }
+                if !this.seekable() {
+                    return;
+                }
+                let zoom = this.imp().zoom.get();
                 let delta = match keyval {
-                    gdk::Key::Left => -0.05,
-                    gdk::Key::Right => 0.05,
+                    gdk::Key::Left => -0.05 / zoom,
+                    gdk::Key::Right => 0.05 / zoom,
+                    gdk::Key::plus | gdk::Key::equal | gdk::Key::KP_Add => {
+                        this.set_zoom(zoom * 2.0, 0.5);
+                        return;
+                    }
+                    gdk::Key::minus | gdk::Key::KP_Subtract => {
+                        this.set_zoom(zoom / 2.0, 0.5);
+                        return;
+                    }
+                    gdk::Key::_0 | gdk::Key::KP_0 => {
+                        this.reset_zoom();
+                        return;
+                    }
                     _ => return,
                 };
 
@@ -439,12 +810,162 @@ impl WaveformView {
         self.add_controller(key_controller);
     }
 
+    fn setup_time_popover(&self) {
+        let label = gtk::Label::new(None);
+        label.add_css_class("numeric");
+
+        let popover = gtk::Popover::builder()
+            .child(&label)
+            .autohide(false)
+            .can_focus(false)
+            .can_target(false)
+            .has_arrow(false)
+            .position(gtk::PositionType::Top)
+            .build();
+        popover.set_parent(self);
+
+        self.imp().time_label.replace(Some(label));
+        self.imp().time_popover.replace(Some(popover));
+    }
+
+    // The context menu switches between the visualizations; the action
+    // is provided by the window, which stores the choice
+    fn setup_context_menu(&self) {
+        let model = gio::Menu::new();
+        model.append(Some(&i18n("Peaks")), Some("win.waveform-mode::peaks"));
+        model.append(Some(&i18n("Loudness")), Some("win.waveform-mode::rms"));
+        model.append(Some(&i18n("Spectrum")), Some("win.waveform-mode::spectrum"));
+
+        let menu = gtk::PopoverMenu::from_model(Some(&model));
+        menu.set_has_arrow(false);
+        menu.set_halign(gtk::Align::Start);
+        menu.set_parent(self);
+        self.imp().context_menu.replace(Some(menu));
+
+        let click_gesture = gtk::GestureClick::new();
+        click_gesture.set_name(Some("waveform-context-click"));
+        click_gesture.set_button(gdk::BUTTON_SECONDARY);
+        click_gesture.connect_pressed(clone!(@weak self as this => move |gesture, _, x, y| {
+            gesture.set_state(gtk::EventSequenceState::Claimed);
+            this.popup_context_menu(Some((x, y)));
+        }));
+        self.add_controller(click_gesture);
+
+        let long_press = gtk::GestureLongPress::new();
+        long_press.set_name(Some("waveform-long-press"));
+        long_press.set_touch_only(true);
+        long_press.connect_pressed(clone!(@weak self as this => move |gesture, x, y| {
+            gesture.set_state(gtk::EventSequenceState::Claimed);
+            this.popup_context_menu(Some((x, y)));
+        }));
+        self.add_controller(long_press);
+    }
+
+    // Opens the context menu at the given coordinates, or in the middle
+    // of the widget when using the keyboard
+    fn popup_context_menu(&self, point: Option<(f64, f64)>) {
+        let (x, y) = point.unwrap_or((self.width() as f64 / 2.0, self.height() as f64 / 2.0));
+        if let Some(menu) = self.imp().context_menu.borrow().as_ref() {
+            self.hide_time();
+            menu.set_pointing_to(Some(&gdk::Rectangle::new(x as i32, y as i32, 1, 1)));
+            menu.popup();
+        }
+    }
+
+    // Shows the time under the pointer while hovering or scrubbing
+    fn show_time(&self, x: f64) {
+        let duration = self.imp().duration.get();
+        if duration == 0 {
+            return;
+        }
+
+        let time = (self.position_at(x) * duration as f64).round() as i64;
+        if let Some(label) = self.imp().time_label.borrow().as_ref() {
+            label.set_text(&utils::format_time(time));
+        }
+
+        if let Some(popover) = self.imp().time_popover.borrow().as_ref() {
+            popover.set_pointing_to(Some(&gdk::Rectangle::new(x as i32, 0, 1, 1)));
+            if !popover.is_visible() {
+                popover.popup();
+            }
+        }
+    }
+
+    fn hide_time(&self) {
+        if let Some(popover) = self.imp().time_popover.borrow().as_ref() {
+            popover.popdown();
+        }
+    }
+
+    // The position in the song, between 0 and 1, at the given coordinate
+    fn position_at(&self, x: f64) -> f64 {
+        let fraction = x / self.width().max(1) as f64;
+        let fraction = match self.direction() {
+            gtk::TextDirection::Rtl => 1.0 - fraction,
+            _ => fraction,
+        };
+
+        (self.imp().offset.get() + fraction / self.imp().zoom.get()).clamp(0.0, 1.0)
+    }
+
+    // We do not zoom past a single peak for each bar; the spectrum does
+    // not zoom at all
+    fn max_zoom(&self) -> f64 {
+        if self.mode() == WaveformMode::Spectrum {
+            return 1.0;
+        }
+
+        match *self.imp().peaks.borrow() {
+            Some(ref levels) => {
+                let n_bars = (self.width().max(1) / 4) as f64;
+                (levels.len() as f64 / n_bars).clamp(1.0, MAX_ZOOM)
+            }
+            None => 1.0,
+        }
+    }
+
+    fn set_offset(&self, offset: f64) {
+        let max_offset = 1.0 - 1.0 / self.imp().zoom.get();
+        self.imp().offset.set(offset.clamp(0.0, max_offset));
+        self.queue_draw();
+    }
+
+    // Zooms while keeping the song position under the anchor, a fraction
+    // of the width, in the same place
+    fn set_zoom(&self, zoom: f64, anchor: f64) {
+        let zoom = zoom.clamp(1.0, self.max_zoom());
+        let anchor_position = self.position_at(anchor * self.width() as f64);
+        let fraction = match self.direction() {
+            gtk::TextDirection::Rtl => 1.0 - anchor,
+            _ => anchor,
+        };
+
+        self.imp().zoom.set(zoom);
+        self.set_offset(anchor_position - fraction / zoom);
+    }
+
+    fn reset_zoom(&self) {
+        self.imp().zoom.set(1.0);
+        self.imp().follow.set(true);
+        self.set_offset(0.0);
+    }
+
+    // Moves the view by the given fraction of its width
+    fn pan(&self, delta: f64) {
+        let delta = match self.direction() {
+            gtk::TextDirection::Rtl => -delta,
+            _ => delta,
+        };
+
+        // Let the user look around without jumping back to the position
+        self.imp().follow.set(false);
+        self.set_offset(self.imp().offset.get() + delta / self.imp().zoom.get());
+    }
+
     fn seek_to_coord(&self, pos: f64) {
         let width = self.width();
-        let position = match self.direction() {
-            gtk::TextDirection::Rtl => 1.0 - (pos / width as f64),
-            _ => pos / width as f64,
-        };
+        let position = self.position_at(pos);
         debug!(
             "Seeking to coord {} (width: {}, position: {})",
             pos, width, position
@@ -474,12 +995,18 @@ impl WaveformView {
         normalized
     }
 
-    pub fn set_peaks(&self, peaks: Option<Vec<(f64, f64)>>) {
+    pub fn set_peaks(&self, peaks: Option<Vec<(f64, f64)>>, rms: Option<Vec<(f64, f64)>>) {
         if let Some(tick_id) = self.imp().tick_id.replace(None) {
             tick_id.remove();
         }
 
-        let peak_pairs = peaks.map(|p| self.normalize_peaks(p));
+        // A new song starts unzoomed
+        self.reset_zoom();
+
+        let peak_pairs = peaks.zip(rms).map(|(peaks, rms)| WaveformLevels {
+            peaks: PeakLevels::new(self.normalize_peaks(peaks), LevelReduction::Max),
+            rms: PeakLevels::new(self.normalize_peaks(rms), LevelReduction::Mean),
+        });
         let enable_animations = self.settings().is_gtk_enable_animations();
         if !enable_animations {
             self.imp().peaks.replace(peak_pairs);
@@ -552,10 +1079,59 @@ impl WaveformView {
         self.queue_resize();
     }
 
+    pub fn seekable(&self) -> bool {
+        self.imp().seekable.get()
+    }
+
+    // Internet radio streams cannot be seeked, and have no waveform
+    pub fn set_seekable(&self, seekable: bool) {
+        if seekable != self.imp().seekable.replace(seekable) {
+            self.imp().hover_position.replace(None);
+            self.hide_time();
+            self.reset_zoom();
+            self.set_focusable(seekable);
+            self.queue_draw();
+        }
+    }
+
     pub fn set_position(&self, position: f64) {
         let pos = position.clamp(0.0, 1.0);
         self.imp().position.replace(pos);
         self.update_property(&[gtk::accessible::Property::ValueNow(pos)]);
+
+        // Turn the page when the position goes out of view
+        let imp = self.imp();
+        let visible = 1.0 / imp.zoom.get();
+        let offset = imp.offset.get();
+        if imp.follow.get() && (pos < offset || pos > offset + visible) {
+            self.set_offset(pos);
+        }
+
+        self.queue_draw();
+    }
+
+    pub fn set_duration(&self, duration: u64) {
+        self.imp().duration.set(duration);
+    }
+
+    pub fn mode(&self) -> WaveformMode {
+        self.imp().mode.get()
+    }
+
+    pub fn set_mode(&self, mode: WaveformMode) {
+        if mode != self.imp().mode.replace(mode) {
+            self.imp().spectrum.replace(vec![]);
+            self.reset_zoom();
+            self.queue_draw();
+        }
+    }
+
+    pub fn set_spectrum(&self, spectrum: &[f32]) {
+        if self.mode() != WaveformMode::Spectrum {
+            return;
+        }
+
+        self.imp().spectrum.replace(spectrum.to_vec());
         self.queue_draw();
     }
 }
@@ -3,6 +3,7 @@
 
 use std::{
     cell::{Cell, RefCell},
+    collections::HashSet,
     rc::Rc,
     time::Instant,
 };
@@ -10,13 +11,15 @@ use std::{
 use adw::subclass::prelude::*;
 use glib::{clone, closure_local};
 use gtk::{gdk, gio, glib, prelude::*, CompositeTemplate};
-use log::debug;
+use log::{debug, warn};
 
 use crate::{
-    audio::{AudioPlayer, RepeatMode, ReplayGainMode, Song},
+    audio::{self, AudioPlayer, Library, RepeatMode, ReplayGainMode, Song, SortMode},
+    browse_view::BrowseView,
     config::APPLICATION_ID,
     drag_overlay::DragOverlay,
-    i18n::{i18n, i18n_k, ni18n_f, ni18n_k},
+    i18n::{i18n, i18n_f, i18n_k, ni18n_f, ni18n_k},
+    library_window::LibraryWindow,
     playback_control::PlaybackControl,
     playlist_view::PlaylistView,
     queue_row::QueueRow,
@@ -26,16 +29,38 @@ use crate::{
     sort::FuzzySorter,
     utils,
     volume_control::VolumeControl,
-    waveform_view::WaveformView,
+    waveform_view::{WaveformMode, WaveformView},
 };
 
+// Queue entries whose contents we load off the main thread
+enum PendingFiles {
+    Folder(gio::File),
+    RadioPlaylist(gio::File),
+}
+
 pub enum WindowMode {
     InitialView,
     MainView,
 }
 
+// The number of background colors used by the style sheet
+const PALETTE_COLORS: usize = 5;
+const PALETTE_TRANSITION_MSECS: u32 = 500;
+
+// The CSS colors for the background; without a palette, or if the
+// palette is too short, we use the window background instead
+fn palette_colors(colors: Option<&[gdk::RGBA]>) -> Vec<String> {
+    let colors = colors.unwrap_or_default();
+    (0..PALETTE_COLORS)
+        .map(|i| match colors.get(i) {
+            Some(c) => c.to_string(),
+            None => "@window_bg_color".to_string(),
+        })
+        .collect()
+}
+
 mod imp {
-    use glib::{ParamSpec, ParamSpecBoolean, ParamSpecEnum, Value};
+    use glib::{ParamSpec, ParamSpecBoolean, ParamSpecEnum, ParamSpecUInt, Value};
     use once_cell::sync::Lazy;
 
     use super::*;
@@ -67,13 +92,21 @@ mod imp {
         #[template_child]
         pub split_view: TemplateChild<adw::OverlaySplitView>,
         #[template_child]
+        pub sidebar_view: TemplateChild<adw::NavigationView>,
+        #[template_child]
         pub playlist_view: TemplateChild<PlaylistView>,
         #[template_child]
+        pub browse_view: TemplateChild<BrowseView>,
+        #[template_child]
         pub add_folder_button: TemplateChild<gtk::Button>,
         #[template_child]
         pub restore_playlist_button: TemplateChild<gtk::Button>,
 
         pub provider: gtk::CssProvider,
+        pub palette_animation: RefCell<Option<adw::TimedAnimation>>,
+        pub palette_from: RefCell<Vec<String>>,
+        pub palette_to: RefCell<Vec<String>>,
+        pub palette_recolor: Cell<bool>,
         pub settings: gio::Settings,
 
         pub playlist_shuffled: Cell<bool>,
@@ -81,16 +114,22 @@ mod imp {
         pub playlist_selection: Cell<bool>,
         pub playlist_search: Cell<bool>,
         pub replaygain_mode: Cell<ReplayGainMode>,
+        pub waveform_mode: Cell<WaveformMode>,
+        pub playlist_sort: Cell<SortMode>,
 
         pub playlist_filtermodel: RefCell<Option<gio::ListModel>>,
+        pub playlist_filter: RefCell<Option<FuzzyFilter>>,
+        pub loading_cancellable: RefCell<Option<gio::Cancellable>>,
 
         pub notify_playing_id: RefCell<Option<glib::SignalHandlerId>>,
         pub notify_position_id: RefCell<Option<glib::SignalHandlerId>>,
         pub notify_song_id: RefCell<Option<glib::SignalHandlerId>>,
         pub notify_cover_id: RefCell<Option<glib::SignalHandlerId>>,
+        pub notify_title_id: RefCell<Option<glib::SignalHandlerId>>,
         pub notify_nsongs_id: RefCell<Option<glib::SignalHandlerId>>,
         pub notify_current_id: RefCell<Option<glib::SignalHandlerId>>,
         pub notify_peaks_id: RefCell<Option<glib::SignalHandlerId>>,
+        pub spectrum_changed_id: RefCell<Option<glib::SignalHandlerId>>,
     }
 
     #[glib::object_subclass]
@@ -146,23 +185,66 @@ mod imp {
                 debug!("Window::win.add-folder()");
                 win.add_folder();
             });
+            klass.install_action("queue.add-stream", None, move |win, _, _| {
+                debug!("Window::win.add-stream()");
+                win.add_stream();
+            });
+            klass.install_action("queue.cancel-loading", None, move |win, _, _| {
+                debug!("Window::queue.cancel-loading()");
+                win.cancel_loading();
+            });
             klass.install_action("queue.restore-playlist", None, move |win, _, _| {
                 debug!("Window::queue.restore-playlist()");
                 win.restore_playlist();
             });
+            klass.install_action("library.manage", None, move |win, _, _| {
+                debug!("Window::library.manage()");
+                win.manage_library();
+            });
+            klass.install_action("library.browse", None, move |win, _, _| {
+                debug!("Window::library.browse()");
+                win.browse_library();
+            });
+            klass.install_action("library.play", None, move |win, _, _| {
+                debug!("Window::library.play()");
+                win.play_library();
+            });
             klass.install_action("win.copy", None, move |win, _, _| {
                 debug!("Window::win.copy()");
                 win.copy_song();
             });
+            klass.install_action("cover.export", None, move |win, _, _| {
+                debug!("Window::cover.export()");
+                win.export_cover();
+            });
+            klass.install_action("cover.copy", None, move |win, _, _| {
+                debug!("Window::cover.copy()");
+                win.copy_cover();
+            });
+            klass.install_action("cover.set", None, move |win, _, _| {
+                debug!("Window::cover.set()");
+                win.choose_cover();
+            });
+            klass.install_action("win.clear-caches", None, move |win, _, _| {
+                debug!("Window::win.clear-caches()");
+                win.clear_caches();
+            });
             klass.install_action("queue.clear", None, move |win, _, _| {
                 debug!("Window::queue.clear()");
                 win.clear_queue();
             });
+            klass.install_action("queue.clean-up", None, move |win, _, _| {
+                debug!("Window::queue.clean-up()");
+                win.clean_up_queue();
+            });
             klass.install_property_action("queue.toggle", "playlist-visible");
             klass.install_property_action("queue.shuffle", "playlist-shuffled");
             klass.install_property_action("queue.select", "playlist-selection");
             klass.install_property_action("queue.search", "playlist-search");
+            klass.install_property_action("queue.sort", "playlist-sort");
             klass.install_property_action("win.replaygain", "replaygain-mode");
+            klass.install_property_action("win.waveform-mode", "waveform-mode");
+            klass.install_property_action("win.rate-song", "song-rating");
 
             klass.install_action(
                 "win.skip-to",
@@ -197,22 +279,34 @@ mod imp {
                 status_page: TemplateChild::default(),
                 add_folder_button: TemplateChild::default(),
                 restore_playlist_button: TemplateChild::default(),
+                sidebar_view: TemplateChild::default(),
                 playlist_view: TemplateChild::default(),
+                browse_view: TemplateChild::default(),
                 playlist_shuffled: Cell::new(false),
                 playlist_visible: Cell::new(true),
                 playlist_selection: Cell::new(false),
                 playlist_search: Cell::new(false),
                 playlist_filtermodel: RefCell::default(),
+                playlist_filter: RefCell::default(),
+                loading_cancellable: RefCell::default(),
                 replaygain_mode: Cell::new(ReplayGainMode::default()),
+                waveform_mode: Cell::new(WaveformMode::default()),
+                playlist_sort: Cell::new(SortMode::default()),
                 provider: gtk::CssProvider::new(),
+                palette_animation: RefCell::default(),
+                palette_from: RefCell::default(),
+                palette_to: RefCell::default(),
+                palette_recolor: Cell::new(false),
                 settings: utils::settings_manager(),
                 notify_playing_id: RefCell::new(None),
                 notify_position_id: RefCell::new(None),
                 notify_song_id: RefCell::new(None),
                 notify_cover_id: RefCell::new(None),
+                notify_title_id: RefCell::new(None),
                 notify_nsongs_id: RefCell::new(None),
                 notify_current_id: RefCell::new(None),
                 notify_peaks_id: RefCell::new(None),
+                spectrum_changed_id: RefCell::new(None),
             }
         }
     }
@@ -234,6 +328,11 @@ mod imp {
                     ParamSpecBoolean::builder("playlist-selection").build(),
                     ParamSpecBoolean::builder("playlist-search").build(),
                     ParamSpecEnum::builder::<ReplayGainMode>("replaygain-mode").build(),
+                    ParamSpecEnum::builder::<WaveformMode>("waveform-mode").build(),
+                    ParamSpecEnum::builder::<SortMode>("playlist-sort").build(),
+                    ParamSpecUInt::builder("song-rating")
+                        .maximum(audio::MAX_RATING)
+                        .build(),
                 ]
             });
             PROPERTIES.as_ref()
@@ -247,6 +346,9 @@ mod imp {
                 "playlist-selection" => obj.set_playlist_selection(value.get::<bool>().unwrap()),
                 "playlist-search" => obj.set_playlist_search(value.get::<bool>().unwrap()),
                 "replaygain-mode" => obj.set_replaygain(value.get::<ReplayGainMode>().unwrap()),
+                "waveform-mode" => obj.set_waveform_mode(value.get::<WaveformMode>().unwrap()),
+                "playlist-sort" => obj.set_playlist_sort(value.get::<SortMode>().unwrap()),
+                "song-rating" => obj.set_song_rating(value.get::<u32>().unwrap()),
                 _ => unimplemented!(),
             }
         }
@@ -259,6 +361,9 @@ mod imp {
                 "playlist-selection" => obj.playlist_selection().to_value(),
                 "playlist-search" => obj.playlist_search().to_value(),
                 "replaygain-mode" => obj.replaygain().to_value(),
+                "waveform-mode" => obj.waveform_mode().to_value(),
+                "playlist-sort" => obj.playlist_sort().to_value(),
+                "song-rating" => obj.song_rating().to_value(),
                 _ => unimplemented!(),
             }
         }
@@ -285,6 +390,7 @@ impl Window {
         win.setup_waveform();
         win.setup_actions();
         win.setup_playlist();
+        win.setup_library();
         win.setup_drop_target();
         win.setup_provider();
         win.bind_state();
@@ -296,6 +402,14 @@ impl Window {
         win
     }
 
+    fn library(&self) -> Option<Library> {
+        self.application().map(|app| {
+            app.downcast::<crate::application::Application>()
+                .unwrap()
+                .library()
+        })
+    }
+
     fn player(&self) -> Option<Rc<AudioPlayer>> {
         if let Some(app) = self.application() {
             let player = app
@@ -328,17 +442,29 @@ impl Window {
 
     fn setup_waveform(&self) {
         if let Some(player) = self.player() {
-            let peaks = player.waveform_generator().peaks();
-            self.imp().waveform_view.set_peaks(peaks);
+            let gen = player.waveform_generator();
+            self.imp().waveform_view.set_peaks(gen.peaks(), gen.rms());
 
-            let notify_peaks_id = player.waveform_generator().connect_notify_local(
+            let notify_peaks_id = gen.connect_notify_local(
                 Some("has-peaks"),
                 clone!(@weak self as win => move |gen, _| {
-                    let peaks = gen.peaks();
-                    win.imp().waveform_view.set_peaks(peaks);
+                    win.imp().waveform_view.set_peaks(gen.peaks(), gen.rms());
                 }),
             );
             self.imp().notify_peaks_id.replace(Some(notify_peaks_id));
+
+            let spectrum_changed_id = player.state().connect_local(
+                "spectrum-changed",
+                false,
+                clone!(@weak self as win => @default-return None, move |values| {
+                    let state = values[0].get::<audio::PlayerState>().unwrap();
+                    win.imp().waveform_view.set_spectrum(&state.spectrum());
+                    None
+                }),
+            );
+            self.imp()
+                .spectrum_changed_id
+                .replace(Some(spectrum_changed_id));
         }
     }
 
@@ -347,6 +473,9 @@ impl Window {
             if let Some(id) = self.imp().notify_peaks_id.take() {
                 player.waveform_generator().disconnect(id);
             }
+            if let Some(id) = self.imp().spectrum_changed_id.take() {
+                player.state().disconnect(id);
+            }
         }
     }
 
@@ -365,11 +494,122 @@ impl Window {
     }
 
     fn clear_queue(&self) {
+        self.cancel_loading();
         if let Some(p) = self.player() {
             p.clear_queue();
         }
     }
 
+    // Removes the songs that went missing or cannot be read anymore, and
+    // the duplicates of other songs in the playlist
+    fn clean_up_queue(&self) {
+        let player = match self.player() {
+            Some(player) => player,
+            None => return,
+        };
+
+        let queue = player.queue();
+        let current = queue.current_song();
+        let songs: Vec<Song> = (0..queue.n_songs())
+            .filter_map(|i| queue.song_at(i))
+            .filter(|s| !s.is_stream() && Some(s) != current.as_ref())
+            .collect();
+        let uris: Vec<String> = songs.iter().map(|s| s.uri()).collect();
+
+        // Checking the files may block on network mounts
+        self.action_set_enabled("queue.clean-up", false);
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let readable = gio::spawn_blocking(move || {
+                uris.iter().map(|uri| utils::is_readable_uri(uri)).collect::<Vec<bool>>()
+            })
+            .await
+            .unwrap_or_default();
+
+            win.action_set_enabled("queue.clean-up", true);
+
+            let player = match win.player() {
+                Some(player) => player,
+                None => return,
+            };
+            let queue = player.queue();
+
+            let missing: Vec<Song> = songs
+                .into_iter()
+                .zip(readable)
+                .filter_map(|(song, readable)| if readable { None } else { Some(song) })
+                .collect();
+            let mut removed = queue.remove_songs(&missing);
+            let n_missing = removed.len() as u32;
+            removed.extend(queue.remove_songs(&queue.duplicate_songs()));
+            let n_duplicates = removed.len() as u32 - n_missing;
+
+            if removed.is_empty() {
+                win.add_toast(i18n("No missing or duplicate songs found"));
+                return;
+            }
+
+            for (_, song) in &removed {
+                debug!("Removed song from the playlist: {}", song.uri());
+            }
+
+            // The positions of the duplicates are relative to the store
+            // without the missing songs, so they have to go back first
+            let (first, last) = removed.split_at(n_missing as usize);
+            let removed: Vec<(u32, Song)> = last.iter().chain(first).cloned().collect();
+
+            utils::store_playlist(queue);
+            win.update_selected_count();
+            win.update_playlist_time();
+
+            let msg = match (n_missing, n_duplicates) {
+                (n, 0) => ni18n_f(
+                    // Translators: the `{}` must be left unmodified;
+                    // it will be expanded to the number of songs removed
+                    // from the playlist
+                    "Removed one missing song",
+                    "Removed {} missing songs",
+                    n,
+                    &[&n.to_string()],
+                ),
+                (0, n) => ni18n_f(
+                    // Translators: the `{}` must be left unmodified;
+                    // it will be expanded to the number of songs removed
+                    // from the playlist
+                    "Removed one duplicate song",
+                    "Removed {} duplicate songs",
+                    n,
+                    &[&n.to_string()],
+                ),
+                (missing, duplicates) => ni18n_k(
+                    // Translators: `{missing}` and `{duplicates}` must be
+                    // left unmodified; they will be expanded to the number
+                    // of songs removed from the playlist
+                    "Removed {missing} missing song and {duplicates} duplicates",
+                    "Removed {missing} missing songs and {duplicates} duplicates",
+                    missing,
+                    &[
+                        ("missing", &missing.to_string()),
+                        ("duplicates", &duplicates.to_string()),
+                    ],
+                ),
+            };
+
+            let toast = adw::Toast::new(&msg);
+            toast.set_button_label(Some(&i18n("_Undo")));
+            toast.connect_button_clicked(clone!(@weak win => move |_| {
+                if let Some(player) = win.player() {
+                    let queue = player.queue();
+                    queue.restore_songs(&removed);
+                    utils::store_playlist(queue);
+                    win.update_selected_count();
+                    win.update_playlist_time();
+                }
+            }));
+            win.imp().toast_overlay.add_toast(toast);
+        }));
+    }
+
     fn playlist_visible(&self) -> bool {
         self.imp().playlist_visible.get()
     }
@@ -405,6 +645,33 @@ impl Window {
         }
     }
 
+    fn playlist_sort(&self) -> SortMode {
+        self.imp().playlist_sort.get()
+    }
+
+    // Choosing the current sort mode again sorts the queue again, which
+    // re-shuffles it in random mode. Sorting the songs turns off the
+    // shuffling, otherwise the new order would not be visible
+    fn set_playlist_sort(&self, mode: SortMode) {
+        let imp = self.imp();
+
+        if let Some(player) = self.player() {
+            self.set_playlist_shuffled(false);
+
+            let queue = player.queue();
+            queue.sort(mode);
+            utils::store_playlist(queue);
+        }
+
+        if mode != imp.playlist_sort.replace(mode) {
+            imp.settings
+                .set_enum("queue-sort", mode.into())
+                .expect("Unable to store setting");
+
+            self.notify("playlist-sort");
+        }
+    }
+
     fn playlist_selection(&self) -> bool {
         self.imp().playlist_selection.get()
     }
@@ -425,6 +692,7 @@ impl Window {
                 .queue_actionbar()
                 .set_revealed(selection);
 
+            self.update_cover_actions();
             self.notify("playlist-selection");
         }
     }
@@ -487,6 +755,144 @@ impl Window {
         }));
     }
 
+    fn add_stream(&self) {
+        let entry = gtk::Entry::builder()
+            .placeholder_text("https://")
+            .input_purpose(gtk::InputPurpose::Url)
+            .activates_default(true)
+            .build();
+
+        let dialog = adw::MessageDialog::builder()
+            .transient_for(self)
+            .modal(true)
+            .heading(i18n("Add Internet Radio Stream"))
+            .body(i18n(
+                "Enter the address of a stream, or of a radio playlist in the PLS or M3U format",
+            ))
+            .extra_child(&entry)
+            .default_response("add")
+            .close_response("cancel")
+            .build();
+        dialog.add_responses(&[("cancel", &i18n("_Cancel")), ("add", &i18n("_Add"))]);
+        dialog.set_response_appearance("add", adw::ResponseAppearance::Suggested);
+        dialog.set_response_enabled("add", false);
+
+        entry.connect_changed(clone!(@weak dialog => move |entry| {
+            let valid = utils::is_stream_uri(entry.text().trim());
+            dialog.set_response_enabled("add", valid);
+        }));
+
+        dialog.connect_response(
+            Some("add"),
+            clone!(@weak self as win, @weak entry => move |_, _| {
+                let file = gio::File::for_uri(entry.text().trim());
+                let model = gio::ListStore::new::<gio::File>();
+                model.append(&file);
+                win.add_files_to_queue(model.upcast_ref::<gio::ListModel>());
+            }),
+        );
+
+        dialog.present();
+    }
+
+    fn manage_library(&self) {
+        if let Some(library) = self.library() {
+            let window = LibraryWindow::new(self, &library);
+            window.present();
+        }
+    }
+
+    fn play_library(&self) {
+        let library = match self.library() {
+            Some(library) => library,
+            None => return,
+        };
+
+        if library.n_songs() == 0 {
+            self.add_toast(i18n("The music library is empty"));
+            return;
+        }
+
+        self.enqueue_songs(library.songs());
+    }
+
+    // Adds songs that are already loaded, like the ones in the library,
+    // to the end of the queue
+    fn enqueue_songs(&self, songs: Vec<Song>) {
+        if let Some(player) = self.player() {
+            let queue = player.queue();
+            let was_empty = queue.is_empty();
+
+            // Skip the songs that are already in the queue
+            let mut known_songs = HashSet::new();
+            for i in 0..queue.n_songs() {
+                known_songs.insert(queue.song_at(i).unwrap().uri());
+            }
+            let songs: Vec<Song> = songs
+                .into_iter()
+                .filter(|s| known_songs.insert(s.uri()))
+                .collect();
+
+            if songs.is_empty() {
+                return;
+            }
+
+            queue.add_songs(&songs);
+            utils::store_playlist(queue);
+
+            self.switch_mode(WindowMode::MainView);
+            if was_empty {
+                player.skip_to(0);
+                player.play();
+            } else {
+                let msg = ni18n_f(
+                    // Translators: the `{}` must be left unmodified;
+                    // it will be expanded to the number of songs added
+                    // to the playlist
+                    "Added one song",
+                    "Added {} songs",
+                    songs.len() as u32,
+                    &[&songs.len().to_string()],
+                );
+                self.add_toast(msg);
+            }
+        }
+    }
+
+    // Replaces the queue with the given songs, and starts playing
+    // from the song at the given position
+    fn play_songs(&self, songs: Vec<Song>, pos: u32) {
+        if songs.is_empty() {
+            return;
+        }
+
+        if let Some(player) = self.player() {
+            self.cancel_loading();
+
+            let first = songs.get(pos as usize).cloned();
+            player.replace_queue(&songs);
+
+            let queue = player.queue();
+            utils::store_playlist(queue);
+
+            // The queue may be shuffled, so we need to look for the song
+            let pos = first
+                .and_then(|first| {
+                    (0..queue.n_songs()).find(|i| queue.song_at(*i).unwrap().equals(&first))
+                })
+                .unwrap_or(0);
+
+            self.switch_mode(WindowMode::MainView);
+            player.skip_to(pos);
+            player.play();
+        }
+    }
+
+    fn browse_library(&self) {
+        self.set_playlist_visible(true);
+        self.imp().sidebar_view.push_by_tag("library");
+    }
+
     fn restore_playlist(&self) {
         if let Some(songs) = utils::load_cached_songs() {
             self.queue_songs(songs);
@@ -501,113 +907,184 @@ impl Window {
 
         self.switch_mode(WindowMode::MainView);
 
-        // Disable actions on the queue; loading is "atomic"
-        self.action_set_enabled("queue.add-song", false);
-        self.action_set_enabled("queue.add-folder", false);
-        self.action_set_enabled("queue.clear", false);
-
-        self.imp().playlist_view.begin_loading();
+        self.begin_queue_loading();
 
         // Begin the trace
         let now = Instant::now();
 
-        // Turn the list of files into songs one at a time into the main loop
         let n_files = queue.len() as u32;
 
-        let mut files = queue.into_iter();
-        let mut songs = Vec::new();
-        let mut cur_file: u32 = 0;
-        let mut duplicates: u32 = 0;
-
-        glib::idle_add_local(
-            clone!(@weak self as win => @default-return glib::ControlFlow::Break, move || {
-                files.next()
-                    .map(|f| {
-                        win.imp().playlist_view.update_loading(cur_file, n_files);
-                        if let Ok(s) = Song::from_uri(f.uri().as_str()) {
-                            if let Some(player) = win.player() {
-                                let queue = player.queue();
-                                if queue.contains(&s) {
-                                    duplicates += 1;
-                                } else {
-                                    songs.push(s);
-                                    cur_file += 1;
-                                }
-                            }
+        // Tags and cover art are loaded in a pool of threads, and the
+        // songs are sent back to us in batches
+        let cancellable = gio::Cancellable::new();
+        let receiver = audio::load_songs(queue, &cancellable);
+        self.imp()
+            .loading_cancellable
+            .replace(Some(cancellable.clone()));
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            use futures::prelude::*;
+
+            // Checking for duplicates in the queue for every song we
+            // load is too expensive for large folders
+            let mut known_songs = HashSet::new();
+            if let Some(player) = win.player() {
+                let queue = player.queue();
+                for i in 0..queue.n_songs() {
+                    let song = queue.song_at(i).unwrap();
+                    known_songs.insert(song.uuid().unwrap_or_else(|| song.uri()));
+                }
+            }
+
+            let mut songs = Vec::new();
+            let mut cur_file: u32 = 0;
+            let mut duplicates: u32 = 0;
+
+            let mut receiver = std::pin::pin!(receiver);
+            while let Some(batch) = receiver.next().await {
+                for (pos, data) in batch {
+                    cur_file += 1;
+                    if let Ok(data) = data {
+                        let s = Song::from_data(data);
+                        if known_songs.insert(s.uuid().unwrap_or_else(|| s.uri())) {
+                            songs.push((pos, s));
+                        } else {
+                            duplicates += 1;
                         }
-                    })
-                    .map(|_| glib::ControlFlow::Continue)
-                    .unwrap_or_else(|| {
-                        debug!("Total loading time for {} files: {} ms", n_files, now.elapsed().as_millis());
-
-                        // Re-enable the actions
-                        win.action_set_enabled("queue.add-song", true);
-                        win.action_set_enabled("queue.add-folder", true);
-                        win.action_set_enabled("queue.clear", true);
-
-                        if songs.is_empty() {
-                            if duplicates == 0 {
-                                win.add_toast(i18n("No songs found"));
-                            }
-                        } else if let Some(player) = win.player() {
-                            let queue = player.queue();
-                            let was_empty = queue.is_empty();
+                    }
+                }
 
-                            win.imp().playlist_view.end_loading();
+                win.imp().playlist_view.update_loading(cur_file, n_files);
+            }
 
-                            // Bulk add to avoid hammering the UI with list model updates
-                            queue.add_songs(&songs);
+            debug!("Total loading time for {} files: {} ms", n_files, now.elapsed().as_millis());
 
-                            // Store the current state of the playlist
-                            utils::store_playlist(queue);
+            win.end_queue_loading();
 
-                            debug!("Queue was empty: {}, new size: {}", was_empty, queue.n_songs());
-                            if was_empty {
-                                player.skip_to(0);
-                            }
+            // Store the metadata we just parsed, so that we can skip
+            // parsing the same files the next time
+            std::thread::spawn(|| {
+                audio::MetadataCache::save();
+                audio::PlayStats::global().lock().unwrap().save();
+            });
 
-                            // Allow jumping to the song we just added
-                            if songs.len() == 1 {
-                                // If we added a single song, and the queue was empty, we
-                                // dispense with the pleasantries and we start playing
-                                // immediately; otherwise, we let the user choose whether
-                                // to jump to the newly added song
-                                if was_empty {
-                                    player.play();
-                                } else {
-                                    win.add_skip_to_toast(
-                                        i18n("Added a new song"),
-                                        i18n("Play"),
-                                        queue.n_songs() - 1,
-                                    );
-                                }
-                            } else {
-                                let msg = ni18n_f(
-                                    // Translators: the `{}` must be left unmodified;
-                                    // it will be expanded to the number of songs added
-                                    // to the playlist
-                                    "Added one song",
-                                    "Added {} songs",
-                                    songs.len() as u32,
-                                    &[&songs.len().to_string()],
-                                );
-
-                                win.add_toast(msg);
-                            }
-                        }
+            if cancellable.is_cancelled() {
+                debug!("Loading cancelled after {} files", cur_file);
+                return;
+            }
 
-                        glib::ControlFlow::Break
-                    })
-            }),
-        );
+            // The workers finish in any order, so we need to restore
+            // the order of the files
+            songs.sort_by_key(|(pos, _)| *pos);
+            let songs: Vec<Song> = songs.into_iter().map(|(_, s)| s).collect();
+
+            if songs.is_empty() {
+                if duplicates == 0 {
+                    win.add_toast(i18n("No songs found"));
+                }
+            } else if let Some(player) = win.player() {
+                let queue = player.queue();
+                let was_empty = queue.is_empty();
+
+                // Bulk add to avoid hammering the UI with list model updates
+                queue.add_songs(&songs);
+
+                // Store the current state of the playlist
+                utils::store_playlist(queue);
+
+                debug!("Queue was empty: {}, new size: {}", was_empty, queue.n_songs());
+                if was_empty {
+                    player.skip_to(0);
+                }
+
+                // Allow jumping to the song we just added
+                if songs.len() == 1 {
+                    // If we added a single song, and the queue was empty, we
+                    // dispense with the pleasantries and we start playing
+                    // immediately; otherwise, we let the user choose whether
+                    // to jump to the newly added song
+                    if was_empty {
+                        player.play();
+                    } else {
+                        win.add_skip_to_toast(
+                            i18n("Added a new song"),
+                            i18n("Play"),
+                            queue.position(&songs[0]).unwrap_or(queue.n_songs() - 1),
+                        );
+                    }
+                } else {
+                    let msg = ni18n_f(
+                        // Translators: the `{}` must be left unmodified;
+                        // it will be expanded to the number of songs added
+                        // to the playlist
+                        "Added one song",
+                        "Added {} songs",
+                        songs.len() as u32,
+                        &[&songs.len().to_string()],
+                    );
+
+                    win.add_toast(msg);
+                }
+            }
+        }));
+    }
+
+    // Disable actions on the queue; loading is "atomic"
+    fn begin_queue_loading(&self) {
+        self.action_set_enabled("queue.add-song", false);
+        self.action_set_enabled("queue.add-folder", false);
+        self.action_set_enabled("queue.add-stream", false);
+        self.action_set_enabled("queue.clear", false);
+        self.action_set_enabled("queue.clean-up", false);
+        self.action_set_enabled("queue.cancel-loading", true);
+
+        self.imp().playlist_view.begin_loading();
+    }
+
+    fn end_queue_loading(&self) {
+        self.imp().loading_cancellable.replace(None);
+        self.imp().playlist_view.end_loading();
+
+        self.action_set_enabled("queue.add-song", true);
+        self.action_set_enabled("queue.add-folder", true);
+        self.action_set_enabled("queue.add-stream", true);
+        self.action_set_enabled("queue.clear", true);
+        self.action_set_enabled("queue.clean-up", true);
+        self.action_set_enabled("queue.cancel-loading", false);
+    }
+
+    fn cancel_loading(&self) {
+        if let Some(cancellable) = self.imp().loading_cancellable.take() {
+            debug!("Cancelling song loading");
+            cancellable.cancel();
+        }
     }
 
     fn add_files_to_queue(&self, model: &gio::ListModel) {
         let mut queue: Vec<gio::File> = vec![];
 
+        // Folders are scanned later, and radio playlists may have to be
+        // downloaded, so we keep track of where their contents go in the
+        // queue
+        let mut pending: Vec<(usize, PendingFiles)> = vec![];
+
         for pos in 0..model.n_items() {
             let file = model.item(pos).unwrap().downcast::<gio::File>().unwrap();
 
+            // Internet radio streams cannot be queried, so we only look
+            // at the URI to decide whether it's a playlist or a stream
+            if utils::is_stream_uri(&file.uri()) {
+                if utils::is_radio_playlist(&file, None) {
+                    debug!("Adding radio playlist '{}' to the queue", file.uri());
+                    pending.push((queue.len(), PendingFiles::RadioPlaylist(file)));
+                } else {
+                    debug!("Adding stream '{}' to the queue", file.uri());
+                    queue.push(file);
+                }
+                continue;
+            }
+
             if let Ok(info) = file.query_info(
                 "standard::name,standard::display-name,standard::type,standard::content-type",
                 gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
@@ -616,7 +1093,10 @@ impl Window {
                 match info.file_type() {
                     gio::FileType::Regular => {
                         if let Some(content_type) = info.content_type() {
-                            if gio::content_type_is_mime_type(&content_type, "audio/*") {
+                            if utils::is_radio_playlist(&file, Some(&content_type)) {
+                                debug!("Adding radio playlist '{}' to the queue", file.uri());
+                                pending.push((queue.len(), PendingFiles::RadioPlaylist(file)));
+                            } else if gio::content_type_is_mime_type(&content_type, "audio/*") {
                                 debug!("Adding file '{}' to the queue", file.uri());
                                 queue.push(file);
                             }
@@ -624,15 +1104,98 @@ impl Window {
                     }
                     gio::FileType::Directory => {
                         debug!("Adding folder '{}' to the queue", file.uri());
-                        let files = utils::load_files_from_folder(&file, true);
-                        queue.extend(files);
+                        pending.push((queue.len(), PendingFiles::Folder(file)));
                     }
                     _ => (),
                 }
             }
         }
 
-        self.queue_songs(queue);
+        if pending.is_empty() {
+            self.queue_songs(queue);
+            return;
+        }
+
+        self.switch_mode(WindowMode::MainView);
+        self.begin_queue_loading();
+
+        // Scanning large folders and downloading playlists takes a while,
+        // so we do it off the main thread and allow cancelling it like the
+        // loading of the songs
+        let cancellable = gio::Cancellable::new();
+        self.imp()
+            .loading_cancellable
+            .replace(Some(cancellable.clone()));
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let dirs: Vec<gio::File> = pending
+                .iter()
+                .filter_map(|(_, p)| match p {
+                    PendingFiles::Folder(f) => Some(f.clone()),
+                    PendingFiles::RadioPlaylist(_) => None,
+                })
+                .collect();
+            let mut results = audio::scan_folders(
+                &dirs,
+                audio::ScanOptions::new(true),
+                &cancellable,
+                clone!(@weak win => move |_| {
+                    win.imp().playlist_view.pulse_loading();
+                }),
+            )
+            .await
+            .into_iter();
+
+            let mut skipped = 0;
+            let mut contents: Vec<(usize, Vec<gio::File>)> = vec![];
+            for (pos, p) in pending {
+                if cancellable.is_cancelled() {
+                    break;
+                }
+
+                let files = match p {
+                    PendingFiles::Folder(_) => {
+                        let res = results.next().unwrap_or_default();
+                        skipped += res.skipped.len();
+                        res.files.iter().map(|uri| gio::File::for_uri(uri)).collect()
+                    }
+                    PendingFiles::RadioPlaylist(file) => {
+                        let c = cancellable.clone();
+                        gio::spawn_blocking(move || utils::load_radio_playlist(&file, &c))
+                            .await
+                            .unwrap_or_default()
+                    }
+                };
+                contents.push((pos, files));
+            }
+
+            win.end_queue_loading();
+
+            if cancellable.is_cancelled() {
+                debug!("Queue loading cancelled");
+                return;
+            }
+
+            // Insert from the end, so that the positions stay valid
+            for (pos, files) in contents.into_iter().rev() {
+                queue.splice(pos..pos, files);
+            }
+
+            if skipped > 0 {
+                let msg = ni18n_f(
+                    // Translators: the `{}` must be left unmodified;
+                    // it will be expanded to the number of folders
+                    "Skipped one folder that could not be read",
+                    "Skipped {} folders that could not be read",
+                    skipped as u32,
+                    &[&skipped.to_string()],
+                );
+                win.add_toast(msg);
+            }
+
+            win.queue_songs(queue);
+        }));
     }
 
     // Bind the PlayerState to the UI
@@ -672,6 +1235,15 @@ impl Window {
             );
             imp.notify_song_id.replace(Some(notify_song_id));
 
+            // Internet radio streams update their title while playing
+            let notify_title_id = state.connect_notify_local(
+                Some("title"),
+                clone!(@weak self as win => move |state, _| {
+                    win.update_title(state.current_song().as_ref());
+                }),
+            );
+            imp.notify_title_id.replace(Some(notify_title_id));
+
             // Update the cover, if any is available
             self.update_cover();
             let notify_cover_id = state.connect_notify_local(
@@ -722,6 +1294,9 @@ impl Window {
             if let Some(id) = self.imp().notify_cover_id.take() {
                 state.disconnect(id);
             }
+            if let Some(id) = self.imp().notify_title_id.take() {
+                state.disconnect(id);
+            }
         }
     }
 
@@ -912,6 +1487,14 @@ impl Window {
         );
         let _dummy = self.imp().settings.boolean("enable-recoloring");
 
+        // The palette depends on the style, as the text color changes
+        adw::StyleManager::default().connect_dark_notify(clone!(@weak self as this => move |_| {
+            if let Some(player) = this.player() {
+                let state = player.state();
+                this.update_style(state.current_song().as_ref());
+            }
+        }));
+
         self.connect_close_request(move |window| {
             debug!("Saving window state");
             let width = window.default_size().0;
@@ -925,6 +1508,7 @@ impl Window {
                 .set_int("window-height", height)
                 .expect("Unable to stop window-height");
 
+            window.cancel_loading();
             window.unbind_queue();
             window.unbind_state();
             window.unbind_waveform();
@@ -947,6 +1531,7 @@ impl Window {
             self.action_set_enabled("win.next", !queue.is_last_song());
 
             self.action_set_enabled("queue.toggle", !queue.is_empty());
+            self.action_set_enabled("queue.cancel-loading", false);
             self.action_set_enabled("queue.shuffle", queue.n_songs() > 1);
             self.action_set_enabled("win.replaygain", player.replaygain_available());
 
@@ -957,11 +1542,23 @@ impl Window {
             // only updates player state when the value changes.
             player.set_replaygain(replaygain);
 
+            // Same as above
+            let waveform_mode = self.imp().settings.enum_("waveform-mode").into();
+            self.set_waveform_mode(waveform_mode);
+            self.imp().waveform_view.set_mode(waveform_mode);
+            player.set_spectrum_enabled(waveform_mode == WaveformMode::Spectrum);
+
             self.imp()
                 .playback_control
                 .set_repeat_mode(queue.repeat_mode());
             self.set_playlist_shuffled(queue.is_shuffled());
 
+            // The queue is still empty, so this only sets the mode for
+            // the songs we are going to add
+            let sort_mode = self.imp().settings.enum_("queue-sort").into();
+            self.imp().playlist_sort.set(sort_mode);
+            queue.sort(sort_mode);
+
             // Manually update the icon on the initial empty state
             // to avoid generating the UI definition file at build
             // time
@@ -994,6 +1591,56 @@ impl Window {
         }
     }
 
+    fn setup_library(&self) {
+        let imp = self.imp();
+
+        if let Some(library) = self.library() {
+            imp.browse_view.set_library(&library);
+
+            self.update_library_state(&library);
+            library.connect_notify_local(
+                Some("enabled"),
+                clone!(@weak self as win => move |library, _| {
+                    win.update_library_state(library);
+                }),
+            );
+        }
+
+        imp.browse_view.connect_closure(
+            "play-songs",
+            false,
+            closure_local!(
+                @watch self as win => move |_: BrowseView, model: gio::ListModel, pos: u32| {
+                    let songs = model.iter::<Song>().filter_map(|s| s.ok()).collect();
+                    win.play_songs(songs, pos);
+                }
+            ),
+        );
+        imp.browse_view.connect_closure(
+            "queue-songs",
+            false,
+            closure_local!(@watch self as win => move |_: BrowseView, model: gio::ListModel| {
+                let songs = model.iter::<Song>().filter_map(|s| s.ok()).collect();
+                win.enqueue_songs(songs);
+            }),
+        );
+    }
+
+    fn update_library_state(&self, library: &Library) {
+        let enabled = library.is_enabled();
+        self.action_set_enabled("library.browse", enabled);
+        self.action_set_enabled("library.play", enabled);
+        self.imp()
+            .playlist_view
+            .browse_button()
+            .set_visible(enabled);
+
+        // Go back to the playlist if the library is gone
+        if !enabled {
+            self.imp().sidebar_view.pop_to_tag("playlist");
+        }
+    }
+
     fn setup_playlist(&self) {
         let imp = self.imp();
 
@@ -1015,6 +1662,14 @@ impl Window {
                 .flags(glib::BindingFlags::DEFAULT | glib::BindingFlags::SYNC_CREATE)
                 .build();
 
+            // Highlight the part of the song matching the search
+            if let Some(filter) = win.imp().playlist_filter.borrow().as_ref() {
+                filter
+                    .bind_property("search", &row, "search")
+                    .flags(glib::BindingFlags::DEFAULT | glib::BindingFlags::SYNC_CREATE)
+                    .build();
+            }
+
             list_item
                 .bind_property("item", &row, "song")
                 .flags(glib::BindingFlags::DEFAULT | glib::BindingFlags::SYNC_CREATE)
@@ -1030,7 +1685,7 @@ impl Window {
                 .bind(&row, "song-title", gtk::Widget::NONE);
             list_item
                 .property_expression("item")
-                .chain_property::<Song>("cover")
+                .chain_property::<Song>("cover-art")
                 .bind(&row, "song-cover", gtk::Widget::NONE);
             list_item
                 .property_expression("item")
@@ -1040,6 +1695,10 @@ impl Window {
                 .property_expression("item")
                 .chain_property::<Song>("selected")
                 .bind(&row, "selected", gtk::Widget::NONE);
+            list_item
+                .property_expression("item")
+                .chain_property::<Song>("broken")
+                .bind(&row, "broken", gtk::Widget::NONE);
         }));
         imp.playlist_view
             .queue_view()
@@ -1049,10 +1708,13 @@ impl Window {
             let queue = player.queue();
 
             let filter = FuzzyFilter::new();
+            imp.playlist_filter.replace(Some(filter.clone()));
             let filter_model =
                 gtk::FilterListModel::new(Some(queue.model().clone()), Some(filter.clone()));
+            filter_model.set_incremental(true);
             let sorter = FuzzySorter::new();
             let sorter_model = gtk::SortListModel::new(Some(filter_model), Some(sorter.clone()));
+            sorter_model.set_incremental(true);
             let selection = gtk::NoSelection::new(Some(sorter_model.clone()));
             imp.playlist_view
                 .queue_view()
@@ -1097,23 +1759,24 @@ impl Window {
             imp.playlist_filtermodel
                 .replace(Some(sorter_model.upcast::<gio::ListModel>()));
 
-            imp.playlist_view
-                .playlist_searchentry()
-                .bind_property("text", &filter, "search")
-                .flags(glib::BindingFlags::SYNC_CREATE)
-                .build();
-            imp.playlist_view
-                .playlist_searchentry()
-                .bind_property("text", &sorter, "search")
-                .flags(glib::BindingFlags::SYNC_CREATE)
-                .build();
-            imp.playlist_view
-                .playlist_searchentry()
-                .connect_search_changed(clone!(@weak self as win => move |_| {
+            // The search entry waits for a pause in typing before
+            // emitting search-changed, so we don't filter thousands of
+            // songs on every key press
+            let search_entry = imp.playlist_view.playlist_searchentry();
+            let search = search_entry.text().to_string();
+            filter.set_search(Some(search.clone()));
+            sorter.set_search(Some(search));
+            search_entry.connect_search_changed(
+                clone!(@weak self as win, @weak filter, @weak sorter => move |entry| {
+                    let search = entry.text().to_string();
+                    filter.set_search(Some(search.clone()));
+                    sorter.set_search(Some(search));
+
                     if let Some(adjustment) = win.imp().playlist_view.queue_view().vadjustment() {
                         adjustment.set_value(0.0);
                     }
-                }));
+                }),
+            );
         }
     }
 
@@ -1162,11 +1825,18 @@ impl Window {
     fn update_position_labels(&self) {
         if let Some(player) = self.player() {
             let state = player.state();
-            if state.current_song().is_some() {
+            let is_stream = state.current_song().is_some_and(|s| s.is_stream());
+            if is_stream {
+                // Streams have no duration, so there's nothing remaining
+                self.set_song_time(Some(state.position()), None);
+                self.imp().remaining_label.set_text(&i18n("Live"));
+                self.set_song_position(0.0);
+            } else if state.current_song().is_some() {
                 let elapsed = state.position();
                 let duration = state.duration();
                 let remaining = duration.checked_sub(elapsed).unwrap_or_default();
                 self.set_song_time(Some(elapsed), Some(remaining));
+                self.imp().waveform_view.set_duration(duration);
 
                 let position = state.position() as f64 / state.duration() as f64;
                 self.set_song_position(position);
@@ -1184,6 +1854,15 @@ impl Window {
             self.update_playlist_time();
             self.update_title(state.current_song().as_ref());
             self.update_style(state.current_song().as_ref());
+            self.update_cover_actions();
+
+            let seekable = !state.current_song().is_some_and(|s| s.is_stream());
+            self.imp().waveform_view.set_seekable(seekable);
+
+            // Streams cannot be rated
+            let rateable = state.current_song().is_some_and(|s| !s.is_stream());
+            self.action_set_enabled("win.rate-song", rateable);
+            self.notify("song-rating");
         }
     }
 
@@ -1191,11 +1870,11 @@ impl Window {
         if let Some(player) = self.player() {
             let state = player.state();
             let song_cover = self.imp().song_cover.get();
-            if let Some(cover) = state.cover() {
-                song_cover.album_image().set_cover(Some(&cover));
+            if let Some(cover) = state.current_song().and_then(|s| s.cover_art()) {
+                song_cover.album_image().set_cover_art(Some(&cover));
                 song_cover.show_cover_image(true);
             } else {
-                song_cover.album_image().set_cover(None);
+                song_cover.album_image().set_cover_art(None);
                 song_cover.show_cover_image(false);
             }
         }
@@ -1285,48 +1964,116 @@ impl Window {
         if let Some(display) = gdk::Display::default() {
             gtk::style_context_add_provider_for_display(&display, &imp.provider, 400);
         }
+
+        // The animation is skipped when gtk-enable-animations is unset
+        let target = adw::CallbackAnimationTarget::new(clone!(@weak self as win => move |value| {
+            win.load_palette_transition(value);
+        }));
+        let animation = adw::TimedAnimation::builder()
+            .widget(self)
+            .value_from(0.0)
+            .value_to(1.0)
+            .duration(PALETTE_TRANSITION_MSECS)
+            .easing(adw::Easing::EaseOutCubic)
+            .target(&target)
+            .build();
+        animation.connect_done(clone!(@weak self as win => move |_| {
+            win.finish_palette_transition();
+        }));
+        imp.palette_animation.replace(Some(animation));
     }
 
     fn update_style(&self, song: Option<&Song>) {
         let imp = self.imp();
 
         if !imp.settings.boolean("enable-recoloring") {
+            if let Some(animation) = imp.palette_animation.borrow().as_ref() {
+                animation.reset();
+            }
+            imp.palette_recolor.set(false);
             imp.provider.load_from_data("");
             imp.main_stack.remove_css_class("main-window");
             return;
         }
 
-        if let Some(song) = song {
-            if let Some(bg_colors) = song.cover_palette() {
-                let mut css = String::new();
+        let palette = song.and_then(|s| s.cover_palette());
+        self.action_set_enabled("win.enable-recoloring", palette.is_some());
 
-                let n_colors = bg_colors.len();
-                for (i, color) in bg_colors.iter().enumerate().take(n_colors) {
-                    let s = format!("@define-color background_color_{} {};", i, color);
-                    css.push_str(&s);
-                }
+        let dark = adw::StyleManager::default().is_dark();
+        let colors = palette_colors(palette.as_ref().map(|p| p.colors(dark)));
+        self.transition_palette(colors, palette.is_some());
+    }
 
-                for i in n_colors - 1..5 {
-                    css.push_str(&format!(
-                        "@define-color background_color_{} @window_bg_color;",
-                        i
-                    ));
-                }
+    // Fades from the colors currently on screen to the given ones
+    fn transition_palette(&self, colors: Vec<String>, recolor: bool) {
+        let imp = self.imp();
+        let animation = match imp.palette_animation.borrow().as_ref() {
+            Some(a) => a.clone(),
+            None => return,
+        };
 
-                imp.provider.load_from_data(&css);
-                if !imp.main_stack.has_css_class("main-window") {
-                    imp.main_stack.add_css_class("main-window");
-                }
+        let recoloring = imp.main_stack.has_css_class("main-window");
+        if !recoloring && !recolor {
+            return;
+        }
 
-                self.action_set_enabled("win.enable-recoloring", true);
+        let from = if !recoloring {
+            palette_colors(None)
+        } else if animation.state() == adw::AnimationState::Playing {
+            // Start from wherever the running transition got to
+            let progress = animation.value();
+            let from = imp.palette_from.borrow();
+            let to = imp.palette_to.borrow();
+            from.iter()
+                .zip(to.iter())
+                .map(|(a, b)| format!("mix({}, {}, {})", a, b, progress))
+                .collect()
+        } else {
+            imp.palette_to.borrow().clone()
+        };
 
-                return;
-            }
+        imp.palette_from.replace(from);
+        imp.palette_to.replace(colors);
+        imp.palette_recolor.set(recolor);
+
+        animation.reset();
+        if !recoloring {
+            imp.main_stack.add_css_class("main-window");
         }
+        animation.play();
+    }
 
-        imp.provider.load_from_data("");
-        imp.main_stack.remove_css_class("main-window");
-        self.action_set_enabled("win.enable-recoloring", false);
+    fn load_palette_transition(&self, progress: f64) {
+        let imp = self.imp();
+        let from = imp.palette_from.borrow();
+        let to = imp.palette_to.borrow();
+
+        let mut css = String::new();
+        for (i, (a, b)) in from.iter().zip(to.iter()).enumerate() {
+            css.push_str(&format!(
+                "@define-color background_color_{} mix({}, {}, {});",
+                i, a, b, progress
+            ));
+        }
+
+        imp.provider.load_from_data(&css);
+    }
+
+    fn finish_palette_transition(&self) {
+        let imp = self.imp();
+
+        if !imp.palette_recolor.get() {
+            imp.provider.load_from_data("");
+            imp.main_stack.remove_css_class("main-window");
+            return;
+        }
+
+        let mut css = String::new();
+        for (i, color) in imp.palette_to.borrow().iter().enumerate() {
+            css.push_str(&format!("@define-color background_color_{} {};", i, color));
+        }
+
+        imp.provider.load_from_data(&css);
     }
 
     fn update_title(&self, song: Option<&Song>) {
@@ -1360,6 +2107,8 @@ impl Window {
                 .queue_selected_label()
                 .set_label(&selected_str);
         }
+
+        self.update_cover_actions();
     }
 
     pub fn open_files(&self, files: &[gio::File]) {
@@ -1388,6 +2137,58 @@ impl Window {
         self.imp().toast_overlay.add_toast(toast);
     }
 
+    // Covers and waveforms are regenerated the next time a song needs
+    // them, so this is always safe
+    fn clear_caches(&self) {
+        audio::CoverCache::global().lock().unwrap().clear();
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let freed = gio::spawn_blocking(utils::clear_cache).await.unwrap_or(0);
+            win.add_toast(i18n_f(
+                // Translators: the `{}` must be left unmodified, and
+                // it will be expanded to an amount of disk space, like
+                // "12.3 MB"
+                "Caches cleared, {} freed",
+                &[&glib::format_size(freed)],
+            ));
+        }));
+    }
+
+    pub fn show_playback_error(&self, error: &audio::PlaybackError) {
+        // Streams have no file name, so we show their URI instead
+        let name = match error.uri.as_deref() {
+            Some(uri) if !utils::is_stream_uri(uri) => gio::File::for_uri(uri)
+                .basename()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_else(|| uri.to_string()),
+            Some(uri) => uri.to_string(),
+            None => i18n("Unknown title"),
+        };
+
+        debug!("Unable to play {}: {}", &name, &error.message);
+
+        let msg = if error.missing_codec {
+            let (content_type, _) = gio::content_type_guess(Some(&name), &[]);
+            let format = gio::content_type_get_description(&content_type);
+            i18n_k(
+                // Translators: `{file}` and `{format}` must be left
+                // untranslated; they will expand to the name of the
+                // file and the description of its format, respectively
+                "Unable to play “{file}”: no codec available for {format}",
+                &[("file", &name), ("format", &format)],
+            )
+        } else {
+            i18n_f(
+                // Translators: the `{}` must be left unmodified, and
+                // it will be expanded to the name of a file
+                "Unable to play “{}”",
+                &[&name],
+            )
+        };
+        self.add_toast(msg);
+    }
+
     pub fn add_skip_to_toast(&self, msg: String, button: String, pos: u32) {
         let toast = adw::Toast::new(&msg);
         toast.set_button_label(Some(&button));
@@ -1396,6 +2197,282 @@ impl Window {
         self.imp().toast_overlay.add_toast(toast);
     }
 
+    // The cover actions apply to the selected song, if there is only one,
+    // and to the current song otherwise
+    fn cover_song(&self) -> Option<Song> {
+        let player = self.player()?;
+        let queue = player.queue();
+
+        if self.playlist_selection() {
+            let mut selected = (0..queue.n_songs())
+                .filter_map(|i| queue.song_at(i))
+                .filter(|s| s.selected());
+            return match (selected.next(), selected.next()) {
+                (Some(song), None) => Some(song),
+                _ => None,
+            };
+        }
+
+        player.state().current_song()
+    }
+
+    fn update_cover_actions(&self) {
+        let song = self.cover_song().filter(|s| !s.is_stream());
+        let has_cover = song.as_ref().is_some_and(|s| s.cover_art().is_some());
+        self.action_set_enabled("cover.export", has_cover);
+        self.action_set_enabled("cover.copy", has_cover);
+        self.action_set_enabled("cover.set", song.is_some());
+    }
+
+    // Prefers the cover in the tags of the song, as it's at its original
+    // size, to the one we loaded
+    async fn load_cover_data(song: &Song) -> Option<glib::Bytes> {
+        let uri = song.uri();
+        let data = gio::spawn_blocking(move || audio::embedded_cover(&uri))
+            .await
+            .ok()
+            .flatten();
+
+        data.or_else(|| song.cover_texture().map(|t| t.save_to_png_bytes()))
+    }
+
+    fn export_cover(&self) {
+        let song = match self.cover_song() {
+            Some(song) => song,
+            None => return,
+        };
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let data = match Window::load_cover_data(&song).await {
+                Some(data) => data,
+                None => return,
+            };
+
+            let extension = audio::cover_extension(&data);
+            let name = format!("{}.{}", song.album().replace('/', "-"), extension);
+            let dialog = gtk::FileDialog::builder()
+                .accept_label(i18n("_Export"))
+                .modal(true)
+                .title(i18n("Export Cover"))
+                .initial_name(name)
+                .build();
+
+            if let Ok(file) = dialog.save_future(Some(&win)).await {
+                let flags = gio::FileCreateFlags::REPLACE_DESTINATION;
+                let res = file.replace_contents_future(data, None, false, flags).await;
+                if let Err((_, e)) = res {
+                    warn!("Unable to export cover: {}", e);
+                    win.add_toast(i18n("Unable to export the cover"));
+                }
+            }
+        }));
+    }
+
+    fn copy_cover(&self) {
+        let song = match self.cover_song() {
+            Some(song) => song,
+            None => return,
+        };
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let texture = match Window::load_cover_data(&song).await {
+                Some(data) => gdk::Texture::from_bytes(&data).ok(),
+                None => None,
+            };
+
+            if let Some(texture) = texture {
+                win.clipboard().set_texture(&texture);
+                win.add_toast(i18n("Cover copied to the clipboard"));
+            }
+        }));
+    }
+
+    fn choose_cover(&self) {
+        let song = match self.cover_song() {
+            Some(song) => song,
+            None => return,
+        };
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let filter = gtk::FileFilter::new();
+            filter.add_mime_type("image/*");
+            filter.set_name(Some(&i18n("Images")));
+
+            let filters = gio::ListStore::new::<gtk::FileFilter>();
+            filters.append(&filter);
+
+            let dialog = gtk::FileDialog::builder()
+                .accept_label(i18n("_Select"))
+                .modal(true)
+                .title(i18n("Set Album Cover"))
+                .filters(&filters)
+                .build();
+
+            let file = match dialog.open_future(Some(&win)).await {
+                Ok(file) => file,
+                Err(_) => return,
+            };
+
+            // Make sure we can load the image before touching any file
+            let data = match file.load_bytes_future().await {
+                Ok((data, _)) if gdk::Texture::from_bytes(&data).is_ok() => data,
+                _ => {
+                    win.add_toast(i18n("Unable to load the image"));
+                    return;
+                }
+            };
+
+            win.ask_cover_destination(song, data);
+        }));
+    }
+
+    fn ask_cover_destination(&self, song: Song, data: glib::Bytes) {
+        let dialog = adw::MessageDialog::builder()
+            .transient_for(self)
+            .modal(true)
+            .heading(i18n("Set Album Cover"))
+            .body(i18n(
+                "The cover can be embedded in the songs of the album, or saved as a file in \
+                 their folder; the file is only used for songs without an embedded cover",
+            ))
+            .default_response("embed")
+            .close_response("cancel")
+            .build();
+        dialog.add_responses(&[
+            ("cancel", &i18n("_Cancel")),
+            ("file", &i18n("Save as _File")),
+            ("embed", &i18n("_Embed in Songs")),
+        ]);
+        dialog.set_response_appearance("embed", adw::ResponseAppearance::Suggested);
+
+        // Only local files can be written
+        let local = song.file().path().is_some();
+        dialog.set_response_enabled("embed", local);
+
+        dialog.connect_response(
+            None,
+            clone!(@weak self as win => move |_, response| {
+                match response {
+                    "embed" => win.set_cover(&song, data.clone(), true),
+                    "file" => win.set_cover(&song, data.clone(), false),
+                    _ => (),
+                }
+            }),
+        );
+
+        dialog.present();
+    }
+
+    // The songs in the playlist and in the library sharing the cover of
+    // the given song; songs without a cover share the album and folder
+    fn songs_sharing_cover(&self, song: &Song) -> Vec<Song> {
+        let mut songs = Vec::new();
+        if let Some(player) = self.player() {
+            let queue = player.queue();
+            songs.extend((0..queue.n_songs()).filter_map(|i| queue.song_at(i)));
+        }
+        if let Some(library) = self.library() {
+            songs.extend(library.songs());
+        }
+
+        let parent = |s: &Song| s.file().parent().map(|p| p.uri());
+        let same_album = |s: &Song| s.album() == song.album() && parent(s) == parent(song);
+        let uuid = song.cover_uuid();
+
+        // The library and the playlist can share songs
+        let mut seen = HashSet::new();
+        let mut res: Vec<Song> = songs
+            .into_iter()
+            .filter(|s| !s.is_stream())
+            .filter(|s| match uuid {
+                Some(ref uuid) => s.cover_uuid().as_ref() == Some(uuid),
+                None => s.cover_uuid().is_none() && same_album(s),
+            })
+            .filter(|s| seen.insert(s.clone()))
+            .collect();
+
+        if res.is_empty() {
+            res.push(song.clone());
+        }
+
+        res
+    }
+
+    fn set_cover(&self, song: &Song, data: glib::Bytes, embed: bool) {
+        let songs = self.songs_sharing_cover(song);
+        let uris: Vec<String> = songs.iter().map(|s| s.uri()).collect();
+        let folder = song.file().parent().map(|p| p.uri().to_string());
+        let uuid = song.cover_uuid();
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            let res = gio::spawn_blocking(move || {
+                if embed {
+                    uris.iter().try_for_each(|uri| audio::embed_cover(uri, &data))
+                } else {
+                    match folder {
+                        Some(folder) => audio::save_cover_file(&folder, &data),
+                        None => Err("No folder".to_string()),
+                    }
+                }
+            })
+            .await;
+
+            if let Ok(Err(e)) = res {
+                warn!("Unable to set cover: {}", e);
+                win.add_toast(i18n("Unable to set the cover"));
+            }
+
+            // Even if we failed, some of the songs may have changed
+            win.refresh_covers(uuid.as_deref(), songs);
+        }));
+    }
+
+    // Loads the cover of the given songs again, dropping every cached
+    // copy first
+    fn refresh_covers(&self, uuid: Option<&str>, songs: Vec<Song>) {
+        if let Some(uuid) = uuid {
+            audio::CoverCache::global().lock().unwrap().invalidate(uuid);
+        }
+
+        {
+            let mut metadata_cache = audio::MetadataCache::global().lock().unwrap();
+            for song in &songs {
+                metadata_cache.remove(&song.uri());
+            }
+        }
+
+        let files: Vec<gio::File> = songs.iter().map(|s| s.file()).collect();
+        let receiver = audio::load_songs(files, &gio::Cancellable::new());
+
+        let ctx = glib::MainContext::default();
+        ctx.spawn_local(clone!(@weak self as win => async move {
+            use futures::prelude::*;
+
+            let mut receiver = std::pin::pin!(receiver);
+            while let Some(batch) = receiver.next().await {
+                for (pos, data) in batch {
+                    if let Ok(data) = data {
+                        songs[pos].update_cover(data);
+                    }
+                }
+            }
+
+            std::thread::spawn(|| {
+                audio::MetadataCache::save();
+            });
+
+            win.update_cover();
+            win.update_cover_actions();
+            if let Some(player) = win.player() {
+                win.update_style(player.state().current_song().as_ref());
+            }
+        }));
+    }
+
     fn copy_song(&self) {
         if let Some(player) = self.player() {
             let state = player.state();
@@ -1446,6 +2523,57 @@ impl Window {
         self.imp().replaygain_mode.get()
     }
 
+    pub fn set_waveform_mode(&self, mode: WaveformMode) {
+        let imp = self.imp();
+
+        if mode != imp.waveform_mode.replace(mode) {
+            imp.waveform_view.set_mode(mode);
+            // The spectrum costs CPU time, so we only analyse the audio
+            // while it's visible
+            if let Some(p) = self.player() {
+                p.set_spectrum_enabled(mode == WaveformMode::Spectrum);
+            }
+            imp.settings
+                .set_enum("waveform-mode", mode.into())
+                .expect("Unable to store setting");
+
+            self.notify("waveform-mode");
+        }
+    }
+
+    pub fn waveform_mode(&self) -> WaveformMode {
+        self.imp().waveform_mode.get()
+    }
+
+    fn current_song(&self) -> Option<Song> {
+        self.player().and_then(|p| p.state().current_song())
+    }
+
+    pub fn set_song_rating(&self, rating: u32) {
+        if let Some(song) = self.current_song() {
+            if song.is_stream() || rating == self.song_rating() {
+                return;
+            }
+
+            audio::PlayStats::global()
+                .lock()
+                .unwrap()
+                .set_rating(&song.uri(), rating);
+            std::thread::spawn(|| audio::PlayStats::global().lock().unwrap().save());
+
+            self.notify("song-rating");
+        }
+    }
+
+    pub fn song_rating(&self) -> u32 {
+        self.current_song()
+            .map(|s| {
+                let stats = audio::PlayStats::global().lock().unwrap().get(&s.uri());
+                stats.rating.unwrap_or(0)
+            })
+            .unwrap_or(0)
+    }
+
     pub fn set_song_time(&self, elapsed: Option<u64>, remaining: Option<u64>) {
         if let Some(elapsed) = elapsed {
             self.imp()